@@ -0,0 +1,700 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use dicom::core::header::Header;
+use dicom::core::value::{DataSetSequence, Value};
+use dicom::core::{DataElement, Tag, VR};
+use dicom::object::mem::InMemElement;
+use dicom::object::{open_file, FileMetaTableBuilder, InMemDicomObject};
+use dicom_dictionary_std::tags;
+use rayon::prelude::*;
+
+use crate::dicom_reader::read_string;
+
+/// Prefix of the UIDs defined by the DICOM standard, which identify no one
+const DICOM_UID_ROOT: &str = "1.2.840.10008.";
+
+/// What the profile does with an attribute it retains, following PS3.15
+/// Table E.1-1. Attributes not listed in [`PROFILE`] are removed (X).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// K: keep the value, applying the profile to the items of sequences
+    Keep,
+    /// Z: replace with a zero length value
+    Empty,
+    /// D: replace with a dummy value of the VR
+    Dummy,
+    /// U: replace each UID with one that is consistent across files
+    Uid,
+    /// Shift dates and keep times when shifting dates, otherwise Z
+    Date,
+}
+
+/// Attributes retained by the profile, those needed to load, display and
+/// relate images, structure sets and dose. Every other attribute, private
+/// ones included, is removed. Retired attributes are listed too, as older
+/// files still carry them.
+#[allow(deprecated)]
+const PROFILE: &[(Tag, Action)] = &[
+    // SOP common, patient and study
+    (tags::SPECIFIC_CHARACTER_SET, Action::Keep),
+    (tags::SOP_CLASS_UID, Action::Keep),
+    (tags::SOP_INSTANCE_UID, Action::Uid),
+    (tags::INSTANCE_NUMBER, Action::Keep),
+    (tags::PATIENT_NAME, Action::Empty),
+    (tags::PATIENT_ID, Action::Empty),
+    (tags::PATIENT_BIRTH_DATE, Action::Empty),
+    (tags::PATIENT_SEX, Action::Empty),
+    (tags::STUDY_INSTANCE_UID, Action::Uid),
+    (tags::STUDY_DATE, Action::Date),
+    (tags::STUDY_TIME, Action::Date),
+    (tags::REFERRING_PHYSICIAN_NAME, Action::Empty),
+    (tags::STUDY_ID, Action::Empty),
+    (tags::ACCESSION_NUMBER, Action::Empty),
+    // Series, frame of reference and equipment
+    (tags::MODALITY, Action::Keep),
+    (tags::SERIES_INSTANCE_UID, Action::Uid),
+    (tags::SERIES_NUMBER, Action::Keep),
+    (tags::SERIES_DATE, Action::Date),
+    (tags::SERIES_TIME, Action::Date),
+    (tags::LATERALITY, Action::Keep),
+    (tags::PATIENT_POSITION, Action::Keep),
+    (tags::BODY_PART_EXAMINED, Action::Keep),
+    (tags::FRAME_OF_REFERENCE_UID, Action::Uid),
+    (tags::POSITION_REFERENCE_INDICATOR, Action::Keep),
+    (tags::MANUFACTURER, Action::Keep),
+    // General image and image plane
+    (tags::IMAGE_TYPE, Action::Keep),
+    (tags::ACQUISITION_NUMBER, Action::Keep),
+    (tags::ACQUISITION_DATE, Action::Date),
+    (tags::ACQUISITION_TIME, Action::Date),
+    (tags::ACQUISITION_DATE_TIME, Action::Date),
+    (tags::CONTENT_DATE, Action::Date),
+    (tags::CONTENT_TIME, Action::Date),
+    (tags::PATIENT_ORIENTATION, Action::Keep),
+    (tags::BURNED_IN_ANNOTATION, Action::Keep),
+    (tags::LOSSY_IMAGE_COMPRESSION, Action::Keep),
+    (tags::LOSSY_IMAGE_COMPRESSION_RATIO, Action::Keep),
+    (tags::LOSSY_IMAGE_COMPRESSION_METHOD, Action::Keep),
+    (tags::PIXEL_SPACING, Action::Keep),
+    (tags::IMAGE_ORIENTATION_PATIENT, Action::Keep),
+    (tags::IMAGE_POSITION_PATIENT, Action::Keep),
+    (tags::SLICE_THICKNESS, Action::Keep),
+    (tags::SPACING_BETWEEN_SLICES, Action::Keep),
+    (tags::SLICE_LOCATION, Action::Keep),
+    // Image pixel, modality and VOI LUT
+    (tags::SAMPLES_PER_PIXEL, Action::Keep),
+    (tags::PHOTOMETRIC_INTERPRETATION, Action::Keep),
+    (tags::ROWS, Action::Keep),
+    (tags::COLUMNS, Action::Keep),
+    (tags::BITS_ALLOCATED, Action::Keep),
+    (tags::BITS_STORED, Action::Keep),
+    (tags::HIGH_BIT, Action::Keep),
+    (tags::PIXEL_REPRESENTATION, Action::Keep),
+    (tags::PLANAR_CONFIGURATION, Action::Keep),
+    (tags::PIXEL_ASPECT_RATIO, Action::Keep),
+    (tags::PIXEL_PADDING_VALUE, Action::Keep),
+    (tags::NUMBER_OF_FRAMES, Action::Keep),
+    (tags::FRAME_INCREMENT_POINTER, Action::Keep),
+    (tags::PIXEL_DATA, Action::Keep),
+    (tags::RESCALE_INTERCEPT, Action::Keep),
+    (tags::RESCALE_SLOPE, Action::Keep),
+    (tags::RESCALE_TYPE, Action::Keep),
+    (tags::WINDOW_CENTER, Action::Keep),
+    (tags::WINDOW_WIDTH, Action::Keep),
+    // CT, MR and PET acquisition
+    (tags::KVP, Action::Keep),
+    (tags::GANTRY_DETECTOR_TILT, Action::Keep),
+    (tags::TABLE_HEIGHT, Action::Keep),
+    (tags::ROTATION_DIRECTION, Action::Keep),
+    (tags::X_RAY_TUBE_CURRENT, Action::Keep),
+    (tags::EXPOSURE_TIME, Action::Keep),
+    (tags::EXPOSURE, Action::Keep),
+    (tags::CONVOLUTION_KERNEL, Action::Keep),
+    (tags::DATA_COLLECTION_DIAMETER, Action::Keep),
+    (tags::RECONSTRUCTION_DIAMETER, Action::Keep),
+    (tags::SCANNING_SEQUENCE, Action::Keep),
+    (tags::SEQUENCE_VARIANT, Action::Keep),
+    (tags::SCAN_OPTIONS, Action::Keep),
+    (tags::MR_ACQUISITION_TYPE, Action::Keep),
+    (tags::REPETITION_TIME, Action::Keep),
+    (tags::ECHO_TIME, Action::Keep),
+    (tags::ECHO_TRAIN_LENGTH, Action::Keep),
+    (tags::INVERSION_TIME, Action::Keep),
+    (tags::MAGNETIC_FIELD_STRENGTH, Action::Keep),
+    (tags::FLIP_ANGLE, Action::Keep),
+    (tags::IMAGED_NUCLEUS, Action::Keep),
+    (tags::UNITS, Action::Keep),
+    (tags::CORRECTED_IMAGE, Action::Keep),
+    (tags::DECAY_CORRECTION, Action::Keep),
+    // Functional groups of enhanced multi-frame images
+    (tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE, Action::Keep),
+    (tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE, Action::Keep),
+    (tags::PIXEL_MEASURES_SEQUENCE, Action::Keep),
+    (tags::PLANE_POSITION_SEQUENCE, Action::Keep),
+    (tags::PLANE_ORIENTATION_SEQUENCE, Action::Keep),
+    (tags::PIXEL_VALUE_TRANSFORMATION_SEQUENCE, Action::Keep),
+    (tags::FRAME_VOILUT_SEQUENCE, Action::Keep),
+    (tags::FRAME_CONTENT_SEQUENCE, Action::Keep),
+    (tags::FRAME_ACQUISITION_DATE_TIME, Action::Date),
+    (tags::FRAME_REFERENCE_DATE_TIME, Action::Date),
+    (tags::DIMENSION_ORGANIZATION_UID, Action::Uid),
+    (tags::DIMENSION_INDEX_VALUES, Action::Keep),
+    (tags::STACK_ID, Action::Keep),
+    (tags::IN_STACK_POSITION_NUMBER, Action::Keep),
+    (tags::TEMPORAL_POSITION_INDEX, Action::Keep),
+    // References between instances
+    (tags::REFERENCED_SERIES_SEQUENCE, Action::Keep),
+    (tags::REFERENCED_IMAGE_SEQUENCE, Action::Keep),
+    (tags::SOURCE_IMAGE_SEQUENCE, Action::Keep),
+    (tags::REFERENCED_SOP_CLASS_UID, Action::Keep),
+    (tags::REFERENCED_SOP_INSTANCE_UID, Action::Uid),
+    (tags::REFERENCED_FRAME_NUMBER, Action::Keep),
+    (tags::REFERENCED_FRAME_OF_REFERENCE_UID, Action::Uid),
+    (tags::RELATED_FRAME_OF_REFERENCE_UID, Action::Uid),
+    // RT Structure Set and ROI Contour
+    (tags::STRUCTURE_SET_LABEL, Action::Dummy),
+    (tags::STRUCTURE_SET_DATE, Action::Date),
+    (tags::STRUCTURE_SET_TIME, Action::Date),
+    (tags::REFERENCED_FRAME_OF_REFERENCE_SEQUENCE, Action::Keep),
+    (tags::RT_REFERENCED_STUDY_SEQUENCE, Action::Keep),
+    (tags::RT_REFERENCED_SERIES_SEQUENCE, Action::Keep),
+    (tags::CONTOUR_IMAGE_SEQUENCE, Action::Keep),
+    (tags::STRUCTURE_SET_ROI_SEQUENCE, Action::Keep),
+    (tags::ROI_NUMBER, Action::Keep),
+    (tags::ROI_NAME, Action::Keep),
+    (tags::ROI_GENERATION_ALGORITHM, Action::Keep),
+    (tags::ROI_CONTOUR_SEQUENCE, Action::Keep),
+    (tags::ROI_DISPLAY_COLOR, Action::Keep),
+    (tags::CONTOUR_SEQUENCE, Action::Keep),
+    (tags::CONTOUR_GEOMETRIC_TYPE, Action::Keep),
+    (tags::NUMBER_OF_CONTOUR_POINTS, Action::Keep),
+    (tags::CONTOUR_NUMBER, Action::Keep),
+    (tags::CONTOUR_DATA, Action::Keep),
+    (tags::REFERENCED_ROI_NUMBER, Action::Keep),
+    (tags::RTROI_OBSERVATIONS_SEQUENCE, Action::Keep),
+    (tags::OBSERVATION_NUMBER, Action::Keep),
+    (tags::RTROI_INTERPRETED_TYPE, Action::Keep),
+    (tags::ROI_INTERPRETER, Action::Empty),
+    // RT Dose
+    (tags::DOSE_UNITS, Action::Keep),
+    (tags::DOSE_TYPE, Action::Keep),
+    (tags::DOSE_SUMMATION_TYPE, Action::Keep),
+    (tags::DOSE_GRID_SCALING, Action::Keep),
+    (tags::GRID_FRAME_OFFSET_VECTOR, Action::Keep),
+    (tags::TISSUE_HETEROGENEITY_CORRECTION, Action::Keep),
+    (tags::REFERENCED_RT_PLAN_SEQUENCE, Action::Keep),
+];
+
+/// Options of a de-identification.
+#[derive(Debug, Clone, Default)]
+pub struct AnonymizeOptions {
+    /// Replacement patient name and ID, `ANONYMOUS` when empty
+    pub patient_name: String,
+    pub patient_id: String,
+    /// Days added to every date, keeping the intervals between them.
+    /// Dates are emptied when not set.
+    pub date_shift_days: Option<i64>,
+}
+
+/// De-identifies DICOM files following the PS3.15 Basic Profile.
+///
+/// Only the attributes of [`PROFILE`] are retained, with identifying ones
+/// emptied or replaced, and every non standard UID replaced. UIDs are
+/// remapped consistently for all files passed to the same anonymizer, so
+/// the references between series and instances still hold. Image geometry
+/// and pixel data are left untouched.
+pub struct Anonymizer {
+    options: AnonymizeOptions,
+    /// Random keys of the UID remapping, fixed for this anonymizer
+    uid_keys: RandomState,
+}
+
+impl Anonymizer {
+    pub fn new(options: AnonymizeOptions) -> Self {
+        Anonymizer {
+            options,
+            uid_keys: RandomState::new(),
+        }
+    }
+
+    /// Write de-identified copies of `files` to `out_dir`, named after their
+    /// new SOP instance UIDs, returning the paths written.
+    pub fn anonymize_files<P: AsRef<Path> + Sync>(
+        &self,
+        files: &[P],
+        out_dir: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        std::fs::create_dir_all(out_dir)
+            .with_context(|| format!("Failed to create {}", out_dir.display()))?;
+        files
+            .par_iter()
+            .map(|file| self.anonymize_file(file.as_ref(), out_dir))
+            .collect()
+    }
+
+    fn anonymize_file(&self, file: &Path, out_dir: &Path) -> Result<PathBuf> {
+        let obj = open_file(file).with_context(|| format!("Failed to open {}", file.display()))?;
+        if read_string(&obj, "BurnedInAnnotation") == "YES" {
+            return Err(anyhow!(
+                "{} has identifying text burned into its pixels",
+                file.display()
+            ));
+        }
+        let transfer_syntax = obj
+            .meta()
+            .transfer_syntax()
+            .trim_end_matches('\0')
+            .to_string();
+
+        let anonymized = self.anonymize(&obj);
+        let sop_instance_uid = read_string(&anonymized, "SOPInstanceUID");
+        let path = out_dir.join(format!("{}.dcm", sop_instance_uid));
+        anonymized
+            .with_meta(FileMetaTableBuilder::new().transfer_syntax(transfer_syntax))?
+            .write_to_file(&path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// A de-identified copy of a data set, recording how it was
+    /// de-identified.
+    fn anonymize(&self, obj: &InMemDicomObject) -> InMemDicomObject {
+        let mut anonymized = self.anonymize_object(obj);
+        let name = if self.options.patient_name.is_empty() {
+            "ANONYMOUS"
+        } else {
+            &self.options.patient_name
+        };
+        let id = if self.options.patient_id.is_empty() {
+            "ANONYMOUS"
+        } else {
+            &self.options.patient_id
+        };
+        anonymized.put_str(tags::PATIENT_NAME, VR::PN, name);
+        anonymized.put_str(tags::PATIENT_ID, VR::LO, id);
+        anonymized.put_str(tags::PATIENT_IDENTITY_REMOVED, VR::CS, "YES");
+        let method = match self.options.date_shift_days {
+            Some(_) => "PS3.15 Basic Profile, Retain Longitudinal With Modified Dates",
+            None => "PS3.15 Basic Profile",
+        };
+        anonymized.put_str(tags::DEIDENTIFICATION_METHOD, VR::LO, method);
+        let temporal = match self.options.date_shift_days {
+            Some(_) => "MODIFIED",
+            None => "REMOVED",
+        };
+        anonymized.put_str(
+            tags::LONGITUDINAL_TEMPORAL_INFORMATION_MODIFIED,
+            VR::CS,
+            temporal,
+        );
+        anonymized
+    }
+
+    /// A copy of a data set, and of the items of its sequences, with the
+    /// actions of the profile applied.
+    fn anonymize_object(&self, obj: &InMemDicomObject) -> InMemDicomObject {
+        InMemDicomObject::from_element_iter(
+            obj.iter()
+                .filter_map(|element| self.anonymize_element(element)),
+        )
+    }
+
+    fn anonymize_element(&self, element: &InMemElement) -> Option<InMemElement> {
+        let tag = element.tag();
+        let vr = element.vr();
+        let (_, action) = PROFILE.iter().find(|(kept, _)| *kept == tag)?;
+
+        let text = || element.to_str().unwrap_or_default().to_string();
+        let empty = DataElement::empty(tag, vr);
+        Some(match action {
+            Action::Keep => match element.value() {
+                Value::Sequence(sequence) => {
+                    let items: Vec<InMemDicomObject> = sequence
+                        .items()
+                        .iter()
+                        .map(|item| self.anonymize_object(item))
+                        .collect();
+                    DataElement::new(tag, vr, DataSetSequence::from(items))
+                }
+                _ => element.clone(),
+            },
+            Action::Empty => empty,
+            Action::Dummy => match dummy_value(vr) {
+                Some(value) => DataElement::new(tag, vr, value),
+                None => empty,
+            },
+            Action::Uid => DataElement::new(tag, vr, self.remap_uids(&text())),
+            Action::Date => match (vr, self.options.date_shift_days) {
+                (VR::DA | VR::DT, Some(days)) => {
+                    DataElement::new(tag, vr, shift_dates(&text(), days))
+                }
+                (_, Some(_)) => element.clone(),
+                (_, None) => empty,
+            },
+        })
+    }
+
+    /// Replace each UID of a value, keeping those defined by the standard.
+    fn remap_uids(&self, value: &str) -> String {
+        value
+            .split('\\')
+            .map(|uid| {
+                let uid = uid.trim_end_matches(['\0', ' ']);
+                if uid.is_empty() || uid.starts_with(DICOM_UID_ROOT) {
+                    return uid.to_string();
+                }
+                let hash = |salt: u8| self.uid_keys.hash_one((salt, uid)) as u128;
+                format!("2.25.{}", hash(0) << 64 | hash(1))
+            })
+            .collect::<Vec<_>>()
+            .join("\\")
+    }
+}
+
+/// A non empty value of a textual VR standing in for a removed one.
+fn dummy_value(vr: VR) -> Option<&'static str> {
+    match vr {
+        VR::DA => Some("19000101"),
+        VR::DT => Some("19000101000000"),
+        VR::TM => Some("000000"),
+        VR::PN => Some("ANONYMOUS"),
+        VR::IS | VR::DS => Some("0"),
+        VR::AE | VR::CS | VR::LO | VR::LT | VR::SH | VR::ST | VR::UC | VR::UT => Some("ANONYMIZED"),
+        _ => None,
+    }
+}
+
+/// Shift the `YYYYMMDD` dates at the start of DA or DT values by some days,
+/// keeping the time of DT values. Values that are not dates, such as
+/// ranges, are emptied.
+fn shift_dates(value: &str, days: i64) -> String {
+    value
+        .split('\\')
+        .map(|date| {
+            let date = date.trim();
+            if date.len() < 8 || !date.is_ascii() {
+                return String::new();
+            }
+            let (ymd, time) = date.split_at(8);
+            // A time with an optional UTC offset, rather than a range
+            let is_time = time
+                .chars()
+                .all(|c| c.is_ascii_digit() || matches!(c, '.' | '+' | '-'))
+                && time.split_once('-').is_none_or(|(_, end)| end.len() < 8);
+            let field = |range: std::ops::Range<usize>| ymd[range].parse::<i64>().ok();
+            match (field(0..4), field(4..6), field(6..8)) {
+                (Some(year), Some(month @ 1..=12), Some(day @ 1..=31)) if is_time => {
+                    let (year, month, day) =
+                        civil_from_days(days_from_civil(year, month, day) + days);
+                    format!("{:04}{:02}{:02}{}", year, month, day, time)
+                }
+                _ => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Proleptic Gregorian date of a number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicom_reader::{load_dicom_image, read_string};
+    use crate::test_util::{ct_slice, put, temp_dir, SliceGeometry};
+    use dicom::core::PrimitiveValue;
+    use std::collections::HashSet;
+
+    const SAMPLE_SERIES: &str = "data/eclipse-10.0.42-fsrt-brain";
+
+    /// A CT slice carrying identifying attributes the profile must not
+    /// retain, and a referenced image whose UIDs it must remap.
+    fn identifying_slice() -> InMemDicomObject {
+        let geometry = SliceGeometry {
+            columns: 2,
+            rows: 2,
+            pixel_spacing: [1., 1.],
+            position: [0., 0., 0.],
+            orientation: [1., 0., 0., 0., 1., 0.],
+        };
+        let mut obj = ct_slice(0, &geometry, &[0, 1, 2, 3]);
+        let text = |value: &str| PrimitiveValue::from(value);
+        put(&mut obj, Tag(0x0009, 0x0010), VR::LO, text("ACME"));
+        put(&mut obj, Tag(0x0009, 0x1001), VR::LO, text("Doe^Jane"));
+        put(&mut obj, tags::PATIENT_BIRTH_TIME, VR::TM, text("0830"));
+        put(&mut obj, tags::ADMISSION_ID, VR::LO, text("ADM-1"));
+        put(
+            &mut obj,
+            tags::CURRENT_PATIENT_LOCATION,
+            VR::LO,
+            text("Ward 4"),
+        );
+        put(&mut obj, tags::PATIENT_STATE, VR::LO, text("Pregnant"));
+        put(&mut obj, tags::REQUESTED_PROCEDURE_ID, VR::SH, text("RP-1"));
+        let reason = tags::REASON_FOR_THE_REQUESTED_PROCEDURE;
+        put(&mut obj, reason, VR::LO, text("Jane's headache"));
+        put(&mut obj, tags::INSTITUTION_NAME, VR::LO, text("General"));
+        let mut content = InMemDicomObject::new_empty();
+        put(&mut content, tags::TEXT_VALUE, VR::UT, text("Jane Doe"));
+        obj.put(DataElement::new(
+            tags::CONTENT_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(vec![content]),
+        ));
+        let mut reference = InMemDicomObject::new_empty();
+        let class = text("1.2.840.10008.5.1.4.1.1.2");
+        put(
+            &mut reference,
+            tags::REFERENCED_SOP_CLASS_UID,
+            VR::UI,
+            class,
+        );
+        let instance = text("1.2.826.0.1.3680043.2.1125.9");
+        put(
+            &mut reference,
+            tags::REFERENCED_SOP_INSTANCE_UID,
+            VR::UI,
+            instance,
+        );
+        obj.put(DataElement::new(
+            tags::REFERENCED_IMAGE_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(vec![reference]),
+        ));
+        obj
+    }
+
+    fn referenced_image(obj: &InMemDicomObject) -> InMemDicomObject {
+        let sequence = obj.element(tags::REFERENCED_IMAGE_SEQUENCE).unwrap();
+        sequence.items().unwrap()[0].clone()
+    }
+
+    #[test]
+    fn keeps_only_the_profile() {
+        let anonymizer = Anonymizer::new(Default::default());
+        let obj = identifying_slice();
+        let anonymized = anonymizer.anonymize(&obj);
+
+        for tag in [
+            Tag(0x0009, 0x0010),
+            Tag(0x0009, 0x1001),
+            tags::PATIENT_BIRTH_TIME,
+            tags::ADMISSION_ID,
+            tags::CURRENT_PATIENT_LOCATION,
+            tags::PATIENT_STATE,
+            tags::REQUESTED_PROCEDURE_ID,
+            tags::REASON_FOR_THE_REQUESTED_PROCEDURE,
+            tags::INSTITUTION_NAME,
+            tags::CONTENT_SEQUENCE,
+        ] {
+            assert!(anonymized.element(tag).is_err(), "{} survived", tag);
+        }
+        assert_eq!(read_string(&anonymized, "PatientName"), "ANONYMOUS");
+        assert_eq!(read_string(&anonymized, "PatientID"), "ANONYMOUS");
+        assert_eq!(read_string(&anonymized, "PatientBirthDate"), "");
+        assert_eq!(read_string(&anonymized, "StudyDate"), "");
+        assert_eq!(read_string(&anonymized, "PatientIdentityRemoved"), "YES");
+        assert_eq!(
+            read_string(&anonymized, "LongitudinalTemporalInformationModified"),
+            "REMOVED"
+        );
+        assert_eq!(read_string(&anonymized, "Modality"), "CT");
+        assert_eq!(read_string(&anonymized, "RescaleIntercept"), "-1024");
+        assert_eq!(
+            anonymized
+                .element(tags::PIXEL_DATA)
+                .unwrap()
+                .to_bytes()
+                .unwrap(),
+            obj.element(tags::PIXEL_DATA).unwrap().to_bytes().unwrap()
+        );
+
+        // UIDs are remapped consistently, in sequences too, except those of
+        // the standard
+        let uid = |obj: &InMemDicomObject, name: &str| read_string(obj, name);
+        let study = uid(&anonymized, "StudyInstanceUID");
+        assert!(study.starts_with("2.25."));
+        assert_ne!(study, uid(&obj, "StudyInstanceUID"));
+        let again = anonymizer.anonymize(&obj);
+        assert_eq!(uid(&again, "StudyInstanceUID"), study);
+        let reference = referenced_image(&anonymized);
+        let instance = uid(&reference, "ReferencedSOPInstanceUID");
+        assert!(instance.starts_with("2.25."));
+        assert_eq!(
+            anonymizer.remap_uids("1.2.826.0.1.3680043.2.1125.9"),
+            instance
+        );
+        assert_eq!(
+            uid(&reference, "ReferencedSOPClassUID"),
+            "1.2.840.10008.5.1.4.1.1.2"
+        );
+        assert_eq!(uid(&anonymized, "SOPClassUID"), uid(&obj, "SOPClassUID"));
+    }
+
+    #[test]
+    fn shifts_dates_and_keeps_times() {
+        let anonymizer = Anonymizer::new(AnonymizeOptions {
+            date_shift_days: Some(-60),
+            ..Default::default()
+        });
+        let mut obj = identifying_slice();
+        let time = PrimitiveValue::from("101500");
+        put(&mut obj, tags::STUDY_TIME, VR::TM, time);
+        let anonymized = anonymizer.anonymize(&obj);
+
+        assert_eq!(read_string(&anonymized, "StudyDate"), "20191231");
+        assert_eq!(read_string(&anonymized, "StudyTime"), "101500");
+        assert_eq!(read_string(&anonymized, "PatientBirthDate"), "");
+        assert!(anonymized.element(tags::PATIENT_BIRTH_TIME).is_err());
+        assert_eq!(
+            read_string(&anonymized, "LongitudinalTemporalInformationModified"),
+            "MODIFIED"
+        );
+    }
+
+    #[test]
+    fn shifts_dates_across_leap_days_months_and_years() {
+        assert_eq!(shift_dates("20200228", 1), "20200229");
+        assert_eq!(shift_dates("20200228", 2), "20200301");
+        assert_eq!(shift_dates("20200301", -1), "20200229");
+        assert_eq!(shift_dates("19000228", 1), "19000301");
+        assert_eq!(shift_dates("20000228", 1), "20000229");
+        assert_eq!(shift_dates("20190228", 1), "20190301");
+        assert_eq!(shift_dates("20191231", 1), "20200101");
+        assert_eq!(shift_dates("20200101", -1), "20191231");
+        assert_eq!(shift_dates("20200131", 1), "20200201");
+        assert_eq!(shift_dates("20200229", 366), "20210301");
+        assert_eq!(shift_dates("20200229", 0), "20200229");
+    }
+
+    #[test]
+    fn shifts_datetimes_and_multiple_values() {
+        assert_eq!(
+            shift_dates("20191231235959.123456-0500", 1),
+            "20200101235959.123456-0500"
+        );
+        assert_eq!(shift_dates("20200101\\20200301", -1), "20191231\\20200229");
+        assert_eq!(shift_dates(" 20200101 ", 1), "20200102");
+    }
+
+    #[test]
+    fn empties_what_is_not_a_date() {
+        assert_eq!(shift_dates("20200101-20200201", 1), "");
+        assert_eq!(shift_dates("-20200101", 1), "");
+        assert_eq!(shift_dates("2020", 1), "");
+        assert_eq!(shift_dates("20201301", 1), "");
+        assert_eq!(shift_dates("20200100", 1), "");
+        assert_eq!(shift_dates("2020010A", 1), "");
+        assert_eq!(shift_dates("", 1), "");
+    }
+
+    #[test]
+    fn converts_civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(
+            days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28),
+            2
+        );
+        assert_eq!(
+            days_from_civil(1900, 3, 1) - days_from_civil(1900, 2, 28),
+            1
+        );
+        assert_eq!(
+            days_from_civil(2021, 1, 1) - days_from_civil(2020, 1, 1),
+            366
+        );
+        assert_eq!(days_from_civil(1601, 1, 1), -134774);
+        for days in (-800_000..800_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn anonymized_sample_series_loads_with_the_same_geometry() {
+        let files: Vec<_> = std::fs::read_dir(SAMPLE_SERIES)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "dcm"))
+            .collect();
+        let out_dir = temp_dir("anonymized-sample");
+        let anonymizer = Anonymizer::new(AnonymizeOptions {
+            date_shift_days: Some(-1000),
+            ..Default::default()
+        });
+        let anonymized = anonymizer.anonymize_files(&files, &out_dir).unwrap();
+        assert_eq!(anonymized.len(), files.len());
+
+        let options = Default::default();
+        let (original, _) = load_dicom_image(&files, &options).unwrap();
+        let (loaded, _) = load_dicom_image(&anonymized, &options).unwrap();
+        assert_eq!(
+            (loaded.columns, loaded.rows, loaded.slices),
+            (original.columns, original.rows, original.slices)
+        );
+        assert_eq!(loaded.pixel_spacing, original.pixel_spacing);
+        assert_eq!(loaded.position_patient, original.position_patient);
+        assert_eq!(
+            loaded.image_orientation_patient,
+            original.image_orientation_patient
+        );
+        assert!(loaded.volume == original.volume);
+        assert_eq!(loaded.metadata.patient_name, "ANONYMOUS");
+
+        // Every file shares the remapped study, series and frame of
+        // reference, and has an instance UID of its own
+        let uids = |files: &[PathBuf], name: &str| -> HashSet<String> {
+            files
+                .iter()
+                .map(|file| read_string(&open_file(file).unwrap(), name))
+                .collect()
+        };
+        for name in [
+            "StudyInstanceUID",
+            "SeriesInstanceUID",
+            "FrameOfReferenceUID",
+        ] {
+            let before = uids(&files, name);
+            let after = uids(&anonymized, name);
+            assert_eq!(after.len(), 1, "{}", name);
+            assert!(before.is_disjoint(&after), "{}", name);
+            let remapped: HashSet<_> = before
+                .iter()
+                .map(|uid| anonymizer.remap_uids(uid))
+                .collect();
+            assert_eq!(remapped, after, "{}", name);
+        }
+        let instances = uids(&anonymized, "SOPInstanceUID");
+        assert_eq!(instances.len(), files.len());
+        assert!(instances.is_disjoint(&uids(&files, "SOPInstanceUID")));
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
@@ -0,0 +1,476 @@
+use std::io::Read;
+
+use anyhow::{anyhow, Result};
+use flate2::read::ZlibDecoder;
+
+const HEADER_SIZE: usize = 16;
+
+const DOSHUFFLE: u8 = 0x1;
+const MEMCPYED: u8 = 0x2;
+const DOBITSHUFFLE: u8 = 0x4;
+const DODELTA: u8 = 0x8;
+const DONT_SPLIT: u8 = 0x10;
+
+/// Blocks are split into one stream per byte of the type up to this size
+const MAX_SPLITS: usize = 16;
+const MIN_BUFFERSIZE: usize = 128;
+
+/// Furthest a short blosclz match can reach back
+const BLOSCLZ_MAX_DISTANCE: usize = 8191;
+
+/// Decompress a Blosc 1 frame, as written by the numcodecs Blosc codec.
+///
+/// The blosclz, lz4, lz4hc, zlib and zstd compressors and byte and bit
+/// shuffling are supported.
+pub fn decompress(src: &[u8]) -> Result<Vec<u8>> {
+    if src.len() < HEADER_SIZE {
+        return Err(anyhow!("Blosc frame too short"));
+    }
+    let version = src[0];
+    let flags = src[2];
+    let typesize = (src[3] as usize).max(1);
+    let nbytes = read_u32(src, 4)? as usize;
+    let blocksize = read_u32(src, 8)? as usize;
+
+    if flags & MEMCPYED != 0 {
+        return src
+            .get(HEADER_SIZE..HEADER_SIZE + nbytes)
+            .map(|data| data.to_vec())
+            .ok_or_else(|| anyhow!("Blosc frame too short"));
+    }
+    if flags & DODELTA != 0 {
+        return Err(anyhow!("Blosc delta filter is not supported"));
+    }
+    if nbytes == 0 {
+        return Ok(Vec::new());
+    }
+    if blocksize == 0 {
+        return Err(anyhow!("Invalid Blosc block size"));
+    }
+
+    let compressor = Compressor::from_code(flags >> 5)?;
+    let shuffle = flags & DOSHUFFLE != 0 && typesize > 1;
+    let bitshuffle = flags & DOSHUFFLE == 0 && flags & DOBITSHUFFLE != 0;
+
+    let mut out = Vec::with_capacity(nbytes);
+    let nblocks = nbytes.div_ceil(blocksize);
+    for block in 0..nblocks {
+        let bsize = blocksize.min(nbytes - block * blocksize);
+        let leftover = bsize < blocksize;
+        let nsplits = if flags & DONT_SPLIT == 0
+            && typesize <= MAX_SPLITS
+            && blocksize / typesize >= MIN_BUFFERSIZE
+            && !leftover
+        {
+            typesize
+        } else {
+            1
+        };
+        let neblock = bsize / nsplits;
+
+        let mut pos = read_u32(src, HEADER_SIZE + 4 * block)? as usize;
+        let mut decoded = Vec::with_capacity(bsize);
+        for _ in 0..nsplits {
+            let cbytes = read_u32(src, pos)? as usize;
+            pos += 4;
+            let data = src
+                .get(pos..pos + cbytes)
+                .ok_or_else(|| anyhow!("Blosc frame too short"))?;
+            pos += cbytes;
+
+            // Streams that did not compress are stored as is
+            if cbytes == neblock {
+                decoded.extend_from_slice(data);
+            } else {
+                let stream = compressor.decompress(data, neblock)?;
+                if stream.len() != neblock {
+                    return Err(anyhow!(
+                        "Blosc stream decompressed to {} bytes, expected {}",
+                        stream.len(),
+                        neblock
+                    ));
+                }
+                decoded.extend_from_slice(&stream);
+            }
+        }
+
+        if shuffle {
+            unshuffle(&decoded, typesize, &mut out);
+        } else if bitshuffle {
+            bitunshuffle(&decoded, typesize, version, &mut out);
+        } else {
+            out.extend_from_slice(&decoded);
+        }
+    }
+
+    Ok(out)
+}
+
+fn read_u32(src: &[u8], offset: usize) -> Result<u32> {
+    let bytes = src
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("Blosc frame too short"))?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+/// Undo the byte shuffle, which groups the n-th byte of every element
+/// together. Trailing bytes that do not fill an element are not shuffled.
+fn unshuffle(src: &[u8], typesize: usize, out: &mut Vec<u8>) {
+    let elements = src.len() / typesize;
+    for element in 0..elements {
+        for byte in 0..typesize {
+            out.push(src[byte * elements + element]);
+        }
+    }
+    out.extend_from_slice(&src[elements * typesize..]);
+}
+
+/// Undo the bit shuffle, which groups bit `n` of every element together,
+/// eight elements to a byte from the lowest bit. Blosc 1 frames, of format
+/// version 2, only bit shuffle blocks of a multiple of eight elements, and
+/// later versions the leading multiple of eight elements of every block.
+/// Other bytes are stored as is.
+fn bitunshuffle(src: &[u8], typesize: usize, version: u8, out: &mut Vec<u8>) {
+    let mut elements = src.len() / typesize;
+    if version <= 2 && !elements.is_multiple_of(8) {
+        elements = 0;
+    }
+    elements -= elements % 8;
+
+    let row = elements / 8;
+    let start = out.len();
+    out.resize(start + elements * typesize, 0);
+    for bit in 0..typesize * 8 {
+        for element in 0..elements {
+            let value = (src[bit * row + element / 8] >> (element % 8)) & 1;
+            out[start + element * typesize + bit / 8] |= value << (bit % 8);
+        }
+    }
+    out.extend_from_slice(&src[elements * typesize..]);
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Compressor {
+    BloscLz,
+    Lz4,
+    Zlib,
+    Zstd,
+}
+
+impl Compressor {
+    fn from_code(code: u8) -> Result<Self> {
+        match code {
+            0 => Ok(Compressor::BloscLz),
+            1 => Ok(Compressor::Lz4),
+            3 => Ok(Compressor::Zlib),
+            4 => Ok(Compressor::Zstd),
+            2 => Err(anyhow!("Blosc snappy compression is not supported")),
+            _ => Err(anyhow!("Unknown Blosc compressor {}", code)),
+        }
+    }
+
+    fn decompress(&self, data: &[u8], size: usize) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compressor::BloscLz => blosclz_decompress(data, size)?,
+            Compressor::Lz4 => lz4_flex::block::decompress(data, size)?,
+            Compressor::Zlib => {
+                let mut out = Vec::with_capacity(size);
+                ZlibDecoder::new(data).read_to_end(&mut out)?;
+                out
+            }
+            Compressor::Zstd => zstd::bulk::decompress(data, size)?,
+        })
+    }
+}
+
+/// Decode the FastLZ level 2 format used by blosclz.
+fn blosclz_decompress(src: &[u8], size: usize) -> Result<Vec<u8>> {
+    let truncated = || anyhow!("Truncated blosclz stream");
+    let mut out: Vec<u8> = Vec::with_capacity(size);
+    let mut ip = 0;
+    let next = |ip: &mut usize| -> Result<usize> {
+        let byte = *src.get(*ip).ok_or_else(truncated)?;
+        *ip += 1;
+        Ok(byte as usize)
+    };
+
+    let mut ctrl = next(&mut ip)? & 31;
+    loop {
+        if ctrl >= 32 {
+            // Back reference
+            let mut len = (ctrl >> 5) - 1;
+            let mut ofs = (ctrl & 31) << 8;
+            if len == 6 {
+                loop {
+                    let code = next(&mut ip)?;
+                    len += code;
+                    if code != 255 {
+                        break;
+                    }
+                }
+            }
+            let code = next(&mut ip)?;
+            ofs += code;
+            len += 3;
+            if code == 255 && ofs == (31 << 8) + 255 {
+                // Far match with a 16 bit distance
+                ofs = (next(&mut ip)? << 8) + next(&mut ip)? + BLOSCLZ_MAX_DISTANCE;
+            }
+
+            let start = out
+                .len()
+                .checked_sub(ofs + 1)
+                .ok_or_else(|| anyhow!("Invalid blosclz back reference"))?;
+            for i in 0..len {
+                out.push(out[start + i]);
+            }
+        } else {
+            // Run of literals
+            let len = ctrl + 1;
+            out.extend_from_slice(src.get(ip..ip + len).ok_or_else(truncated)?);
+            ip += len;
+        }
+
+        if ip >= src.len() || out.len() >= size {
+            break;
+        }
+        ctrl = next(&mut ip)?;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    //! c-blosc and numcodecs are not available to the tests, so the frames
+    //! are assembled here following the Blosc 1 format description: a 16
+    //! byte header, the offset of every block, then each block as one or
+    //! more streams prefixed by their compressed size. Streams are
+    //! compressed with the same codecs the decoder uses.
+
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+
+    const LZ4: u8 = 1 << 5;
+    const ZLIB: u8 = 3 << 5;
+    const ZSTD: u8 = 4 << 5;
+
+    fn frame(
+        flags: u8,
+        typesize: u8,
+        nbytes: usize,
+        blocksize: usize,
+        blocks: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut frame = vec![2, 1, flags, typesize];
+        frame.extend((nbytes as u32).to_le_bytes());
+        frame.extend((blocksize as u32).to_le_bytes());
+        frame.extend([0; 4]);
+        let mut start = HEADER_SIZE + 4 * blocks.len();
+        for block in blocks {
+            frame.extend((start as u32).to_le_bytes());
+            start += block.len();
+        }
+        for block in blocks {
+            frame.extend(block);
+        }
+        let cbytes = frame.len() as u32;
+        frame[12..16].copy_from_slice(&cbytes.to_le_bytes());
+        frame
+    }
+
+    /// A block of `data` cut into `splits` streams, each stored as is when
+    /// compressing it does not make it smaller, as c-blosc does.
+    fn block(data: &[u8], splits: usize, compress: impl Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+        let mut block = Vec::new();
+        for stream in data.chunks(data.len() / splits) {
+            let compressed = compress(stream);
+            let stored = if compressed.len() < stream.len() {
+                compressed
+            } else {
+                stream.to_vec()
+            };
+            block.extend((stored.len() as u32).to_le_bytes());
+            block.extend(stored);
+        }
+        block
+    }
+
+    fn lz4(data: &[u8]) -> Vec<u8> {
+        lz4_flex::block::compress(data)
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        zstd::bulk::compress(data, 3).unwrap()
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Byte `n` of every element, then byte `n + 1`.
+    fn shuffle(data: &[u8], typesize: usize) -> Vec<u8> {
+        let elements = data.len() / typesize;
+        let mut out: Vec<u8> = (0..typesize)
+            .flat_map(|byte| (0..elements).map(move |element| (byte, element)))
+            .map(|(byte, element)| data[element * typesize + byte])
+            .collect();
+        out.extend(&data[elements * typesize..]);
+        out
+    }
+
+    /// Bit `n` of every element, eight elements to a byte, for a whole
+    /// number of groups of eight elements.
+    fn bitshuffle(data: &[u8], typesize: usize) -> Vec<u8> {
+        let elements = data.len() / typesize;
+        assert!(elements.is_multiple_of(8));
+        let mut out = vec![0; data.len()];
+        for bit in 0..typesize * 8 {
+            for element in 0..elements {
+                let value = (data[element * typesize + bit / 8] >> (bit % 8)) & 1;
+                out[bit * elements / 8 + element / 8] |= value << (element % 8);
+            }
+        }
+        out
+    }
+
+    fn u16_bytes(values: impl Iterator<Item = u16>) -> Vec<u8> {
+        values.flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn bit_shuffles_as_in_the_format_description() {
+        // Bit n of eight one byte elements lands in bit n of byte n
+        let data = [1, 2, 4, 8, 16, 32, 64, 128];
+        assert_eq!(bitshuffle(&data, 1), data);
+        // The lowest bit of the first element, then the highest of the last
+        let data = u16_bytes([1, 0, 0, 0, 0, 0, 0, 0x8000].into_iter());
+        let mut expected = vec![0; 16];
+        expected[0] = 0x01;
+        expected[15] = 0x80;
+        assert_eq!(bitshuffle(&data, 2), expected);
+        assert_eq!(shuffle(&data, 2)[..4], [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn reads_memcpyed_frame() {
+        let data = b"stored without compression".to_vec();
+        let mut src = frame(MEMCPYED | DOSHUFFLE, 2, data.len(), 256, &[]);
+        src.truncate(HEADER_SIZE);
+        src.extend(&data);
+
+        assert_eq!(decompress(&src).unwrap(), data);
+    }
+
+    #[test]
+    fn reads_shuffled_split_blocks_and_leftover() {
+        // Two split blocks of 128 elements, then a leftover that is not split
+        let data = u16_bytes(0..300);
+        let blocks: Vec<Vec<u8>> = data
+            .chunks(256)
+            .map(|chunk| {
+                let splits = if chunk.len() == 256 { 2 } else { 1 };
+                block(&shuffle(chunk, 2), splits, lz4)
+            })
+            .collect();
+        let src = frame(LZ4 | DOSHUFFLE, 2, data.len(), 256, &blocks);
+
+        assert_eq!(decompress(&src).unwrap(), data);
+    }
+
+    #[test]
+    fn reads_shuffled_blocks_that_are_not_split() {
+        let data: Vec<u8> = (0..100u32).flat_map(|v| (v * 7919).to_le_bytes()).collect();
+        let blocks: Vec<Vec<u8>> = data
+            .chunks(128)
+            .map(|chunk| block(&shuffle(chunk, 4), 1, zstd))
+            .collect();
+        let src = frame(ZSTD | DOSHUFFLE | DONT_SPLIT, 4, data.len(), 128, &blocks);
+
+        assert_eq!(decompress(&src).unwrap(), data);
+    }
+
+    #[test]
+    fn reads_bit_shuffled_blocks_and_leftover() {
+        // The leftover block of three elements is stored without shuffling
+        let data = u16_bytes((0..19).map(|v| v * 1000));
+        let mut blocks: Vec<Vec<u8>> = data[..32]
+            .chunks(16)
+            .map(|chunk| block(&bitshuffle(chunk, 2), 1, zlib))
+            .collect();
+        blocks.push(block(&data[32..], 1, zlib));
+        let src = frame(ZLIB | DOBITSHUFFLE | DONT_SPLIT, 2, data.len(), 16, &blocks);
+
+        assert_eq!(decompress(&src).unwrap(), data);
+    }
+
+    #[test]
+    fn bit_unshuffles_whole_groups_of_later_formats() {
+        let data = u16_bytes(1000..1010);
+        let mut shuffled = bitshuffle(&data[..16], 2);
+        shuffled.extend(&data[16..]);
+
+        let mut out = Vec::new();
+        bitunshuffle(&shuffled, 2, 3, &mut out);
+        assert_eq!(out, data);
+
+        // Blocks of Blosc 1 frames are only shuffled as a whole
+        out.clear();
+        bitunshuffle(&shuffled, 2, 2, &mut out);
+        assert_eq!(out, shuffled);
+    }
+
+    #[test]
+    fn reads_blosclz_literals_and_matches() {
+        let stream = [
+            // Three literals, with the compression level in the top bits
+            0x20 | 2,
+            b'a',
+            b'b',
+            b'c',
+            // Four bytes from three back
+            2 << 5,
+            2,
+            // Nine bytes from three back, with an extended length
+            7 << 5,
+            0,
+            2,
+            // One literal
+            0,
+            b'X',
+        ];
+        let mut block = (stream.len() as u32).to_le_bytes().to_vec();
+        block.extend(stream);
+        let src = frame(0, 1, 17, 17, &[block]);
+
+        assert_eq!(decompress(&src).unwrap(), b"abcabcabcabcabcaX");
+    }
+
+    #[test]
+    fn rejects_unsupported_frames() {
+        let data = u16_bytes(0..8);
+        let blocks = [block(&data, 1, lz4)];
+
+        let delta = frame(LZ4 | DOSHUFFLE | DODELTA, 2, 16, 16, &blocks);
+        assert!(decompress(&delta)
+            .unwrap_err()
+            .to_string()
+            .contains("delta"));
+
+        let snappy = frame((2 << 5) | DONT_SPLIT, 2, 16, 16, &blocks);
+        assert!(decompress(&snappy)
+            .unwrap_err()
+            .to_string()
+            .contains("snappy"));
+
+        let mut truncated = frame(LZ4 | DONT_SPLIT, 2, 16, 16, &blocks);
+        truncated.truncate(truncated.len() - 1);
+        assert!(decompress(&truncated).is_err());
+        assert!(decompress(&truncated[..8]).is_err());
+    }
+}
@@ -1,50 +1,402 @@
 // original source: https://github.com/davazp/webgpu-and-volume-rendering/blob/main/src/api/dicom.ts
 
-pub type Vec3 = [f32; 3];
+use crate::image_volume::{cross, dot, ImageVolume, Vec3, VolumeMetadata};
+use dicom::encoding::transfer_syntax::Codec;
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::mem::InMemElement;
+use dicom::object::{open_file, DefaultDicomObject, InMemDicomObject};
+use dicom::pixeldata::PixelDecoder;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
+/// Positions closer than this along the slice normal, in mm, are duplicates.
+const DUPLICATE_POSITION_TOLERANCE: f32 = 1e-3;
+/// Relative deviation from the nominal slice spacing that is still uniform.
+const SPACING_TOLERANCE: f32 = 0.01;
+/// Spacing larger than this multiple of the nominal spacing is a gap.
+const GAP_FACTOR: f32 = 1.5;
+/// Angle in degrees between the slice normal and the stacking direction
+/// above which the series is considered acquired with a tilted gantry.
+const GANTRY_TILT_TOLERANCE: f32 = 0.05;
+/// Maximum difference between direction cosines of slices in the same stack.
+const ORIENTATION_TOLERANCE: f32 = 1e-4;
+/// Maximum relative difference between the pixel spacing of slices.
+const PIXEL_SPACING_TOLERANCE: f32 = 1e-4;
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Resample non uniformly spaced slices onto a regular grid
+    pub resample: bool,
+    /// Shift tilted gantry slices in plane so the grid becomes orthogonal
+    pub correct_gantry_tilt: bool,
+    /// Receives a message every time a file has been read
+    pub progress: Option<Sender<LoadProgress>>,
+    /// Stops reading further files once cancelled
+    pub cancel: Option<CancellationToken>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Shared flag used to stop a load in progress from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Problems in the slice geometry that did not prevent loading.
+#[derive(Debug, Clone)]
+pub enum LoadWarning {
+    /// One or more slices seem to be missing before `file`
+    SliceGap {
+        file: PathBuf,
+        spacing: f32,
+        expected: f32,
+    },
+    /// The distance to the slice before `file` differs from the nominal spacing
+    IrregularSpacing {
+        file: PathBuf,
+        spacing: f32,
+        expected: f32,
+    },
+    /// Slices are stacked at `angle` degrees from their normal
+    GantryTilt { angle: f32 },
+}
+
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadWarning::SliceGap {
+                file,
+                spacing,
+                expected,
+            } => write!(
+                f,
+                "Gap of {} mm before {}, expected {} mm",
+                spacing,
+                file.display(),
+                expected
+            ),
+            LoadWarning::IrregularSpacing {
+                file,
+                spacing,
+                expected,
+            } => write!(
+                f,
+                "Irregular spacing of {} mm before {}, expected {} mm",
+                spacing,
+                file.display(),
+                expected
+            ),
+            LoadWarning::GantryTilt { angle } => write!(f, "Gantry tilt of {} degrees", angle),
+        }
+    }
+}
+
+/// Reasons a set of files could not be loaded as a volume.
 #[derive(Debug)]
-#[allow(dead_code)]
-pub struct ImageVolume {
-    pub columns: u16,
-    pub rows: u16,
-    pub slices: usize,
-    pub pixel_spacing: Vec3,
-    pub position_patient: Vec3,
-    pub image_orientation_patient: [Vec3; 3],
-    pub volume: Vec<f32>,
+pub enum DicomLoadError {
+    /// The file could not be read as a DICOM file
+    Open {
+        file: PathBuf,
+        source: Box<dicom::object::ReadError>,
+    },
+    /// A required attribute is not present
+    MissingTag { file: PathBuf, tag: &'static str },
+    /// An attribute has the wrong number of values or cannot be converted
+    InvalidTag { file: PathBuf, tag: &'static str },
+    /// The pixel data is encoded with a transfer syntax that cannot be decoded
+    UnsupportedTransferSyntax { file: PathBuf, uid: String },
+    /// The pixel data cannot be unpacked with the declared layout
+    InvalidPixelData { file: PathBuf, reason: String },
+    /// Rows or columns differ from the first slice
+    InconsistentDimensions {
+        file: PathBuf,
+        expected: (u16, u16),
+        found: (u16, u16),
+    },
+    /// Pixel spacing differs from the first slice
+    InconsistentPixelSpacing {
+        file: PathBuf,
+        expected: [f32; 2],
+        found: [f32; 2],
+    },
+    /// Image orientation differs from the first slice
+    InconsistentOrientation {
+        file: PathBuf,
+        expected: [Vec3; 2],
+        found: [Vec3; 2],
+    },
+    /// The position along the slice normal is not a finite number
+    InvalidSliceLocation { file: PathBuf },
+    /// Two slices share the same position along the slice normal
+    DuplicatePosition {
+        file: PathBuf,
+        other: PathBuf,
+        location: f32,
+    },
+    /// Less than two slices were found in `files`
+    TooFewSlices { files: Vec<PathBuf> },
+    /// The load was cancelled through its cancellation token
+    Cancelled,
 }
 
-use anyhow::{anyhow, Result};
-use dicom::object::{open_file, DefaultDicomObject};
-use std::path::Path;
+impl DicomLoadError {
+    fn missing(file: &Path, tag: &'static str) -> Self {
+        DicomLoadError::MissingTag {
+            file: file.to_path_buf(),
+            tag,
+        }
+    }
+
+    fn invalid(file: &Path, tag: &'static str) -> Self {
+        DicomLoadError::InvalidTag {
+            file: file.to_path_buf(),
+            tag,
+        }
+    }
+
+    fn invalid_pixel_data(file: &Path, reason: String) -> Self {
+        DicomLoadError::InvalidPixelData {
+            file: file.to_path_buf(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for DicomLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DicomLoadError::Open { file, source } => {
+                write!(f, "Failed to open {}: {}", file.display(), source)
+            }
+            DicomLoadError::MissingTag { file, tag } => {
+                write!(f, "Missing {} in {}", tag, file.display())
+            }
+            DicomLoadError::InvalidTag { file, tag } => {
+                write!(f, "Invalid {} in {}", tag, file.display())
+            }
+            DicomLoadError::UnsupportedTransferSyntax { file, uid } => {
+                write!(
+                    f,
+                    "Unsupported transfer syntax {} in {}",
+                    uid,
+                    file.display()
+                )
+            }
+            DicomLoadError::InvalidPixelData { file, reason } => {
+                write!(f, "Invalid pixel data in {}: {}", file.display(), reason)
+            }
+            DicomLoadError::InconsistentDimensions {
+                file,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Slice {} is {}x{}, expected {}x{}",
+                file.display(),
+                found.0,
+                found.1,
+                expected.0,
+                expected.1
+            ),
+            DicomLoadError::InconsistentPixelSpacing {
+                file,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Slice {} has pixel spacing {:?}, expected {:?}",
+                file.display(),
+                found,
+                expected
+            ),
+            DicomLoadError::InconsistentOrientation {
+                file,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Slice {} has orientation {:?}, expected {:?}",
+                file.display(),
+                found,
+                expected
+            ),
+            DicomLoadError::InvalidSliceLocation { file } => {
+                write!(f, "Invalid slice location in {}", file.display())
+            }
+            DicomLoadError::DuplicatePosition {
+                file,
+                other,
+                location,
+            } => write!(
+                f,
+                "Duplicate slice position {} in {} and {}",
+                location,
+                other.display(),
+                file.display()
+            ),
+            DicomLoadError::TooFewSlices { files } => {
+                write!(f, "Need at least two slices, found {} files", files.len())
+            }
+            DicomLoadError::Cancelled => write!(f, "Loading cancelled"),
+        }
+    }
+}
+
+impl Error for DicomLoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DicomLoadError::Open { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 struct DicomSlice {
+    file: PathBuf,
+    sop_instance_uid: String,
+    metadata: VolumeMetadata,
     columns: u16,
     rows: u16,
-    slice_location: f32,
     pixel_spacing: [f32; 2],
     position_patient: Vec3,
     image_orientation_patient: [Vec3; 2],
     image: Vec<f32>,
 }
 
-pub fn load_dicom_image<P: AsRef<Path>>(files: &[P]) -> Result<ImageVolume> {
+/// Build a volume from the slices in `files`, which can be one file per slice
+/// or a single Enhanced CT/MR multi-frame file.
+pub fn load_dicom_image<P: AsRef<Path> + Sync>(
+    files: &[P],
+    options: &LoadOptions,
+) -> Result<(ImageVolume, Vec<LoadWarning>), DicomLoadError> {
+    load_slices(
+        files,
+        options,
+        |file| file.as_ref().to_path_buf(),
+        |file| read_dicom_file(file),
+    )
+}
+
+/// Build a volume from objects already in memory, such as instances
+/// retrieved over the network. Each object is paired with the name used for
+/// it in errors and warnings.
+pub fn load_dicom_objects(
+    objects: &[(PathBuf, DefaultDicomObject)],
+    options: &LoadOptions,
+) -> Result<(ImageVolume, Vec<LoadWarning>), DicomLoadError> {
+    load_slices(
+        objects,
+        options,
+        |(name, _)| name.clone(),
+        |(name, obj)| read_dicom_object(name, obj),
+    )
+}
+
+fn load_slices<T: Sync>(
+    files: &[T],
+    options: &LoadOptions,
+    name: impl Fn(&T) -> PathBuf,
+    read: impl Fn(&T) -> Result<Vec<DicomSlice>, DicomLoadError> + Sync,
+) -> Result<(ImageVolume, Vec<LoadWarning>), DicomLoadError> {
+    // Parse files in parallel, reporting each one done
+    let done = AtomicUsize::new(0);
     let mut slices: Vec<DicomSlice> = files
-        .iter()
-        .map(|file| read_single_image(file))
-        .collect::<Result<Vec<_>>>()?;
+        .par_iter()
+        .map(|file| {
+            if options
+                .cancel
+                .as_ref()
+                .is_some_and(|cancel| cancel.is_cancelled())
+            {
+                return Err(DicomLoadError::Cancelled);
+            }
+
+            let slices = read(file)?;
+
+            if let Some(progress) = &options.progress {
+                // The receiver may have stopped listening, which is fine
+                let _ = progress.send(LoadProgress {
+                    done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                    total: files.len(),
+                });
+            }
 
-    // Sort slices by location
-    slices.sort_by(|a, b| a.slice_location.partial_cmp(&b.slice_location).unwrap());
+            Ok(slices)
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten()
+        .collect();
 
     if slices.len() < 2 {
-        return Err(anyhow!("Need at least two slices"));
+        return Err(DicomLoadError::TooFewSlices {
+            files: files.iter().map(name).collect(),
+        });
+    }
+
+    // Verify every slice matches the first one
+    for slice in &slices[1..] {
+        check_consistency(&slices[0], slice)?;
     }
 
+    // Sort slices by position along the slice normal
+    let [row_direction, column_direction] = slices[0].image_orientation_patient;
+    let normal = cross(row_direction, column_direction);
+    let location = |slice: &DicomSlice| dot(slice.position_patient, normal);
+
+    if let Some(slice) = slices.iter().find(|slice| !location(slice).is_finite()) {
+        return Err(DicomLoadError::InvalidSliceLocation {
+            file: slice.file.clone(),
+        });
+    }
+
+    slices.sort_by(|a, b| location(a).total_cmp(&location(b)));
+
+    if let Some(pair) = slices
+        .windows(2)
+        .find(|pair| location(&pair[1]) - location(&pair[0]) < DUPLICATE_POSITION_TOLERANCE)
+    {
+        return Err(DicomLoadError::DuplicatePosition {
+            file: pair[1].file.clone(),
+            other: pair[0].file.clone(),
+            location: location(&pair[0]),
+        });
+    }
+
+    let locations: Vec<f32> = slices.iter().map(location).collect();
+    let (nominal_spacing, mut warnings) = check_slice_spacing(&slices, &locations);
+
+    // Resample onto the nominal spacing only when the slices are not uniform
+    let slice_count = if options.resample && !warnings.is_empty() {
+        let extent = locations[locations.len() - 1] - locations[0];
+        (extent / nominal_spacing).round() as usize + 1
+    } else {
+        slices.len()
+    };
+
     let first_slice = slices.first().unwrap();
     let last_slice = slices.last().unwrap();
 
-    // Verify consistent dimensions
     let columns = first_slice.columns;
     let rows = first_slice.rows;
     let pixel_spacing_2d = first_slice.pixel_spacing;
@@ -52,105 +404,1427 @@ pub fn load_dicom_image<P: AsRef<Path>>(files: &[P]) -> Result<ImageVolume> {
     // Calculate interslice spacing
     let interslice_vector = [
         (last_slice.position_patient[0] - first_slice.position_patient[0])
-            / (slices.len() - 1) as f32,
+            / (slice_count - 1) as f32,
         (last_slice.position_patient[1] - first_slice.position_patient[1])
-            / (slices.len() - 1) as f32,
+            / (slice_count - 1) as f32,
         (last_slice.position_patient[2] - first_slice.position_patient[2])
-            / (slices.len() - 1) as f32,
+            / (slice_count - 1) as f32,
     ];
 
-    let pixel_spacing_z = (interslice_vector[0].powi(2)
+    let mut pixel_spacing_z = (interslice_vector[0].powi(2)
         + interslice_vector[1].powi(2)
         + interslice_vector[2].powi(2))
     .sqrt();
 
-    let pixel_spacing = [pixel_spacing_2d[0], pixel_spacing_2d[1], pixel_spacing_z];
+    let mut slice_direction = [
+        interslice_vector[0] / pixel_spacing_z,
+        interslice_vector[1] / pixel_spacing_z,
+        interslice_vector[2] / pixel_spacing_z,
+    ];
+
+    // A tilted gantry stacks the slices at an angle to their normal
+    let gantry_tilt = dot(slice_direction, normal)
+        .clamp(-1., 1.)
+        .acos()
+        .to_degrees();
+    let correct_gantry_tilt = gantry_tilt > GANTRY_TILT_TOLERANCE;
+    if correct_gantry_tilt {
+        warnings.push(LoadWarning::GantryTilt { angle: gantry_tilt });
+    }
 
     // Combine all slice data into volume
     let slice_size = (columns as usize) * (rows as usize);
-    let mut volume = Vec::with_capacity(slice_size * slices.len());
+    let mut volume = Vec::with_capacity(slice_size * slice_count);
+
+    if slice_count == slices.len() {
+        for slice in &slices {
+            volume.extend(&slice.image);
+        }
+    } else {
+        let step = (locations[locations.len() - 1] - locations[0]) / (slice_count - 1) as f32;
+        for k in 0..slice_count {
+            let target = locations[0] + k as f32 * step;
+            // Linear interpolation between the two slices around the target
+            let upper = locations
+                .partition_point(|&l| l < target)
+                .clamp(1, slices.len() - 1);
+            let lower = upper - 1;
+            let t =
+                ((target - locations[lower]) / (locations[upper] - locations[lower])).clamp(0., 1.);
+            volume.extend(
+                slices[lower]
+                    .image
+                    .iter()
+                    .zip(&slices[upper].image)
+                    .map(|(a, b)| a + (b - a) * t),
+            );
+        }
+    }
+
+    if correct_gantry_tilt && options.correct_gantry_tilt {
+        // Split the interslice vector into its part along the normal and the
+        // in plane shear that each slice adds to the previous one
+        let spacing_along_normal = dot(interslice_vector, normal);
+        let shear = [
+            interslice_vector[0] - normal[0] * spacing_along_normal,
+            interslice_vector[1] - normal[1] * spacing_along_normal,
+            interslice_vector[2] - normal[2] * spacing_along_normal,
+        ];
+        let shift = [
+            dot(shear, row_direction) / pixel_spacing_2d[1],
+            dot(shear, column_direction) / pixel_spacing_2d[0],
+        ];
+        deshear_volume(&mut volume, columns as usize, rows as usize, shift);
 
-    for slice in &slices {
-        volume.extend(&slice.image);
+        pixel_spacing_z = spacing_along_normal;
+        slice_direction = normal;
     }
 
+    // PixelSpacing holds the distance between rows first, then between columns
+    let pixel_spacing = [pixel_spacing_2d[1], pixel_spacing_2d[0], pixel_spacing_z];
+
     let image_orientation_patient = [
         first_slice.image_orientation_patient[0],
         first_slice.image_orientation_patient[1],
-        [
-            interslice_vector[0] / pixel_spacing_z,
-            interslice_vector[1] / pixel_spacing_z,
-            interslice_vector[2] / pixel_spacing_z,
-        ],
+        slice_direction,
     ];
 
-    Ok(ImageVolume {
-        columns,
-        rows,
-        slices: slices.len(),
-        pixel_spacing,
-        position_patient: first_slice.position_patient,
-        image_orientation_patient,
-        volume,
-    })
+    Ok((
+        ImageVolume {
+            columns,
+            rows,
+            slices: slice_count,
+            pixel_spacing,
+            position_patient: first_slice.position_patient,
+            image_orientation_patient,
+            volume,
+            metadata: VolumeMetadata {
+                // Interpolated slices have no source image of their own
+                sop_instance_uids: if slice_count == slices.len() {
+                    slices
+                        .iter()
+                        .map(|slice| slice.sop_instance_uid.clone())
+                        .collect()
+                } else {
+                    Vec::new()
+                },
+                ..first_slice.metadata.clone()
+            },
+        },
+        warnings,
+    ))
+}
+
+fn check_consistency(first: &DicomSlice, slice: &DicomSlice) -> Result<(), DicomLoadError> {
+    if (slice.columns, slice.rows) != (first.columns, first.rows) {
+        return Err(DicomLoadError::InconsistentDimensions {
+            file: slice.file.clone(),
+            expected: (first.columns, first.rows),
+            found: (slice.columns, slice.rows),
+        });
+    }
+
+    let spacing_matches = first
+        .pixel_spacing
+        .iter()
+        .zip(&slice.pixel_spacing)
+        .all(|(a, b)| (a - b).abs() <= a.abs() * PIXEL_SPACING_TOLERANCE);
+    if !spacing_matches {
+        return Err(DicomLoadError::InconsistentPixelSpacing {
+            file: slice.file.clone(),
+            expected: first.pixel_spacing,
+            found: slice.pixel_spacing,
+        });
+    }
+
+    let orientation_matches = first
+        .image_orientation_patient
+        .iter()
+        .flatten()
+        .zip(slice.image_orientation_patient.iter().flatten())
+        .all(|(a, b)| (a - b).abs() <= ORIENTATION_TOLERANCE);
+    if !orientation_matches {
+        return Err(DicomLoadError::InconsistentOrientation {
+            file: slice.file.clone(),
+            expected: first.image_orientation_patient,
+            found: slice.image_orientation_patient,
+        });
+    }
+
+    Ok(())
+}
+
+/// Resample every slice by moving it back `shift` pixels per slice index,
+/// using bilinear interpolation and padding with the volume minimum.
+fn deshear_volume(volume: &mut [f32], columns: usize, rows: usize, shift: [f32; 2]) {
+    let slice_size = columns * rows;
+    let padding = volume.iter().copied().fold(f32::INFINITY, f32::min);
+    let mut resampled = vec![0.; slice_size];
+
+    for (k, slice) in volume.chunks_exact_mut(slice_size).enumerate() {
+        let offset_x = k as f32 * shift[0];
+        let offset_y = k as f32 * shift[1];
+
+        for j in 0..rows {
+            for i in 0..columns {
+                let x = i as f32 - offset_x;
+                let y = j as f32 - offset_y;
+                let x0 = x.floor();
+                let y0 = y.floor();
+                let tx = x - x0;
+                let ty = y - y0;
+
+                let sample = |xi: f32, yi: f32| {
+                    if xi < 0. || yi < 0. || xi >= columns as f32 || yi >= rows as f32 {
+                        padding
+                    } else {
+                        slice[yi as usize * columns + xi as usize]
+                    }
+                };
+
+                let top = sample(x0, y0) * (1. - tx) + sample(x0 + 1., y0) * tx;
+                let bottom = sample(x0, y0 + 1.) * (1. - tx) + sample(x0 + 1., y0 + 1.) * tx;
+                resampled[j * columns + i] = top * (1. - ty) + bottom * ty;
+            }
+        }
+
+        slice.copy_from_slice(&resampled);
+    }
 }
 
-fn read_single_image<P: AsRef<Path>>(file: P) -> Result<DicomSlice> {
-    let obj: DefaultDicomObject = open_file(file)?;
+/// Compare the distance between neighbouring slices against the median
+/// distance, returning the median and any deviations from it.
+fn check_slice_spacing(slices: &[DicomSlice], locations: &[f32]) -> (f32, Vec<LoadWarning>) {
+    let spacings: Vec<f32> = locations.windows(2).map(|pair| pair[1] - pair[0]).collect();
+
+    let mut sorted = spacings.clone();
+    sorted.sort_by(f32::total_cmp);
+    let expected = sorted[sorted.len() / 2];
+
+    let warnings = spacings
+        .iter()
+        .zip(&slices[1..])
+        .filter_map(|(&spacing, slice)| {
+            let file = slice.file.clone();
+            if spacing > expected * GAP_FACTOR {
+                Some(LoadWarning::SliceGap {
+                    file,
+                    spacing,
+                    expected,
+                })
+            } else if (spacing - expected).abs() > expected * SPACING_TOLERANCE {
+                Some(LoadWarning::IrregularSpacing {
+                    file,
+                    spacing,
+                    expected,
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    (expected, warnings)
+}
+
+/// Read every frame stored in a file as a slice. Classic images hold a
+/// single frame, Enhanced CT/MR objects hold the whole stack and describe
+/// each frame through their functional groups.
+fn read_dicom_file<P: AsRef<Path>>(file: P) -> Result<Vec<DicomSlice>, DicomLoadError> {
+    let file = file.as_ref().to_path_buf();
+    let obj: DefaultDicomObject = open_file(&file).map_err(|source| DicomLoadError::Open {
+        file: file.clone(),
+        source: Box::new(source),
+    })?;
+    read_dicom_object(&file, &obj)
+}
+
+fn read_dicom_object(
+    file: &Path,
+    obj: &DefaultDicomObject,
+) -> Result<Vec<DicomSlice>, DicomLoadError> {
+    let file = file.to_path_buf();
+
+    let read_u16 = |tag: &'static str| {
+        obj.element_by_name(tag)
+            .map_err(|_| DicomLoadError::missing(&file, tag))?
+            .uint16()
+            .map_err(|_| DicomLoadError::invalid(&file, tag))
+    };
+
+    let columns = read_u16("Columns")?;
+    let rows = read_u16("Rows")?;
+    let sop_instance_uid = read_string(obj, "SOPInstanceUID");
+    let metadata = read_metadata(obj);
+    let number_of_frames = obj
+        .element_by_name("NumberOfFrames")
+        .map_or(1, |elem| elem.to_int::<usize>().unwrap_or(1));
+
+    // Read pixel format
+    let pixel_format = PixelFormat {
+        bits_allocated: read_u16("BitsAllocated")?,
+        bits_stored: read_u16("BitsStored")?,
+        high_bit: read_u16("HighBit")?,
+        signed: read_u16("PixelRepresentation")? == 1,
+    };
+
+    // Read pixel data of all frames
+    let frame_size = columns as usize * rows as usize;
+    // RT Dose scales its stored values to dose with one factor
+    let dose_grid_scaling = obj
+        .element_by_name("DoseGridScaling")
+        .map_or(1.0, |elem| elem.to_float32().unwrap_or(1.0));
+    let pixel_data = read_pixel_bytes(&file, obj)?;
+    let pixels = decode_pixels(&pixel_data, &pixel_format, frame_size * number_of_frames)
+        .map_err(|reason| DicomLoadError::invalid_pixel_data(&file, reason))?;
+
+    let frames: Vec<FrameGeometry> = match obj.element_by_name("PerFrameFunctionalGroupsSequence") {
+        Ok(per_frame) => {
+            let shared = obj
+                .element_by_name("SharedFunctionalGroupsSequence")
+                .ok()
+                .and_then(|elem| elem.items())
+                .and_then(|items| items.first());
+            per_frame
+                .items()
+                .unwrap_or_default()
+                .iter()
+                .take(number_of_frames)
+                .map(|frame| {
+                    read_frame_geometry(&file, |sequence, name| {
+                        // Per frame values take precedence over shared ones
+                        std::iter::once(frame)
+                            .chain(shared)
+                            .find_map(|group| functional_group_element(group, sequence, name))
+                    })
+                })
+                .collect::<Result<_, _>>()?
+        }
+        Err(_) => {
+            let frame = read_frame_geometry(&file, |_, name| obj.element_by_name(name).ok())?;
+            match obj.element_by_name("GridFrameOffsetVector") {
+                Ok(offsets) if number_of_frames > 1 => {
+                    grid_frames(&file, frame, offsets, number_of_frames)?
+                }
+                _ => vec![frame],
+            }
+        }
+    };
+
+    if frames.len() != number_of_frames {
+        return Err(DicomLoadError::missing(
+            &file,
+            "PerFrameFunctionalGroupsSequence",
+        ));
+    }
+
+    let slices = frames
+        .into_iter()
+        .zip(pixels.chunks_exact(frame_size))
+        .map(|(frame, pixels)| DicomSlice {
+            file: file.clone(),
+            sop_instance_uid: sop_instance_uid.clone(),
+            metadata: metadata.clone(),
+            columns,
+            rows,
+            pixel_spacing: frame.pixel_spacing,
+            position_patient: frame.position_patient,
+            image_orientation_patient: frame.image_orientation_patient,
+            // Convert pixel data to f32 with rescale
+            image: pixels
+                .iter()
+                .map(|&x| {
+                    ((x as f32) * frame.rescale_slope + frame.rescale_intercept) * dose_grid_scaling
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(slices)
+}
+
+fn read_metadata(obj: &InMemDicomObject) -> VolumeMetadata {
+    let read_float = |name: &str| {
+        obj.element_by_name(name)
+            .ok()
+            .and_then(|elem| elem.to_float32().ok())
+    };
+
+    VolumeMetadata {
+        patient_name: read_string(obj, "PatientName"),
+        patient_id: read_string(obj, "PatientID"),
+        patient_birth_date: read_string(obj, "PatientBirthDate"),
+        patient_sex: read_string(obj, "PatientSex"),
+        study_date: read_string(obj, "StudyDate"),
+        study_time: read_string(obj, "StudyTime"),
+        study_id: read_string(obj, "StudyID"),
+        study_description: read_string(obj, "StudyDescription"),
+        accession_number: read_string(obj, "AccessionNumber"),
+        modality: read_string(obj, "Modality"),
+        series_description: read_string(obj, "SeriesDescription"),
+        series_number: read_string(obj, "SeriesNumber"),
+        manufacturer: read_string(obj, "Manufacturer"),
+        kvp: read_float("KVP"),
+        slice_thickness: read_float("SliceThickness"),
+        // Only the first window is kept when several are present
+        window_center: read_float("WindowCenter"),
+        window_width: read_float("WindowWidth"),
+        study_instance_uid: read_string(obj, "StudyInstanceUID"),
+        series_instance_uid: read_string(obj, "SeriesInstanceUID"),
+        frame_of_reference_uid: read_string(obj, "FrameOfReferenceUID"),
+        sop_instance_uids: Vec::new(),
+    }
+}
+
+/// Read a text attribute with padding removed, or an empty string if absent.
+pub(crate) fn read_string(obj: &InMemDicomObject, name: &str) -> String {
+    obj.element_by_name(name)
+        .ok()
+        .and_then(|elem| elem.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Geometry and rescale of a single frame.
+#[derive(Clone, Copy)]
+struct FrameGeometry {
+    pixel_spacing: [f32; 2],
+    position_patient: Vec3,
+    image_orientation_patient: [Vec3; 2],
+    rescale_slope: f32,
+    rescale_intercept: f32,
+}
+
+/// Read the attributes of a frame, using `lookup` to find an attribute by
+/// name given the functional group sequence that holds it in enhanced objects.
+fn read_frame_geometry<'a>(
+    file: &Path,
+    lookup: impl Fn(&str, &str) -> Option<&'a InMemElement>,
+) -> Result<FrameGeometry, DicomLoadError> {
+    let read_floats = |sequence: &str, tag: &'static str| {
+        lookup(sequence, tag)
+            .ok_or_else(|| DicomLoadError::missing(file, tag))?
+            .to_multi_float32()
+            .map_err(|_| DicomLoadError::invalid(file, tag))
+    };
 
-    let columns = obj.element_by_name("Columns")?.uint16()?;
-    let rows = obj.element_by_name("Rows")?.uint16()?;
     // Read position patient
-    let position_patient: [f32; 3] = obj
-        .element_by_name("ImagePositionPatient")?
-        .to_multi_float32()?
+    let position_patient: [f32; 3] = read_floats("PlanePositionSequence", "ImagePositionPatient")?
         .try_into()
-        .map_err(|_| anyhow!("Invalid ImagePositionPatient length"))?;
-
-    let slice_location = obj
-        .element_by_name("SliceLocation")
-        .map_or(position_patient[2], |elem| elem.to_float32().unwrap());
+        .map_err(|_| DicomLoadError::invalid(file, "ImagePositionPatient"))?;
 
     // Read pixel spacing
-    let pixel_spacing: [f32; 2] = obj
-        .element_by_name("PixelSpacing")?
-        .to_multi_float32()?
+    let pixel_spacing: [f32; 2] = read_floats("PixelMeasuresSequence", "PixelSpacing")?
         .try_into()
-        .map_err(|_| anyhow!("Invalid PixelSpacing length"))?;
+        .map_err(|_| DicomLoadError::invalid(file, "PixelSpacing"))?;
 
     // Read orientation
-    let orientation: Vec<f32> = obj
-        .element_by_name("ImageOrientationPatient")?
-        .to_multi_float32()?;
+    let orientation: [f32; 6] = read_floats("PlaneOrientationSequence", "ImageOrientationPatient")?
+        .try_into()
+        .map_err(|_| DicomLoadError::invalid(file, "ImageOrientationPatient"))?;
 
     let image_orientation_patient = [
         [orientation[0], orientation[1], orientation[2]],
         [orientation[3], orientation[4], orientation[5]],
     ];
 
-    // Read pixel data and apply rescale
-    let pixel_data = obj.element_by_name("PixelData")?;
-    let rescale_intercept = obj
-        .element_by_name("RescaleIntercept")
+    // Read rescale
+    let rescale_intercept = lookup("PixelValueTransformationSequence", "RescaleIntercept")
         .map_or(0.0, |elem| elem.to_float32().unwrap_or(0.0));
-    let rescale_slope = obj
-        .element_by_name("RescaleSlope")
+    let rescale_slope = lookup("PixelValueTransformationSequence", "RescaleSlope")
         .map_or(1.0, |elem| elem.to_float32().unwrap_or(1.0));
 
-    // Convert pixel data to f32 with rescale
-    let image: Vec<f32> = pixel_data
-        .to_multi_int::<i16>()?
-        .iter()
-        .map(|&x| (x as f32) * rescale_slope + rescale_intercept)
-        .collect();
-
-    Ok(DicomSlice {
-        columns,
-        rows,
-        slice_location,
+    Ok(FrameGeometry {
         pixel_spacing,
         position_patient,
         image_orientation_patient,
-        image,
+        rescale_slope,
+        rescale_intercept,
     })
 }
+
+/// Frames of a multi-frame RT Dose or NM image, stacked along the normal by
+/// GridFrameOffsetVector. Offsets are relative to the first frame, whether
+/// they start at zero or at its position along the normal.
+fn grid_frames(
+    file: &Path,
+    first: FrameGeometry,
+    offsets: &InMemElement,
+    number_of_frames: usize,
+) -> Result<Vec<FrameGeometry>, DicomLoadError> {
+    let offsets = offsets
+        .to_multi_float32()
+        .ok()
+        .filter(|offsets| offsets.len() == number_of_frames)
+        .ok_or_else(|| DicomLoadError::invalid(file, "GridFrameOffsetVector"))?;
+    let base = offsets[0];
+
+    let [row_direction, column_direction] = first.image_orientation_patient;
+    let normal = cross(row_direction, column_direction);
+    Ok(offsets
+        .iter()
+        .map(|offset| FrameGeometry {
+            position_patient: [0, 1, 2]
+                .map(|i| first.position_patient[i] + (offset - base) * normal[i]),
+            ..first
+        })
+        .collect())
+}
+
+/// Find an attribute inside the first item of a functional group sequence.
+fn functional_group_element<'a>(
+    group: &'a InMemDicomObject,
+    sequence: &str,
+    name: &str,
+) -> Option<&'a InMemElement> {
+    group
+        .element_by_name(sequence)
+        .ok()?
+        .items()?
+        .first()?
+        .element_by_name(name)
+        .ok()
+}
+
+/// Get the stored pixel values of all frames as little endian bytes,
+/// decoding encapsulated pixel data when the transfer syntax requires it.
+/// JPEG-LS and JPEG 2000 are reported as unsupported, since their codecs
+/// are not enabled in dicom-pixeldata.
+fn read_pixel_bytes<'a>(
+    file: &Path,
+    obj: &'a DefaultDicomObject,
+) -> Result<Cow<'a, [u8]>, DicomLoadError> {
+    let uid = obj.meta().transfer_syntax();
+    let unsupported = || DicomLoadError::UnsupportedTransferSyntax {
+        file: file.to_path_buf(),
+        uid: uid.to_string(),
+    };
+    let ts = TransferSyntaxRegistry.get(uid).ok_or_else(unsupported)?;
+
+    // Pixel data is native unless encapsulated, even when the data set
+    // itself was deflated
+    if !matches!(ts.codec(), Codec::EncapsulatedPixelData(..)) {
+        return obj
+            .element_by_name("PixelData")
+            .map_err(|_| DicomLoadError::missing(file, "PixelData"))?
+            .to_bytes()
+            .map_err(|_| DicomLoadError::invalid(file, "PixelData"));
+    }
+
+    if !ts.can_decode_all() {
+        return Err(DicomLoadError::UnsupportedTransferSyntax {
+            file: file.to_path_buf(),
+            uid: format!("{} ({})", ts.name(), ts.uid()),
+        });
+    }
+
+    let decoded = obj.decode_pixel_data().map_err(|e| {
+        DicomLoadError::invalid_pixel_data(file, format!("Failed to decode {}: {}", ts.name(), e))
+    })?;
+
+    Ok(Cow::Owned(decoded.data().to_vec()))
+}
+
+/// Layout of a stored pixel as described by the image pixel module.
+struct PixelFormat {
+    bits_allocated: u16,
+    bits_stored: u16,
+    high_bit: u16,
+    signed: bool,
+}
+
+/// Unpack little endian stored pixel values, masking out the unused bits and
+/// sign extending when PixelRepresentation is two's complement.
+fn decode_pixels(bytes: &[u8], format: &PixelFormat, count: usize) -> Result<Vec<i64>, String> {
+    let bytes_per_pixel = match format.bits_allocated {
+        8 => 1,
+        16 => 2,
+        32 => 4,
+        bits => return Err(format!("Unsupported BitsAllocated {}", bits)),
+    };
+
+    if format.bits_stored == 0
+        || format.bits_stored > format.bits_allocated
+        || format.high_bit >= format.bits_allocated
+        || format.high_bit + 1 < format.bits_stored
+    {
+        return Err(format!(
+            "Invalid pixel layout: BitsStored {}, HighBit {}",
+            format.bits_stored, format.high_bit
+        ));
+    }
+
+    if bytes.len() < count * bytes_per_pixel {
+        return Err(format!(
+            "PixelData too short: expected {} bytes, found {}",
+            count * bytes_per_pixel,
+            bytes.len()
+        ));
+    }
+
+    let shift = format.high_bit + 1 - format.bits_stored;
+    let mask = (1u64 << format.bits_stored) - 1;
+    let sign_bit = 1u64 << (format.bits_stored - 1);
+
+    let pixels = bytes[..count * bytes_per_pixel]
+        .chunks_exact(bytes_per_pixel)
+        .map(|chunk| {
+            let raw = match chunk {
+                [b0] => *b0 as u64,
+                [b0, b1] => u16::from_le_bytes([*b0, *b1]) as u64,
+                _ => u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64,
+            };
+            let value = (raw >> shift) & mask;
+            if format.signed && value & sign_bit != 0 {
+                value as i64 - (1i64 << format.bits_stored)
+            } else {
+                value as i64
+            }
+        })
+        .collect();
+
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        ct_slice, put, sequence, strings, temp_dir, with_meta, write_ct_series, SliceGeometry,
+        FRAME_OF_REFERENCE_UID, SERIES_INSTANCE_UID, STUDY_INSTANCE_UID,
+    };
+    use dicom::core::{PrimitiveValue, Tag, VR};
+    use dicom_dictionary_std::{tags, uids};
+
+    const AXIAL: SliceGeometry = SliceGeometry {
+        columns: 4,
+        rows: 2,
+        pixel_spacing: [0.5, 0.75],
+        position: [-10., -20., 30.],
+        orientation: [1., 0., 0., 0., 1., 0.],
+    };
+
+    fn format(bits_allocated: u16, bits_stored: u16, high_bit: u16, signed: bool) -> PixelFormat {
+        PixelFormat {
+            bits_allocated,
+            bits_stored,
+            high_bit,
+            signed,
+        }
+    }
+
+    fn u16_bytes(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_unsigned_16_bit_pixels() {
+        let bytes = u16_bytes(&[0, 1, 32768, 65535]);
+        let pixels = decode_pixels(&bytes, &format(16, 16, 15, false), 4).unwrap();
+        assert_eq!(pixels, [0, 1, 32768, 65535]);
+    }
+
+    #[test]
+    fn decodes_signed_16_bit_pixels() {
+        let bytes = u16_bytes(&[0, 1, 0x8000, 0xFFFF]);
+        let pixels = decode_pixels(&bytes, &format(16, 16, 15, true), 4).unwrap();
+        assert_eq!(pixels, [0, 1, -32768, -1]);
+    }
+
+    #[test]
+    fn masks_and_sign_extends_12_bit_pixels() {
+        // Bits above the high bit hold unrelated data, such as an overlay
+        let bytes = u16_bytes(&[0xF7FF, 0x0800, 0x1FFF, 0x0001]);
+        let signed = decode_pixels(&bytes, &format(16, 12, 11, true), 4).unwrap();
+        assert_eq!(signed, [2047, -2048, -1, 1]);
+        let unsigned = decode_pixels(&bytes, &format(16, 12, 11, false), 4).unwrap();
+        assert_eq!(unsigned, [2047, 2048, 4095, 1]);
+    }
+
+    #[test]
+    fn shifts_pixels_stored_below_the_high_bit() {
+        let bytes = u16_bytes(&[0xFFF0, 0x0010, 0x000F]);
+        let pixels = decode_pixels(&bytes, &format(16, 12, 15, true), 3).unwrap();
+        assert_eq!(pixels, [-1, 1, 0]);
+    }
+
+    #[test]
+    fn decodes_8_and_32_bit_pixels() {
+        let pixels = decode_pixels(&[0, 127, 128, 255], &format(8, 8, 7, false), 4).unwrap();
+        assert_eq!(pixels, [0, 127, 128, 255]);
+        let pixels = decode_pixels(&[0, 128, 255], &format(8, 8, 7, true), 3).unwrap();
+        assert_eq!(pixels, [0, -128, -1]);
+
+        let bytes: Vec<u8> = [u32::MAX, 70000, 0x8000_0000]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let unsigned = decode_pixels(&bytes, &format(32, 32, 31, false), 3).unwrap();
+        assert_eq!(unsigned, [u32::MAX as i64, 70000, 0x8000_0000]);
+        let signed = decode_pixels(&bytes, &format(32, 32, 31, true), 3).unwrap();
+        assert_eq!(signed, [-1, 70000, i32::MIN as i64]);
+    }
+
+    #[test]
+    fn rejects_invalid_pixel_layouts() {
+        let bytes = u16_bytes(&[0; 4]);
+        assert!(decode_pixels(&bytes, &format(12, 12, 11, false), 4).is_err());
+        assert!(decode_pixels(&bytes, &format(16, 17, 15, false), 4).is_err());
+        assert!(decode_pixels(&bytes, &format(16, 12, 16, false), 4).is_err());
+        assert!(decode_pixels(&bytes, &format(16, 12, 10, false), 4).is_err());
+        assert!(decode_pixels(&bytes, &format(16, 0, 15, false), 4).is_err());
+        assert!(decode_pixels(&bytes, &format(16, 16, 15, false), 5).is_err());
+    }
+
+    #[test]
+    fn applies_rescale_to_stored_values() {
+        let stored = [0, 1, -1, 1024, 2000, -2000, 32767, -32768];
+        let mut obj = ct_slice(0, &AXIAL, &stored);
+        put(&mut obj, tags::RESCALE_SLOPE, VR::DS, "0.5".into());
+        let obj = with_meta(obj, uids::EXPLICIT_VR_LITTLE_ENDIAN);
+
+        let slices = read_dicom_object(Path::new("CT1.dcm"), &obj).unwrap();
+        assert_eq!(slices.len(), 1);
+        let expected: Vec<f32> = stored.iter().map(|&v| v as f32 * 0.5 - 1024.).collect();
+        assert_eq!(slices[0].image, expected);
+    }
+
+    #[test]
+    fn rescales_unsigned_8_bit_pixels() {
+        let mut obj = ct_slice(0, &AXIAL, &[0; 8]);
+        put(
+            &mut obj,
+            tags::BITS_ALLOCATED,
+            VR::US,
+            PrimitiveValue::from(8u16),
+        );
+        put(
+            &mut obj,
+            tags::BITS_STORED,
+            VR::US,
+            PrimitiveValue::from(8u16),
+        );
+        put(&mut obj, tags::HIGH_BIT, VR::US, PrimitiveValue::from(7u16));
+        put(
+            &mut obj,
+            tags::PIXEL_REPRESENTATION,
+            VR::US,
+            PrimitiveValue::from(0u16),
+        );
+        put(&mut obj, tags::RESCALE_INTERCEPT, VR::DS, "10".into());
+        let pixels = PrimitiveValue::U8([0, 1, 2, 3, 252, 253, 254, 255][..].into());
+        put(&mut obj, tags::PIXEL_DATA, VR::OB, pixels);
+        let obj = with_meta(obj, uids::EXPLICIT_VR_LITTLE_ENDIAN);
+
+        let slices = read_dicom_object(Path::new("CT1.dcm"), &obj).unwrap();
+        assert_eq!(
+            slices[0].image,
+            [10., 11., 12., 13., 262., 263., 264., 265.]
+        );
+    }
+
+    /// Replace the pixel data with a single encapsulated fragment.
+    fn encapsulated(
+        mut obj: InMemDicomObject,
+        transfer_syntax: &str,
+        fragment: Vec<u8>,
+    ) -> DefaultDicomObject {
+        obj.put(dicom::core::DataElement::new(
+            tags::PIXEL_DATA,
+            VR::OB,
+            dicom::core::value::PixelFragmentSequence::new(vec![], vec![fragment]),
+        ));
+        with_meta(obj, transfer_syntax)
+    }
+
+    /// Encode bytes with the PackBits scheme of RLE Lossless, replicating
+    /// runs of three or more bytes.
+    fn rle_segment(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let run = bytes[i..]
+                .iter()
+                .take(128)
+                .take_while(|&&b| b == bytes[i])
+                .count();
+            if run >= 3 {
+                out.extend([(257 - run) as u8, bytes[i]]);
+                i += run;
+                continue;
+            }
+            let start = i;
+            while i < bytes.len() && i - start < 128 && !bytes[i..].starts_with(&[bytes[i]; 3]) {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend(&bytes[start..i]);
+        }
+        // Segments have an even length, padded with a no operation
+        if out.len() % 2 == 1 {
+            out.push(0x80);
+        }
+        out
+    }
+
+    /// RLE Lossless frame of 16 bit pixels, most significant bytes first.
+    fn rle_frame(pixels: &[i16]) -> Vec<u8> {
+        let high: Vec<u8> = pixels.iter().map(|p| p.to_be_bytes()[0]).collect();
+        let low: Vec<u8> = pixels.iter().map(|p| p.to_be_bytes()[1]).collect();
+        let (high, low) = (rle_segment(&high), rle_segment(&low));
+        let mut header = [0u32; 16];
+        header[0] = 2;
+        header[1] = 64;
+        header[2] = 64 + high.len() as u32;
+        let mut frame: Vec<u8> = header.iter().flat_map(|v| v.to_le_bytes()).collect();
+        frame.extend(high);
+        frame.extend(low);
+        frame
+    }
+
+    /// JPEG Lossless, first order prediction frame of 16 bit samples, with
+    /// every difference category coded in 5 bits.
+    fn jpeg_lossless_frame(samples: &[u16], columns: usize, rows: usize) -> Vec<u8> {
+        let mut out = vec![0xFF, 0xD8, 0xFF, 0xC3, 0, 11, 16];
+        out.extend((rows as u16).to_be_bytes());
+        out.extend((columns as u16).to_be_bytes());
+        out.extend([1, 1, 0x11, 0]);
+        let mut counts = [0u8; 16];
+        counts[4] = 17;
+        out.extend([0xFF, 0xC4, 0, 36, 0x00]);
+        out.extend(counts);
+        out.extend(0..=16u8);
+        out.extend([0xFF, 0xDA, 0, 8, 1, 1, 0x00, 1, 0, 0]);
+
+        let mut bits: Vec<bool> = Vec::new();
+        let mut push = |value: u32, count: u32| {
+            bits.extend((0..count).rev().map(|bit| value >> bit & 1 == 1));
+        };
+        for (index, &sample) in samples.iter().enumerate() {
+            let (i, j) = (index % columns, index / columns);
+            let prediction = match (i, j) {
+                (0, 0) => 1 << 15,
+                (0, _) => samples[index - columns],
+                _ => samples[index - 1],
+            };
+            let difference = sample.wrapping_sub(prediction) as i16 as i32;
+            let category = 32 - difference.unsigned_abs().leading_zeros();
+            push(category, 5);
+            let extra = if difference < 0 {
+                difference + (1 << category) - 1
+            } else {
+                difference
+            };
+            push(extra as u32 & ((1 << category) - 1), category);
+        }
+        bits.resize(bits.len().div_ceil(8) * 8, true);
+        for byte in bits.chunks(8) {
+            let byte = byte.iter().fold(0u8, |byte, &bit| byte << 1 | bit as u8);
+            out.push(byte);
+            // A data byte of 0xFF is followed by a stuffed zero
+            if byte == 0xFF {
+                out.push(0);
+            }
+        }
+        out.extend([0xFF, 0xD9]);
+        out
+    }
+
+    fn ct_values(stored: &[i16]) -> Vec<f32> {
+        stored.iter().map(|&v| v as f32 - 1024.).collect()
+    }
+
+    const STORED: [i16; 8] = [-2000, -2000, -2000, -2000, 0, 1, 3000, -1];
+
+    #[test]
+    fn reads_uncompressed_transfer_syntaxes() {
+        let dir = crate::test_util::temp_dir("uncompressed");
+        for ts in [
+            uids::IMPLICIT_VR_LITTLE_ENDIAN,
+            uids::EXPLICIT_VR_LITTLE_ENDIAN,
+            // Explicit VR Big Endian, retired but still found in archives
+            "1.2.840.10008.1.2.2",
+            uids::DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN,
+        ] {
+            let path = dir.join(format!("{}.dcm", ts));
+            with_meta(ct_slice(0, &AXIAL, &STORED), ts)
+                .write_to_file(&path)
+                .unwrap();
+            let slices = read_dicom_file(&path).unwrap();
+            assert_eq!(slices[0].image, ct_values(&STORED), "{}", ts);
+        }
+    }
+
+    #[test]
+    fn decodes_rle_lossless() {
+        let obj = ct_slice(0, &AXIAL, &STORED);
+        let obj = encapsulated(obj, uids::RLE_LOSSLESS, rle_frame(&STORED));
+        let slices = read_dicom_object(Path::new("CT1.dcm"), &obj).unwrap();
+        assert_eq!(slices[0].image, ct_values(&STORED));
+    }
+
+    #[test]
+    fn decodes_jpeg_lossless() {
+        let samples: Vec<u16> = STORED.iter().map(|&v| v as u16).collect();
+        let frame = jpeg_lossless_frame(&samples, 4, 2);
+        for ts in [uids::JPEG_LOSSLESS, uids::JPEG_LOSSLESS_SV1] {
+            let obj = encapsulated(ct_slice(0, &AXIAL, &STORED), ts, frame.clone());
+            let slices = read_dicom_object(Path::new("CT1.dcm"), &obj).unwrap();
+            assert_eq!(slices[0].image, ct_values(&STORED), "{}", ts);
+        }
+    }
+
+    #[test]
+    fn decodes_jpeg_baseline() {
+        let geometry = SliceGeometry {
+            columns: 16,
+            rows: 8,
+            ..AXIAL
+        };
+        let samples: Vec<u8> = (0..16 * 8)
+            .map(|i| (i % 16 * 8 + i / 16 * 4) as u8)
+            .collect();
+        let mut frame = Vec::new();
+        jpeg_encoder::Encoder::new(&mut frame, 100)
+            .encode(&samples, 16, 8, jpeg_encoder::ColorType::Luma)
+            .unwrap();
+
+        let mut obj = ct_slice(0, &geometry, &[0; 16 * 8]);
+        put(
+            &mut obj,
+            tags::BITS_ALLOCATED,
+            VR::US,
+            PrimitiveValue::from(8u16),
+        );
+        put(
+            &mut obj,
+            tags::BITS_STORED,
+            VR::US,
+            PrimitiveValue::from(8u16),
+        );
+        put(&mut obj, tags::HIGH_BIT, VR::US, PrimitiveValue::from(7u16));
+        put(
+            &mut obj,
+            tags::PIXEL_REPRESENTATION,
+            VR::US,
+            PrimitiveValue::from(0u16),
+        );
+        let obj = encapsulated(obj, uids::JPEG_BASELINE8_BIT, frame);
+
+        let slices = read_dicom_object(Path::new("CT1.dcm"), &obj).unwrap();
+        for (&decoded, &sample) in slices[0].image.iter().zip(&samples) {
+            // Lossy, but close at the highest quality
+            assert!((decoded + 1024. - sample as f32).abs() <= 4., "{}", decoded);
+        }
+    }
+
+    #[test]
+    fn names_transfer_syntaxes_that_cannot_be_decoded() {
+        // JPEG-LS and JPEG 2000 need the charls and openjp2 codecs, which
+        // are not built in
+        for (ts, name) in [
+            (uids::JPEGLS_LOSSLESS, "JPEG-LS"),
+            (uids::JPEG2000_LOSSLESS, "JPEG 2000"),
+            ("1.2.3.4.5", "1.2.3.4.5"),
+        ] {
+            let obj = encapsulated(ct_slice(0, &AXIAL, &STORED), ts, vec![0; 16]);
+            match read_dicom_object(Path::new("CT1.dcm"), &obj) {
+                Err(DicomLoadError::UnsupportedTransferSyntax { uid, .. }) => {
+                    assert!(uid.contains(name) && uid.contains(ts), "{}", uid)
+                }
+                other => panic!("{} was not rejected: {:?}", ts, other.map(|s| s.len())),
+            }
+        }
+    }
+
+    /// Slices of a gantry tilted series with 0.8 mm columns and 0.5 mm rows,
+    /// each shifted one column and two rows from the previous one, in which
+    /// a single bright voxel marks the same point in space.
+    fn tilted_series() -> Vec<(PathBuf, DefaultDicomObject)> {
+        (0..4)
+            .map(|k| {
+                let geometry = SliceGeometry {
+                    columns: 8,
+                    rows: 8,
+                    pixel_spacing: [0.5, 0.8],
+                    position: [0.8 * k as f32, 1.0 * k as f32, 2.0 * k as f32],
+                    orientation: [1., 0., 0., 0., 1., 0.],
+                };
+                let mut stored = [0i16; 64];
+                stored[(6 - 2 * k) * 8 + 5 - k] = 1000;
+                let obj = with_meta(
+                    ct_slice(k, &geometry, &stored),
+                    uids::EXPLICIT_VR_LITTLE_ENDIAN,
+                );
+                (PathBuf::from(format!("CT{}.dcm", k + 1)), obj)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn corrects_gantry_tilt_with_non_square_pixels() {
+        let options = LoadOptions {
+            correct_gantry_tilt: true,
+            ..Default::default()
+        };
+        let (image, warnings) = load_dicom_objects(&tilted_series(), &options).unwrap();
+
+        assert!(matches!(warnings[..], [LoadWarning::GantryTilt { .. }]));
+        assert_eq!(image.pixel_spacing, [0.8, 0.5, 2.0]);
+        assert_eq!(image.image_orientation_patient[2], [0., 0., 1.]);
+        for k in 0..4 {
+            let slice = &image.volume[k * 64..(k + 1) * 64];
+            for (index, &value) in slice.iter().enumerate() {
+                let expected = if index == 6 * 8 + 5 { -24. } else { -1024. };
+                assert_eq!(value, expected, "slice {} voxel {}", k, index);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_tilted_slices_sheared_unless_asked() {
+        let (image, warnings) = load_dicom_objects(&tilted_series(), &Default::default()).unwrap();
+
+        assert!(matches!(warnings[..], [LoadWarning::GantryTilt { .. }]));
+        let length = (0.8f32 * 0.8 + 1. + 4.).sqrt();
+        assert_eq!(image.pixel_spacing, [0.8, 0.5, length]);
+        let slice_direction = image.image_orientation_patient[2];
+        for (axis, component) in [0.8, 1., 2.].into_iter().enumerate() {
+            assert!((slice_direction[axis] - component / length).abs() < 1e-6);
+        }
+        // The marked point lies on the slice axis through its first voxel
+        for k in 0..4 {
+            assert_eq!(image.volume[k * 64 + (6 - 2 * k) * 8 + 5 - k], -24.);
+        }
+    }
+
+    /// Slices of `geometry` placed at `positions`, slice `k` holding the
+    /// stored value `k * 100` everywhere.
+    fn series_at(
+        geometry: &SliceGeometry,
+        positions: &[Vec3],
+    ) -> Vec<(PathBuf, DefaultDicomObject)> {
+        let size = geometry.columns as usize * geometry.rows as usize;
+        positions
+            .iter()
+            .enumerate()
+            .map(|(k, &position)| {
+                let geometry = SliceGeometry {
+                    position,
+                    ..*geometry
+                };
+                let stored = vec![k as i16 * 100; size];
+                let obj = with_meta(
+                    ct_slice(k, &geometry, &stored),
+                    uids::EXPLICIT_VR_LITTLE_ENDIAN,
+                );
+                (PathBuf::from(format!("CT{}.dcm", k + 1)), obj)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn orders_slices_along_the_normal_rather_than_by_instance_number() {
+        // The normal of a sagittal slice with rows pointing down is towards
+        // the patient's right, against increasing x
+        let sagittal = SliceGeometry {
+            orientation: [0., 1., 0., 0., 0., -1.],
+            ..AXIAL
+        };
+        let mut objects = series_at(&sagittal, &[[0., 0., 0.], [2., 0., 0.], [4., 0., 0.]]);
+        objects.swap(0, 1);
+        let (image, warnings) = load_dicom_objects(&objects, &Default::default()).unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!(image.position_patient, [4., 0., 0.]);
+        assert_eq!(image.image_orientation_patient[2], [-1., 0., 0.]);
+        assert_eq!(image.pixel_spacing[2], 2.);
+        let size = 8;
+        for (k, stored) in [200., 100., 0.].into_iter().enumerate() {
+            assert_eq!(image.volume[k * size], stored - 1024., "slice {}", k);
+        }
+    }
+
+    #[test]
+    fn rejects_slices_at_the_same_position() {
+        let positions = [[0., 0., 0.], [0., 0., 2.], [0.5, 0., 2.0005]];
+        let error =
+            load_dicom_objects(&series_at(&AXIAL, &positions), &Default::default()).unwrap_err();
+        match error {
+            DicomLoadError::DuplicatePosition {
+                file,
+                other,
+                location,
+            } => {
+                assert_eq!(other, Path::new("CT2.dcm"));
+                assert_eq!(file, Path::new("CT3.dcm"));
+                assert_eq!(location, 2.);
+            }
+            error => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[test]
+    fn rejects_positions_that_are_not_finite() {
+        for invalid in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let positions = [[0., 0., 0.], [0., 0., invalid], [0., 0., 4.]];
+            let error = load_dicom_objects(&series_at(&AXIAL, &positions), &Default::default())
+                .unwrap_err();
+            assert!(
+                matches!(&error, DicomLoadError::InvalidSliceLocation { file } if file == Path::new("CT2.dcm")),
+                "{}: {}",
+                invalid,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn warns_about_missing_and_irregular_slices() {
+        let positions = [0., 2., 4., 6.5, 8.5, 12.5].map(|z| [0., 0., z]);
+        let (image, warnings) =
+            load_dicom_objects(&series_at(&AXIAL, &positions), &Default::default()).unwrap();
+
+        match &warnings[..] {
+            [LoadWarning::IrregularSpacing {
+                file: irregular,
+                spacing: 2.5,
+                expected: 2.,
+            }, LoadWarning::SliceGap {
+                file: gap,
+                spacing: 4.,
+                expected: 2.,
+            }] => {
+                assert_eq!(irregular, Path::new("CT4.dcm"));
+                assert_eq!(gap, Path::new("CT6.dcm"));
+            }
+            warnings => panic!("unexpected warnings {:?}", warnings),
+        }
+        // Without resampling the slices are kept as they are
+        assert_eq!(image.slices, 6);
+        assert_eq!(image.metadata.sop_instance_uids.len(), 6);
+    }
+
+    #[test]
+    fn resamples_over_a_missing_slice() {
+        let positions = [[0., 0., 0.], [0., 0., 2.], [0., 0., 4.], [0., 0., 8.]];
+        let objects = series_at(&AXIAL, &positions);
+        let options = LoadOptions {
+            resample: true,
+            ..Default::default()
+        };
+        let (image, warnings) = load_dicom_objects(&objects, &options).unwrap();
+
+        assert!(matches!(
+            &warnings[..],
+            [LoadWarning::SliceGap { file, spacing: 4., expected: 2. }] if file == Path::new("CT4.dcm")
+        ));
+        assert_eq!(image.slices, 5);
+        assert_eq!(image.pixel_spacing, [0.75, 0.5, 2.]);
+        let size = 8;
+        for (k, stored) in [0., 100., 200., 250., 300.].into_iter().enumerate() {
+            let slice = &image.volume[k * size..(k + 1) * size];
+            assert!(slice.iter().all(|&v| v == stored - 1024.), "slice {}", k);
+        }
+        // The interpolated slices do not match the source images
+        assert!(image.metadata.sop_instance_uids.is_empty());
+
+        let (image, _) = load_dicom_objects(&objects, &Default::default()).unwrap();
+        assert_eq!(image.slices, 4);
+        assert_eq!(image.pixel_spacing[2], 8. / 3.);
+        let uids: Vec<_> = (1..=4)
+            .map(|k| format!("{}.{}", SERIES_INSTANCE_UID, k))
+            .collect();
+        assert_eq!(image.metadata.sop_instance_uids, uids);
+    }
+
+    #[test]
+    fn swaps_non_square_pixel_spacing_into_column_then_row_order() {
+        // PixelSpacing is 0.5 mm between rows and 0.75 mm between columns
+        let positions = [[-10., -20., 30.], [-10., -20., 32.], [-10., -20., 34.]];
+        let (image, warnings) =
+            load_dicom_objects(&series_at(&AXIAL, &positions), &Default::default()).unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!(image.pixel_spacing, [0.75, 0.5, 2.]);
+        // The last voxel lies 3 columns right and 1 row down of the first
+        let last = image.index_to_patient([3., 1., 2.]);
+        assert_eq!(last, [-10. + 3. * 0.75, -20. + 0.5, 34.]);
+    }
+
+    /// A functional group holding a single item with the given attribute.
+    fn group(sequence_tag: Tag, tag: Tag, vr: VR, value: PrimitiveValue) -> InMemElement {
+        let mut item = InMemDicomObject::new_empty();
+        put(&mut item, tag, vr, value);
+        sequence(sequence_tag, vec![item])
+    }
+
+    #[test]
+    fn reads_frame_positions_from_the_functional_groups() {
+        let stored: Vec<i16> = (0..3).flat_map(|frame| [frame * 100; 8]).collect();
+        let mut obj = ct_slice(0, &AXIAL, &stored);
+        for tag in [
+            tags::IMAGE_POSITION_PATIENT,
+            tags::IMAGE_ORIENTATION_PATIENT,
+            tags::PIXEL_SPACING,
+            tags::RESCALE_INTERCEPT,
+            tags::RESCALE_SLOPE,
+        ] {
+            obj.remove_element(tag);
+        }
+        let class = uids::ENHANCED_CT_IMAGE_STORAGE;
+        put(&mut obj, tags::SOP_CLASS_UID, VR::UI, class.into());
+        put(&mut obj, tags::NUMBER_OF_FRAMES, VR::IS, "3".into());
+
+        let mut shared = InMemDicomObject::new_empty();
+        shared.put(group(
+            tags::PIXEL_MEASURES_SEQUENCE,
+            tags::PIXEL_SPACING,
+            VR::DS,
+            strings(&AXIAL.pixel_spacing),
+        ));
+        shared.put(group(
+            tags::PLANE_ORIENTATION_SEQUENCE,
+            tags::IMAGE_ORIENTATION_PATIENT,
+            VR::DS,
+            strings(&AXIAL.orientation),
+        ));
+        let mut transformation = InMemDicomObject::new_empty();
+        put(
+            &mut transformation,
+            tags::RESCALE_INTERCEPT,
+            VR::DS,
+            "-1024".into(),
+        );
+        put(&mut transformation, tags::RESCALE_SLOPE, VR::DS, "2".into());
+        shared.put(sequence(
+            tags::PIXEL_VALUE_TRANSFORMATION_SEQUENCE,
+            vec![transformation],
+        ));
+        obj.put(sequence(
+            tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+            vec![shared],
+        ));
+
+        // Frames are stored from head to feet
+        let per_frame = [34., 32., 30.]
+            .into_iter()
+            .map(|z| {
+                let mut frame = InMemDicomObject::new_empty();
+                frame.put(group(
+                    tags::PLANE_POSITION_SEQUENCE,
+                    tags::IMAGE_POSITION_PATIENT,
+                    VR::DS,
+                    strings(&[-10., -20., z]),
+                ));
+                frame
+            })
+            .collect();
+        obj.put(sequence(
+            tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            per_frame,
+        ));
+
+        let objects = [(
+            PathBuf::from("CT.dcm"),
+            with_meta(obj, uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )];
+        let (image, warnings) = load_dicom_objects(&objects, &Default::default()).unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!(image.slices, 3);
+        assert_eq!(image.position_patient, [-10., -20., 30.]);
+        assert_eq!(image.pixel_spacing, [0.75, 0.5, 2.]);
+        for (k, stored) in [200., 100., 0.].into_iter().enumerate() {
+            let slice = &image.volume[k * 8..(k + 1) * 8];
+            assert!(
+                slice.iter().all(|&v| v == stored * 2. - 1024.),
+                "slice {}",
+                k
+            );
+        }
+    }
+
+    /// Load three axial slices, the second one drawn with `second` instead.
+    fn load_with_second(second: SliceGeometry) -> Result<ImageVolume, DicomLoadError> {
+        let mut objects = series_at(&AXIAL, &[[-10., -20., 30.], [0.; 3], [-10., -20., 34.]]);
+        let geometry = SliceGeometry {
+            position: [-10., -20., 32.],
+            ..second
+        };
+        let size = geometry.columns as usize * geometry.rows as usize;
+        let obj = ct_slice(1, &geometry, &vec![0; size]);
+        objects[1].1 = with_meta(obj, uids::EXPLICIT_VR_LITTLE_ENDIAN);
+        load_dicom_objects(&objects, &Default::default()).map(|(image, _)| image)
+    }
+
+    #[test]
+    fn rejects_slices_that_differ_from_the_first() {
+        match load_with_second(SliceGeometry { rows: 3, ..AXIAL }) {
+            Err(DicomLoadError::InconsistentDimensions {
+                file,
+                expected: (4, 2),
+                found: (4, 3),
+            }) => assert_eq!(file, Path::new("CT2.dcm")),
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+
+        let pixel_spacing = [0.5, 0.8];
+        match load_with_second(SliceGeometry {
+            pixel_spacing,
+            ..AXIAL
+        }) {
+            Err(DicomLoadError::InconsistentPixelSpacing {
+                file,
+                expected,
+                found,
+            }) => {
+                assert_eq!(file, Path::new("CT2.dcm"));
+                assert_eq!(expected, AXIAL.pixel_spacing);
+                assert_eq!(found, pixel_spacing);
+            }
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+
+        // Rotated by one degree around the slice normal
+        let (sin, cos) = 1f32.to_radians().sin_cos();
+        match load_with_second(SliceGeometry {
+            orientation: [cos, sin, 0., -sin, cos, 0.],
+            ..AXIAL
+        }) {
+            Err(DicomLoadError::InconsistentOrientation { file, found, .. }) => {
+                assert_eq!(file, Path::new("CT2.dcm"));
+                assert_eq!(found[0], [cos, sin, 0.]);
+            }
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn accepts_differences_within_the_tolerances() {
+        let image = load_with_second(SliceGeometry {
+            pixel_spacing: [0.50001, 0.75],
+            orientation: [1., 0.00005, 0., 0., 1., 0.],
+            ..AXIAL
+        })
+        .unwrap();
+        assert_eq!(image.slices, 3);
+        assert_eq!(image.pixel_spacing, [0.75, 0.5, 2.]);
+    }
+
+    #[test]
+    fn needs_at_least_two_slices() {
+        let objects = series_at(&AXIAL, &[[0.; 3]]);
+        match load_dicom_objects(&objects, &Default::default()) {
+            Err(DicomLoadError::TooFewSlices { files }) => {
+                assert_eq!(files, [PathBuf::from("CT1.dcm")]);
+            }
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+        assert!(matches!(
+            load_dicom_objects(&[], &Default::default()),
+            Err(DicomLoadError::TooFewSlices { files }) if files.is_empty()
+        ));
+    }
+
+    #[test]
+    fn reports_progress_up_to_the_file_count() {
+        let files = write_ct_series(&temp_dir("load-progress"), 5, &AXIAL);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let options = LoadOptions {
+            progress: Some(sender),
+            ..Default::default()
+        };
+        let (image, _) = load_dicom_image(&files, &options).unwrap();
+        drop(options);
+
+        assert_eq!(image.slices, 5);
+        // Files are read in parallel, so messages may arrive out of order
+        let mut done: Vec<usize> = receiver
+            .iter()
+            .map(|progress| {
+                assert_eq!(progress.total, 5);
+                progress.done
+            })
+            .collect();
+        done.sort();
+        assert_eq!(done, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let files = write_ct_series(&temp_dir("load-cancelled"), 3, &AXIAL);
+        let cancel = CancellationToken::default();
+        cancel.cancel();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let options = LoadOptions {
+            progress: Some(sender),
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+
+        let result = load_dicom_image(&files, &options);
+        drop(options);
+        assert!(matches!(result, Err(DicomLoadError::Cancelled)));
+        assert_eq!(receiver.iter().count(), 0);
+    }
+
+    #[test]
+    fn fills_the_volume_metadata_from_the_first_slice() {
+        let mut objects = series_at(&AXIAL, &[[0., 0., 0.], [0., 0., 2.]]);
+        for (_, obj) in &mut objects {
+            let text = |value: &str| PrimitiveValue::from(value);
+            put(obj, tags::PATIENT_BIRTH_DATE, VR::DA, text("19700101"));
+            // Padding to an even length is removed
+            put(obj, tags::PATIENT_SEX, VR::CS, text("F "));
+            put(obj, tags::STUDY_TIME, VR::TM, text("093000"));
+            put(obj, tags::STUDY_ID, VR::SH, text("S1"));
+            put(obj, tags::STUDY_DESCRIPTION, VR::LO, text("Head"));
+            put(obj, tags::ACCESSION_NUMBER, VR::SH, text("A42"));
+            put(obj, tags::SERIES_DESCRIPTION, VR::LO, text("Axial 2mm"));
+            put(obj, tags::SERIES_NUMBER, VR::IS, text("3"));
+            put(obj, tags::MANUFACTURER, VR::LO, text("ACME"));
+            put(obj, tags::KVP, VR::DS, text("120"));
+            put(obj, tags::SLICE_THICKNESS, VR::DS, text("2.5"));
+            put(obj, tags::WINDOW_CENTER, VR::DS, strings(&[40, 600]));
+            put(obj, tags::WINDOW_WIDTH, VR::DS, strings(&[400, 2000]));
+        }
+        let (image, _) = load_dicom_objects(&objects, &Default::default()).unwrap();
+        let metadata = &image.metadata;
+
+        assert_eq!(metadata.patient_name, "Doe^Jane");
+        assert_eq!(metadata.patient_id, "12345");
+        assert_eq!(metadata.patient_birth_date, "19700101");
+        assert_eq!(metadata.patient_sex, "F");
+        assert_eq!(metadata.study_date, "20200229");
+        assert_eq!(metadata.study_time, "093000");
+        assert_eq!(metadata.study_id, "S1");
+        assert_eq!(metadata.study_description, "Head");
+        assert_eq!(metadata.accession_number, "A42");
+        assert_eq!(metadata.modality, "CT");
+        assert_eq!(metadata.series_description, "Axial 2mm");
+        assert_eq!(metadata.series_number, "3");
+        assert_eq!(metadata.manufacturer, "ACME");
+        assert_eq!(metadata.kvp, Some(120.));
+        assert_eq!(metadata.slice_thickness, Some(2.5));
+        assert_eq!(metadata.window_center, Some(40.));
+        assert_eq!(metadata.window_width, Some(400.));
+        assert_eq!(metadata.study_instance_uid, STUDY_INSTANCE_UID);
+        assert_eq!(metadata.series_instance_uid, SERIES_INSTANCE_UID);
+        assert_eq!(metadata.frame_of_reference_uid, FRAME_OF_REFERENCE_UID);
+        assert_eq!(metadata.sop_instance_uids.len(), 2);
+    }
+
+    #[test]
+    fn leaves_absent_metadata_empty() {
+        let metadata = read_metadata(&InMemDicomObject::new_empty());
+        assert_eq!(metadata.patient_name, "");
+        assert_eq!(metadata.study_instance_uid, "");
+        assert_eq!(metadata.kvp, None);
+        assert_eq!(metadata.window_center, None);
+        assert!(metadata.sop_instance_uids.is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use dicom::object::OpenFileOptions;
+use dicom_dictionary_std::{tags, uids};
+
+use crate::dicom_reader::{self, read_string, DicomLoadError, LoadOptions, LoadWarning};
+use crate::image_volume::ImageVolume;
+
+/// SOP classes whose instances can be stacked into a volume.
+pub(crate) const IMAGE_SOP_CLASSES: &[&str] = &[
+    uids::CT_IMAGE_STORAGE,
+    uids::ENHANCED_CT_IMAGE_STORAGE,
+    uids::MR_IMAGE_STORAGE,
+    uids::ENHANCED_MR_IMAGE_STORAGE,
+    uids::POSITRON_EMISSION_TOMOGRAPHY_IMAGE_STORAGE,
+    uids::ENHANCED_PET_IMAGE_STORAGE,
+    uids::NUCLEAR_MEDICINE_IMAGE_STORAGE,
+];
+
+/// A group of files sharing the same study and series instance UIDs.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct DicomSeries {
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+    pub modality: String,
+    pub series_description: String,
+    pub files: Vec<PathBuf>,
+    /// Number of frames summed over the files, one for single frame images.
+    pub frames: usize,
+}
+
+impl DicomSeries {
+    pub fn slice_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Build an image volume from every file in the series.
+    pub fn load(
+        &self,
+        options: &LoadOptions,
+    ) -> Result<(ImageVolume, Vec<LoadWarning>), DicomLoadError> {
+        dicom_reader::load_dicom_image(&self.files, options)
+    }
+}
+
+/// Walk a directory tree and group the image files found into series.
+///
+/// Files that are not DICOM, or whose SOP class is not a volumetric image,
+/// are skipped. Series are returned ordered by study and series UID.
+pub fn find_series<P: AsRef<Path>>(dir: P) -> Result<Vec<DicomSeries>> {
+    let mut files = Vec::new();
+    collect_files(dir.as_ref(), &mut files)?;
+
+    let mut series: BTreeMap<(String, String), DicomSeries> = BTreeMap::new();
+    for file in files {
+        // Only the header is needed, stop before the pixel data
+        let Ok(obj) = OpenFileOptions::new()
+            .read_until(tags::PIXEL_DATA)
+            .open_file(&file)
+        else {
+            continue;
+        };
+
+        if !IMAGE_SOP_CLASSES.contains(&obj.meta().media_storage_sop_class_uid()) {
+            continue;
+        }
+
+        let study_instance_uid = read_string(&obj, "StudyInstanceUID");
+        let series_instance_uid = read_string(&obj, "SeriesInstanceUID");
+        let frames = obj
+            .element_by_name("NumberOfFrames")
+            .map_or(1, |elem| elem.to_int::<usize>().unwrap_or(1));
+
+        let entry = series
+            .entry((study_instance_uid.clone(), series_instance_uid.clone()))
+            .or_insert_with(|| DicomSeries {
+                study_instance_uid,
+                series_instance_uid,
+                modality: read_string(&obj, "Modality"),
+                series_description: read_string(&obj, "SeriesDescription"),
+                files: Vec::new(),
+                frames: 0,
+            });
+        entry.files.push(file);
+        entry.frames += frames;
+    }
+
+    Ok(series.into_values().collect())
+}
+
+pub(crate) fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        ct_slice, put, temp_dir, write_ct_series, write_file, write_structure_set, SliceGeometry,
+        FRAME_OF_REFERENCE_UID, SERIES_INSTANCE_UID, STUDY_INSTANCE_UID,
+    };
+    use dicom::core::{PrimitiveValue, VR};
+
+    const GEOMETRY: SliceGeometry = SliceGeometry {
+        columns: 2,
+        rows: 2,
+        pixel_spacing: [1., 1.],
+        position: [0., 0., 0.],
+        orientation: [1., 0., 0., 0., 1., 0.],
+    };
+
+    /// A CT slice of another series, optionally in another study.
+    fn write_other_slice(path: &Path, study: &str, series: &str, frames: Option<usize>) {
+        let mut obj = ct_slice(0, &GEOMETRY, &[0, 1, 2, 3]);
+        put(&mut obj, tags::STUDY_INSTANCE_UID, VR::UI, study.into());
+        put(&mut obj, tags::SERIES_INSTANCE_UID, VR::UI, series.into());
+        put(&mut obj, tags::SERIES_DESCRIPTION, VR::LO, "Other".into());
+        if let Some(frames) = frames {
+            let frames = PrimitiveValue::from(frames.to_string());
+            put(&mut obj, tags::NUMBER_OF_FRAMES, VR::IS, frames);
+        }
+        write_file(obj, path);
+    }
+
+    #[test]
+    fn groups_nested_files_by_study_and_series() {
+        let dir = temp_dir("find-series");
+        let first = write_ct_series(&dir.join("patient/ct"), 3, &GEOMETRY);
+        let other_series = format!("{}.5", SERIES_INSTANCE_UID);
+        let other_study = format!("{}.4", STUDY_INSTANCE_UID);
+        std::fs::create_dir_all(dir.join("patient/other/deeper")).unwrap();
+        let multi_frame = dir.join("patient/other/deeper/MF.dcm");
+        write_other_slice(&multi_frame, STUDY_INSTANCE_UID, &other_series, Some(4));
+        let single_frame = dir.join("patient/other/CT.dcm");
+        write_other_slice(&single_frame, STUDY_INSTANCE_UID, &other_series, None);
+        // Same series UID as the first series, but in another study
+        let moved = dir.join("patient/moved.dcm");
+        write_other_slice(&moved, &other_study, SERIES_INSTANCE_UID, None);
+        write_structure_set(&dir.join("patient/ct/RS.dcm"), FRAME_OF_REFERENCE_UID, &[]);
+        std::fs::write(dir.join("patient/notes.txt"), "not DICOM").unwrap();
+
+        let series = find_series(&dir).unwrap();
+        let keys: Vec<_> = series
+            .iter()
+            .map(|s| {
+                (
+                    s.study_instance_uid.as_str(),
+                    s.series_instance_uid.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (STUDY_INSTANCE_UID, SERIES_INSTANCE_UID),
+                (STUDY_INSTANCE_UID, other_series.as_str()),
+                (other_study.as_str(), SERIES_INSTANCE_UID),
+            ]
+        );
+
+        // The structure set sits beside the slices but is not an image
+        let mut files = series[0].files.clone();
+        files.sort();
+        assert_eq!(files, first);
+        assert_eq!(series[0].modality, "CT");
+        assert_eq!(series[0].series_description, "");
+        assert_eq!(series[0].slice_count(), 3);
+
+        assert_eq!(series[1].files.len(), 2);
+        assert_eq!(series[1].series_description, "Other");
+        assert_eq!(series[1].slice_count(), 5);
+
+        assert_eq!(series[2].files, [moved]);
+        assert_eq!(series[2].slice_count(), 1);
+    }
+}
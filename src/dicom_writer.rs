@@ -0,0 +1,510 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+use dicom_dictionary_std::{tags, uids};
+use rayon::prelude::*;
+
+use crate::graphics::RenderedFrame;
+use crate::image_volume::{cross, dot, ImageVolume, VolumeMetadata};
+
+/// Distinguishes UIDs generated within the same clock tick
+static UID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Write a volume as a series of single frame CT or MR images in `dir`,
+/// one file per slice, returning the paths written.
+///
+/// The series is MR when the volume metadata says so and CT otherwise.
+/// Patient and study attributes are copied from the metadata, with new SOP
+/// instance and series instance UIDs. Voxels are stored as signed 16 bit
+/// integers with a rescale slope and intercept that fit their range.
+pub fn save_dicom_series<P: AsRef<Path>>(image: &ImageVolume, dir: P) -> Result<Vec<PathBuf>> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let metadata = &image.metadata;
+    let (modality, sop_class_uid) = match metadata.modality.as_str() {
+        "MR" => ("MR", uids::MR_IMAGE_STORAGE),
+        _ => ("CT", uids::CT_IMAGE_STORAGE),
+    };
+    let or_new_uid = |uid: &str| {
+        if uid.is_empty() {
+            generate_uid()
+        } else {
+            uid.to_string()
+        }
+    };
+    let study_instance_uid = or_new_uid(&metadata.study_instance_uid);
+    let frame_of_reference_uid = or_new_uid(&metadata.frame_of_reference_uid);
+    let series_instance_uid = generate_uid();
+
+    let (slope, intercept) = rescale_to_int16(&image.volume);
+    let [row, column, _] = image.image_orientation_patient;
+    let normal = cross(row, column);
+    let slice_len = image.columns as usize * image.rows as usize;
+
+    // Attributes shared by every slice
+    let mut common = InMemDicomObject::new_empty();
+    let mut put = |tag: Tag, vr: VR, value: &str| {
+        common.put_str(tag, vr, value);
+    };
+    put(tags::SPECIFIC_CHARACTER_SET, VR::CS, "ISO_IR 192");
+    put(tags::IMAGE_TYPE, VR::CS, "DERIVED\\SECONDARY");
+    put(tags::SOP_CLASS_UID, VR::UI, sop_class_uid);
+    put(tags::STUDY_DATE, VR::DA, &metadata.study_date);
+    put(tags::STUDY_TIME, VR::TM, &metadata.study_time);
+    put(tags::ACCESSION_NUMBER, VR::SH, &metadata.accession_number);
+    put(tags::MODALITY, VR::CS, modality);
+    put(tags::MANUFACTURER, VR::LO, &metadata.manufacturer);
+    put(tags::REFERRING_PHYSICIAN_NAME, VR::PN, "");
+    put(tags::STUDY_DESCRIPTION, VR::LO, &metadata.study_description);
+    put(
+        tags::SERIES_DESCRIPTION,
+        VR::LO,
+        &metadata.series_description,
+    );
+    put(tags::PATIENT_NAME, VR::PN, &metadata.patient_name);
+    put(tags::PATIENT_ID, VR::LO, &metadata.patient_id);
+    put(
+        tags::PATIENT_BIRTH_DATE,
+        VR::DA,
+        &metadata.patient_birth_date,
+    );
+    put(tags::PATIENT_SEX, VR::CS, &metadata.patient_sex);
+    put(tags::STUDY_INSTANCE_UID, VR::UI, &study_instance_uid);
+    put(tags::SERIES_INSTANCE_UID, VR::UI, &series_instance_uid);
+    put(tags::STUDY_ID, VR::SH, &metadata.study_id);
+    put(tags::SERIES_NUMBER, VR::IS, &metadata.series_number);
+    put(tags::ACQUISITION_NUMBER, VR::IS, "");
+    put(
+        tags::FRAME_OF_REFERENCE_UID,
+        VR::UI,
+        &frame_of_reference_uid,
+    );
+    put(tags::POSITION_REFERENCE_INDICATOR, VR::LO, "");
+    put(
+        tags::IMAGE_ORIENTATION_PATIENT,
+        VR::DS,
+        &decimal_strings(&[row, column].concat()),
+    );
+    put(
+        tags::PIXEL_SPACING,
+        VR::DS,
+        &decimal_strings(&[image.pixel_spacing[1], image.pixel_spacing[0]]),
+    );
+    let slice_thickness = metadata.slice_thickness.unwrap_or(image.pixel_spacing[2]);
+    put(
+        tags::SLICE_THICKNESS,
+        VR::DS,
+        &decimal_string(slice_thickness),
+    );
+    put(
+        tags::SPACING_BETWEEN_SLICES,
+        VR::DS,
+        &decimal_string(image.pixel_spacing[2]),
+    );
+    put(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2");
+    put(tags::RESCALE_INTERCEPT, VR::DS, &decimal_string(intercept));
+    put(tags::RESCALE_SLOPE, VR::DS, &decimal_string(slope));
+    if let (Some(center), Some(width)) = (metadata.window_center, metadata.window_width) {
+        put(tags::WINDOW_CENTER, VR::DS, &decimal_string(center));
+        put(tags::WINDOW_WIDTH, VR::DS, &decimal_string(width));
+    }
+
+    if modality == "CT" {
+        let kvp = metadata.kvp.map(decimal_string).unwrap_or_default();
+        put(tags::KVP, VR::DS, &kvp);
+        put(tags::RESCALE_TYPE, VR::LO, "HU");
+    } else {
+        // Acquisition details of the source are not known
+        put(tags::SCANNING_SEQUENCE, VR::CS, "RM");
+        put(tags::SEQUENCE_VARIANT, VR::CS, "NONE");
+        put(tags::SCAN_OPTIONS, VR::CS, "");
+        put(tags::MR_ACQUISITION_TYPE, VR::CS, "3D");
+        put(tags::ECHO_TIME, VR::DS, "");
+        put(tags::ECHO_TRAIN_LENGTH, VR::IS, "");
+    }
+
+    let us = |tag: Tag, value: u16| DataElement::new(tag, VR::US, PrimitiveValue::from(value));
+    common.put(us(tags::ROWS, image.rows));
+    common.put(us(tags::COLUMNS, image.columns));
+    common.put(us(tags::SAMPLES_PER_PIXEL, 1));
+    common.put(us(tags::BITS_ALLOCATED, 16));
+    common.put(us(tags::BITS_STORED, 16));
+    common.put(us(tags::HIGH_BIT, 15));
+    common.put(us(tags::PIXEL_REPRESENTATION, 1));
+
+    (0..image.slices)
+        .into_par_iter()
+        .map(|slice| {
+            let sop_instance_uid = generate_uid();
+            let position = image.index_to_patient([0., 0., slice as f32]);
+
+            let mut obj = common.clone();
+            obj.put_str(tags::SOP_INSTANCE_UID, VR::UI, sop_instance_uid.as_str());
+            obj.put_str(tags::INSTANCE_NUMBER, VR::IS, (slice + 1).to_string());
+            obj.put_str(
+                tags::IMAGE_POSITION_PATIENT,
+                VR::DS,
+                decimal_strings(&position),
+            );
+            obj.put_str(
+                tags::SLICE_LOCATION,
+                VR::DS,
+                decimal_string(dot(position, normal)),
+            );
+
+            let pixels: Vec<u8> = image.volume[slice * slice_len..(slice + 1) * slice_len]
+                .iter()
+                .flat_map(|&value| {
+                    let stored = ((value - intercept) / slope).round();
+                    (stored.clamp(i16::MIN as f32, i16::MAX as f32) as i16).to_le_bytes()
+                })
+                .collect();
+            obj.put(DataElement::new(
+                tags::PIXEL_DATA,
+                VR::OW,
+                PrimitiveValue::from(pixels),
+            ));
+
+            let path = dir.join(format!("{}{:04}.dcm", modality, slice + 1));
+            obj.with_meta(
+                FileMetaTableBuilder::new().transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+            )?
+            .write_to_file(&path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// A new series of RGB Secondary Capture images of the same volume, such as
+/// screenshots of the rendered view, within the study of the volume.
+pub struct CaptureSeries {
+    metadata: VolumeMetadata,
+    study_instance_uid: String,
+    series_instance_uid: String,
+    instance_count: usize,
+}
+
+impl CaptureSeries {
+    pub fn new(metadata: &VolumeMetadata) -> Self {
+        let study_instance_uid = if metadata.study_instance_uid.is_empty() {
+            generate_uid()
+        } else {
+            metadata.study_instance_uid.clone()
+        };
+        CaptureSeries {
+            metadata: metadata.clone(),
+            study_instance_uid,
+            series_instance_uid: generate_uid(),
+            instance_count: 0,
+        }
+    }
+
+    /// Write a frame as the next image of the series in `dir`, returning
+    /// the path written.
+    pub fn save<P: AsRef<Path>>(&mut self, frame: &RenderedFrame, dir: P) -> Result<PathBuf> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        self.instance_count += 1;
+
+        let metadata = &self.metadata;
+        let sop_instance_uid = generate_uid();
+        let mut obj = InMemDicomObject::new_empty();
+        let mut put = |tag: Tag, vr: VR, value: &str| {
+            obj.put_str(tag, vr, value);
+        };
+        put(tags::SPECIFIC_CHARACTER_SET, VR::CS, "ISO_IR 192");
+        put(tags::IMAGE_TYPE, VR::CS, "DERIVED\\SECONDARY");
+        put(
+            tags::SOP_CLASS_UID,
+            VR::UI,
+            uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+        );
+        put(tags::SOP_INSTANCE_UID, VR::UI, &sop_instance_uid);
+        put(tags::STUDY_DATE, VR::DA, &metadata.study_date);
+        put(tags::STUDY_TIME, VR::TM, &metadata.study_time);
+        put(tags::ACCESSION_NUMBER, VR::SH, &metadata.accession_number);
+        put(tags::MODALITY, VR::CS, "OT");
+        put(tags::CONVERSION_TYPE, VR::CS, "WSD");
+        put(tags::REFERRING_PHYSICIAN_NAME, VR::PN, "");
+        put(tags::STUDY_DESCRIPTION, VR::LO, &metadata.study_description);
+        put(tags::SERIES_DESCRIPTION, VR::LO, "Volume rendering");
+        put(tags::DERIVATION_DESCRIPTION, VR::ST, "Volume rendering");
+        put(tags::PATIENT_NAME, VR::PN, &metadata.patient_name);
+        put(tags::PATIENT_ID, VR::LO, &metadata.patient_id);
+        put(
+            tags::PATIENT_BIRTH_DATE,
+            VR::DA,
+            &metadata.patient_birth_date,
+        );
+        put(tags::PATIENT_SEX, VR::CS, &metadata.patient_sex);
+        put(tags::STUDY_INSTANCE_UID, VR::UI, &self.study_instance_uid);
+        put(tags::SERIES_INSTANCE_UID, VR::UI, &self.series_instance_uid);
+        put(tags::STUDY_ID, VR::SH, &metadata.study_id);
+        put(tags::SERIES_NUMBER, VR::IS, "");
+        put(
+            tags::INSTANCE_NUMBER,
+            VR::IS,
+            &self.instance_count.to_string(),
+        );
+        put(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "RGB");
+
+        let us = |tag: Tag, value: u16| DataElement::new(tag, VR::US, PrimitiveValue::from(value));
+        obj.put(us(tags::SAMPLES_PER_PIXEL, 3));
+        obj.put(us(tags::PLANAR_CONFIGURATION, 0));
+        obj.put(us(tags::ROWS, u16::try_from(frame.height)?));
+        obj.put(us(tags::COLUMNS, u16::try_from(frame.width)?));
+        obj.put(us(tags::BITS_ALLOCATED, 8));
+        obj.put(us(tags::BITS_STORED, 8));
+        obj.put(us(tags::HIGH_BIT, 7));
+        obj.put(us(tags::PIXEL_REPRESENTATION, 0));
+
+        // Values have an even length
+        let mut pixels = frame.rgb.clone();
+        if pixels.len() % 2 == 1 {
+            pixels.push(0);
+        }
+        obj.put(DataElement::new(
+            tags::PIXEL_DATA,
+            VR::OB,
+            PrimitiveValue::from(pixels),
+        ));
+
+        let path = dir.join(format!("{}.dcm", sop_instance_uid));
+        obj.with_meta(
+            FileMetaTableBuilder::new().transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )?
+        .write_to_file(&path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// A new UID under the `2.25` root, formed from a random version 4 UUID.
+pub fn generate_uid() -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let count = UID_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    // Each RandomState is seeded with fresh random keys
+    let random = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(time);
+        hasher.write_u64(count);
+        hasher.write_u64(salt);
+        hasher.finish() as u128
+    };
+    let mut uuid = random(0) << 64 | random(1);
+    uuid = uuid & !(0xf << 76) | 0x4 << 76;
+    uuid = uuid & !(0x3 << 62) | 0x2 << 62;
+    format!("2.25.{}", uuid)
+}
+
+/// Rescale slope and intercept mapping the values of a volume into int16.
+///
+/// Integer volumes that already fit, like CT in Hounsfield units, are kept
+/// as they are. Others are spread over the whole int16 range.
+fn rescale_to_int16(volume: &[f32]) -> (f32, f32) {
+    let (min, max) = volume
+        .iter()
+        .filter(|value| value.is_finite())
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &value| {
+            (min.min(value), max.max(value))
+        });
+    if min > max {
+        return (1., 0.);
+    }
+
+    let integral = volume
+        .iter()
+        .filter(|value| value.is_finite())
+        .all(|value| value.fract() == 0.);
+    if integral && min >= i16::MIN as f32 && max <= i16::MAX as f32 {
+        (1., 0.)
+    } else if integral && max - min <= u16::MAX as f32 {
+        (1., min - i16::MIN as f32)
+    } else {
+        let slope = ((max - min) / u16::MAX as f32).max(f32::MIN_POSITIVE);
+        (slope, min - i16::MIN as f32 * slope)
+    }
+}
+
+/// A value in the at most 16 characters of a DICOM decimal string.
+fn decimal_string(value: f32) -> String {
+    let text = value.to_string();
+    if text.len() <= 16 {
+        text
+    } else {
+        format!("{:e}", value)
+    }
+}
+
+fn decimal_strings(values: &[f32]) -> String {
+    values
+        .iter()
+        .map(|&value| decimal_string(value))
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicom_reader::{load_dicom_image, read_string};
+    use crate::test_util::temp_dir;
+    use dicom::object::open_file;
+
+    fn oblique_volume(volume: Vec<f32>, metadata: VolumeMetadata) -> ImageVolume {
+        let (sin, cos) = 0.25f32.sin_cos();
+        ImageVolume {
+            columns: 3,
+            rows: 2,
+            slices: 2,
+            pixel_spacing: [0.5, 0.75, 2.5],
+            position_patient: [-120.5, 30.25, 64.],
+            image_orientation_patient: [[cos, sin, 0.], [-sin, cos, 0.], [0., 0., 1.]],
+            volume,
+            metadata,
+        }
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= tolerance, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn saved_series_loads_with_the_same_geometry_and_values() {
+        let metadata = VolumeMetadata {
+            patient_name: "Doe^Jane".to_string(),
+            patient_id: "12345".to_string(),
+            study_instance_uid: "1.2.3.4".to_string(),
+            series_instance_uid: "1.2.3.4.5".to_string(),
+            ..Default::default()
+        };
+        let values: Vec<f32> = (0..12).map(|v| v as f32 * 0.37 - 1.5).collect();
+        let image = oblique_volume(values.clone(), metadata);
+        let dir = temp_dir("dicom-writer-roundtrip");
+
+        let files = save_dicom_series(&image, &dir).unwrap();
+        let (loaded, warnings) = load_dicom_image(&files, &Default::default()).unwrap();
+
+        assert!(warnings.is_empty());
+        assert_eq!((loaded.columns, loaded.rows, loaded.slices), (3, 2, 2));
+        assert_close(&loaded.pixel_spacing, &image.pixel_spacing, 1e-5);
+        assert_close(&loaded.position_patient, &image.position_patient, 1e-4);
+        for axis in 0..3 {
+            assert_close(
+                &loaded.image_orientation_patient[axis],
+                &image.image_orientation_patient[axis],
+                1e-5,
+            );
+        }
+        // Values are quantized to the slope spread over the int16 range
+        let (slope, _) = rescale_to_int16(&values);
+        assert_close(&loaded.volume, &values, slope);
+        assert_eq!(loaded.metadata.modality, "CT");
+        assert_eq!(loaded.metadata.patient_name, "Doe^Jane");
+        assert_eq!(loaded.metadata.study_instance_uid, "1.2.3.4");
+        assert_ne!(loaded.metadata.series_instance_uid, "1.2.3.4.5");
+    }
+
+    #[test]
+    fn keeps_integer_values_exact() {
+        let metadata = VolumeMetadata {
+            modality: "MR".to_string(),
+            ..Default::default()
+        };
+        let values = vec![0., 1., 40000., 65535., 2., 3., 4., 5., 6., 7., 8., 9.];
+        let image = oblique_volume(values.clone(), metadata);
+        let dir = temp_dir("dicom-writer-integers");
+
+        let files = save_dicom_series(&image, &dir).unwrap();
+        let (loaded, _) = load_dicom_image(&files, &Default::default()).unwrap();
+
+        assert_eq!(loaded.volume, values);
+        assert_eq!(loaded.metadata.modality, "MR");
+    }
+
+    #[test]
+    fn rescales_into_int16() {
+        assert_eq!(rescale_to_int16(&[-1024., 3071.]), (1., 0.));
+        assert_eq!(rescale_to_int16(&[0., 65535.]), (1., 32768.));
+        assert_eq!(rescale_to_int16(&[f32::NAN]), (1., 0.));
+        let (slope, intercept) = rescale_to_int16(&[-1., 1.5]);
+        assert_eq!(slope, 2.5 / 65535.);
+        assert_eq!(intercept, -1. + 32768. * slope);
+    }
+
+    #[test]
+    fn captures_frames_into_one_secondary_capture_series() {
+        let metadata = VolumeMetadata {
+            patient_name: "Doe^Jane".to_string(),
+            patient_id: "12345".to_string(),
+            patient_sex: "F".to_string(),
+            study_date: "20200229".to_string(),
+            accession_number: "A42".to_string(),
+            study_instance_uid: "1.2.3.4".to_string(),
+            series_instance_uid: "1.2.3.4.5".to_string(),
+            ..Default::default()
+        };
+        // Three pixels, an odd number of bytes
+        let frame = RenderedFrame {
+            width: 3,
+            height: 1,
+            rgb: vec![255, 0, 0, 0, 255, 0, 0, 0, 255],
+        };
+        let dir = temp_dir("capture-series");
+        let mut series = CaptureSeries::new(&metadata);
+
+        let first = open_file(series.save(&frame, &dir).unwrap()).unwrap();
+        let second = open_file(series.save(&frame, &dir).unwrap()).unwrap();
+
+        for obj in [&first, &second] {
+            assert_eq!(
+                obj.meta().media_storage_sop_class_uid(),
+                uids::SECONDARY_CAPTURE_IMAGE_STORAGE
+            );
+            assert_eq!(
+                read_string(obj, "SOPClassUID"),
+                uids::SECONDARY_CAPTURE_IMAGE_STORAGE
+            );
+            assert_eq!(read_string(obj, "PhotometricInterpretation"), "RGB");
+            let uint16 = |name| obj.element_by_name(name).unwrap().uint16().unwrap();
+            assert_eq!(uint16("SamplesPerPixel"), 3);
+            assert_eq!(uint16("PlanarConfiguration"), 0);
+            assert_eq!((uint16("Rows"), uint16("Columns")), (1, 3));
+            let pixels = obj.element(tags::PIXEL_DATA).unwrap().to_bytes().unwrap();
+            assert_eq!(pixels[..9], frame.rgb[..]);
+
+            assert_eq!(read_string(obj, "PatientName"), "Doe^Jane");
+            assert_eq!(read_string(obj, "PatientID"), "12345");
+            assert_eq!(read_string(obj, "PatientSex"), "F");
+            assert_eq!(read_string(obj, "StudyDate"), "20200229");
+            assert_eq!(read_string(obj, "AccessionNumber"), "A42");
+            assert_eq!(read_string(obj, "StudyInstanceUID"), "1.2.3.4");
+        }
+
+        let series_uid = read_string(&first, "SeriesInstanceUID");
+        assert_ne!(series_uid, "1.2.3.4.5");
+        assert_eq!(read_string(&second, "SeriesInstanceUID"), series_uid);
+        assert_ne!(
+            read_string(&first, "SOPInstanceUID"),
+            read_string(&second, "SOPInstanceUID")
+        );
+        assert_eq!(read_string(&first, "InstanceNumber"), "1");
+        assert_eq!(read_string(&second, "InstanceNumber"), "2");
+    }
+}
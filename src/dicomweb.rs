@@ -0,0 +1,485 @@
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use dicom::object::file::ReadPreamble;
+use dicom::object::{DefaultDicomObject, OpenFileOptions};
+use dicom_dictionary_std::tags;
+use serde_json::Value;
+
+use crate::dicom_reader::{self, LoadOptions, LoadWarning};
+use crate::image_volume::ImageVolume;
+
+const DICOM_JSON: &str = "application/dicom+json";
+/// Instances are sent in Explicit VR Little Endian unless another transfer
+/// syntax is requested
+const MULTIPART_DICOM: &str = "multipart/related; type=\"application/dicom\"";
+
+/// Study level attributes returned by a QIDO-RS search.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct StudySummary {
+    pub study_instance_uid: String,
+    pub patient_name: String,
+    pub patient_id: String,
+    pub study_date: String,
+    pub study_description: String,
+    pub modalities_in_study: Vec<String>,
+}
+
+/// Series level attributes returned by a QIDO-RS search.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct SeriesSummary {
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+    pub modality: String,
+    pub series_number: String,
+    pub series_description: String,
+    pub number_of_instances: usize,
+}
+
+/// Client for the QIDO-RS and WADO-RS services of a DICOMweb server.
+///
+/// `base_url` is the service root, e.g. `http://localhost:8042/dicom-web`,
+/// under which the `studies` resources live.
+pub struct DicomWebClient {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl DicomWebClient {
+    pub fn new(base_url: &str) -> Self {
+        DicomWebClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// Search for studies, with `filters` given as attribute keyword or tag
+    /// and value pairs, e.g. `("PatientName", "DOE*")`.
+    #[allow(dead_code)]
+    pub fn search_studies(&self, filters: &[(&str, &str)]) -> Result<Vec<StudySummary>> {
+        let url = format!("{}/studies", self.base_url);
+        let results = self.search(&url, filters)?;
+        Ok(results
+            .iter()
+            .map(|dataset| StudySummary {
+                study_instance_uid: json_string(dataset, tags::STUDY_INSTANCE_UID),
+                patient_name: json_string(dataset, tags::PATIENT_NAME),
+                patient_id: json_string(dataset, tags::PATIENT_ID),
+                study_date: json_string(dataset, tags::STUDY_DATE),
+                study_description: json_string(dataset, tags::STUDY_DESCRIPTION),
+                modalities_in_study: json_strings(dataset, tags::MODALITIES_IN_STUDY),
+            })
+            .collect())
+    }
+
+    /// Search for the series of a study.
+    pub fn search_series(
+        &self,
+        study_instance_uid: &str,
+        filters: &[(&str, &str)],
+    ) -> Result<Vec<SeriesSummary>> {
+        let url = format!("{}/studies/{}/series", self.base_url, study_instance_uid);
+        let results = self.search(&url, filters)?;
+        Ok(results
+            .iter()
+            .map(|dataset| SeriesSummary {
+                study_instance_uid: study_instance_uid.to_string(),
+                series_instance_uid: json_string(dataset, tags::SERIES_INSTANCE_UID),
+                modality: json_string(dataset, tags::MODALITY),
+                series_number: json_string(dataset, tags::SERIES_NUMBER),
+                series_description: json_string(dataset, tags::SERIES_DESCRIPTION),
+                number_of_instances: json_string(dataset, tags::NUMBER_OF_SERIES_RELATED_INSTANCES)
+                    .parse()
+                    .unwrap_or(0),
+            })
+            .collect())
+    }
+
+    /// Retrieve every instance of a series, parsed in memory. Each object is
+    /// named by its WADO-RS instance URL.
+    pub fn retrieve_series(
+        &self,
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+    ) -> Result<Vec<(PathBuf, DefaultDicomObject)>> {
+        let url = format!(
+            "{}/studies/{}/series/{}",
+            self.base_url, study_instance_uid, series_instance_uid
+        );
+        let response = self
+            .agent
+            .get(&url)
+            .set("Accept", MULTIPART_DICOM)
+            .call()
+            .with_context(|| format!("Failed to retrieve {}", url))?;
+
+        let content_type = response.header("Content-Type").unwrap_or("").to_string();
+        let boundary = multipart_boundary(&content_type)
+            .ok_or_else(|| anyhow!("Expected a multipart response from {}", url))?;
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body)?;
+
+        multipart_parts(&body, &boundary)?
+            .into_iter()
+            .map(|part| {
+                let obj = parse_instance(part).with_context(|| {
+                    format!("Failed to read an instance retrieved from {}", url)
+                })?;
+                let sop_instance_uid = obj.meta().media_storage_sop_instance_uid();
+                let name = format!(
+                    "{}/instances/{}",
+                    url,
+                    sop_instance_uid.trim_end_matches('\0')
+                );
+                Ok((PathBuf::from(name), obj))
+            })
+            .collect()
+    }
+
+    /// Retrieve a series and build a volume from it.
+    pub fn load_series(
+        &self,
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+        options: &LoadOptions,
+    ) -> Result<(ImageVolume, Vec<LoadWarning>)> {
+        let objects = self.retrieve_series(study_instance_uid, series_instance_uid)?;
+        Ok(dicom_reader::load_dicom_objects(&objects, options)?)
+    }
+
+    fn search(&self, url: &str, filters: &[(&str, &str)]) -> Result<Vec<Value>> {
+        let mut request = self.agent.get(url).set("Accept", DICOM_JSON);
+        for (key, value) in filters {
+            request = request.query(key, value);
+        }
+        let response = request
+            .call()
+            .with_context(|| format!("Failed to search {}", url))?;
+
+        // No matches are reported with an empty body
+        if response.status() == 204 {
+            return Ok(Vec::new());
+        }
+        let results: Value = serde_json::from_reader(response.into_reader())
+            .with_context(|| format!("Invalid search results from {}", url))?;
+        match results {
+            Value::Array(results) => Ok(results),
+            _ => Err(anyhow!("Expected an array of results from {}", url)),
+        }
+    }
+}
+
+/// Split a service URL of the form `<base>/studies/<uid>[/series/<uid>]`
+/// into the service root and the study and series UIDs.
+pub fn parse_url(url: &str) -> Option<(&str, &str, Option<&str>)> {
+    let url = url.trim_end_matches('/');
+    let (base, path) = url.rsplit_once("/studies/")?;
+    match path.split('/').collect::<Vec<_>>()[..] {
+        [study] => Some((base, study, None)),
+        [study, "series", series] => Some((base, study, Some(series))),
+        _ => None,
+    }
+}
+
+/// First value of an attribute in a DICOM JSON data set, as a string.
+fn json_string(dataset: &Value, tag: dicom::core::Tag) -> String {
+    json_strings(dataset, tag)
+        .into_iter()
+        .next()
+        .unwrap_or_default()
+}
+
+/// Every value of an attribute in a DICOM JSON data set, as strings.
+/// Person names are given by their alphabetic representation.
+fn json_strings(dataset: &Value, tag: dicom::core::Tag) -> Vec<String> {
+    let key = format!("{:04X}{:04X}", tag.group(), tag.element());
+    let Some(Value::Array(values)) = dataset.get(&key).and_then(|element| element.get("Value"))
+    else {
+        return Vec::new();
+    };
+    values
+        .iter()
+        .map(|value| match value {
+            Value::String(value) => value.clone(),
+            Value::Number(value) => value.to_string(),
+            Value::Object(name) => name
+                .get("Alphabetic")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+/// The boundary parameter of a multipart content type.
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/related")
+    {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Bodies of the parts of a multipart message, without their headers.
+fn multipart_parts<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<&'a [u8]>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let truncated = || anyhow!("Truncated multipart response");
+
+    let mut pos = find(body, &delimiter, 0).ok_or_else(truncated)? + delimiter.len();
+    let mut parts = Vec::new();
+    loop {
+        // The closing delimiter is followed by two dashes
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        let headers_end = find(body, b"\r\n\r\n", pos).ok_or_else(truncated)? + 4;
+        let next = find(body, &delimiter, headers_end).ok_or_else(truncated)?;
+        // The line break before a delimiter belongs to the delimiter
+        let end = if body[..next].ends_with(b"\r\n") {
+            next - 2
+        } else {
+            next
+        };
+        parts.push(&body[headers_end..end]);
+        pos = next + delimiter.len();
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| from + pos)
+}
+
+/// Parse a Part 10 instance, with or without its 128 byte preamble.
+fn parse_instance(data: &[u8]) -> Result<DefaultDicomObject> {
+    let data = match data.get(128..132) {
+        Some(b"DICM") => &data[128..],
+        _ => data,
+    };
+    Ok(OpenFileOptions::new()
+        .read_preamble(ReadPreamble::Never)
+        .from_reader(Cursor::new(data))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        temp_dir, write_ct_series, SliceGeometry, SERIES_INSTANCE_UID, STUDY_INSTANCE_UID,
+    };
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn parses_service_urls() {
+        assert_eq!(
+            parse_url("http://host:8042/dicom-web/studies/1.2.3"),
+            Some(("http://host:8042/dicom-web", "1.2.3", None))
+        );
+        assert_eq!(
+            parse_url("http://host/dicom-web/studies/1.2.3/series/1.2.3.4/"),
+            Some(("http://host/dicom-web", "1.2.3", Some("1.2.3.4")))
+        );
+        assert_eq!(
+            parse_url("http://host/dicom-web/studies/1.2.3/instances"),
+            None
+        );
+        assert_eq!(
+            parse_url("http://host/dicom-web/studies/1.2.3/series/1.2.3.4/instances/5"),
+            None
+        );
+        assert_eq!(parse_url("http://host/dicom-web"), None);
+        assert_eq!(parse_url("path/to/folder"), None);
+    }
+
+    #[test]
+    fn reads_multipart_boundaries() {
+        assert_eq!(
+            multipart_boundary("multipart/related; type=\"application/dicom\"; boundary=abc")
+                .as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            multipart_boundary("Multipart/Related;Boundary=\"a b:c\";type=application/dicom")
+                .as_deref(),
+            Some("a b:c")
+        );
+        assert_eq!(
+            multipart_boundary("multipart/related; type=application/dicom"),
+            None
+        );
+        assert_eq!(multipart_boundary("application/dicom; boundary=abc"), None);
+        assert_eq!(multipart_boundary(""), None);
+    }
+
+    #[test]
+    fn splits_multipart_bodies() {
+        let body = b"preamble, ignored\r\n\
+            --abc\r\nContent-Type: application/dicom\r\n\r\nfirst\r\n\
+            --abc\r\nContent-Type: application/dicom\r\nContent-Length: 8\r\n\r\nsec\r\nond\r\n\
+            --abc\r\n\r\n\r\n\
+            --abc--\r\nepilogue";
+        let parts = multipart_parts(body, "abc").unwrap();
+        assert_eq!(parts, [&b"first"[..], b"sec\r\nond", b""]);
+
+        // Bare line feeds are kept as part of the body
+        let parts = multipart_parts(b"--abc\r\n\r\nfirst\n--abc--", "abc").unwrap();
+        assert_eq!(parts, [&b"first\n"[..]]);
+        assert!(multipart_parts(b"--abc--", "abc").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_multipart_bodies() {
+        assert!(multipart_parts(b"no delimiter", "abc").is_err());
+        assert!(multipart_parts(b"--abc\r\nContent-Type: application/dicom", "abc").is_err());
+        assert!(multipart_parts(b"--abc\r\n\r\nfirst\r\n", "abc").is_err());
+    }
+
+    #[test]
+    fn reads_dicom_json_values() {
+        let dataset: Value = serde_json::from_str(
+            r#"{
+                "00100010": {"vr": "PN", "Value": [{"Alphabetic": "Doe^Jane"}]},
+                "00201209": {"vr": "IS", "Value": [12]},
+                "00080061": {"vr": "CS", "Value": ["CT", "RTSTRUCT"]},
+                "00081030": {"vr": "LO"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(json_string(&dataset, tags::PATIENT_NAME), "Doe^Jane");
+        let instances = tags::NUMBER_OF_SERIES_RELATED_INSTANCES;
+        assert_eq!(json_string(&dataset, instances), "12");
+        assert_eq!(
+            json_strings(&dataset, tags::MODALITIES_IN_STUDY),
+            ["CT", "RTSTRUCT"]
+        );
+        assert_eq!(json_string(&dataset, tags::STUDY_DESCRIPTION), "");
+        assert_eq!(json_string(&dataset, tags::PATIENT_ID), "");
+    }
+
+    /// Serve `responses`, pairs of a path prefix and a content type and body,
+    /// over HTTP on a loopback port, returning the service root.
+    fn serve(responses: Vec<(String, String, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/dicom-web", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let path = path.split('?').next().unwrap_or_default();
+                let response = responses.iter().find(|(prefix, _, _)| path == prefix);
+                let (status, content_type, body) = match response {
+                    Some((_, content_type, body)) => ("200 OK", content_type.as_str(), &body[..]),
+                    None => ("404 Not Found", "text/plain", &b""[..]),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    content_type,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        base_url
+    }
+
+    #[test]
+    fn searches_and_retrieves_a_series_over_http() {
+        let dir = temp_dir("dicomweb-series");
+        let geometry = SliceGeometry {
+            columns: 4,
+            rows: 3,
+            pixel_spacing: [0.5, 0.75],
+            position: [-10., -20., 30.],
+            orientation: [1., 0., 0., 0., 1., 0.],
+        };
+        let files = write_ct_series(&dir, 3, &geometry);
+
+        let series = format!(
+            r#"[{{
+                "0020000E": {{"vr": "UI", "Value": ["{}"]}},
+                "00080060": {{"vr": "CS", "Value": ["CT"]}},
+                "00200011": {{"vr": "IS", "Value": [2]}},
+                "00201209": {{"vr": "IS", "Value": [3]}}
+            }}]"#,
+            SERIES_INSTANCE_UID
+        );
+        let mut body = b"\r\n".to_vec();
+        for file in &files {
+            body.extend_from_slice(b"--instances\r\nContent-Type: application/dicom\r\n\r\n");
+            body.extend(std::fs::read(file).unwrap());
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--instances--\r\n");
+        let study_url = format!("/dicom-web/studies/{}/series", STUDY_INSTANCE_UID);
+        let series_url = format!("{}/{}", study_url, SERIES_INSTANCE_UID);
+        let base_url = serve(vec![
+            (study_url, DICOM_JSON.to_string(), series.into_bytes()),
+            (
+                series_url,
+                format!("{}; boundary=\"instances\"", MULTIPART_DICOM),
+                body,
+            ),
+        ]);
+
+        let client = DicomWebClient::new(&format!("{}/", base_url));
+        let found = client
+            .search_series(STUDY_INSTANCE_UID, &[("Modality", "CT")])
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].series_instance_uid, SERIES_INSTANCE_UID);
+        assert_eq!(found[0].study_instance_uid, STUDY_INSTANCE_UID);
+        assert_eq!(found[0].modality, "CT");
+        assert_eq!(found[0].series_number, "2");
+        assert_eq!(found[0].number_of_instances, 3);
+
+        let retrieved = client
+            .retrieve_series(STUDY_INSTANCE_UID, &found[0].series_instance_uid)
+            .unwrap();
+        assert_eq!(retrieved.len(), 3);
+        assert!(retrieved[0].0.starts_with(format!(
+            "{}/studies/{}/series/",
+            base_url, STUDY_INSTANCE_UID
+        )));
+
+        let options = Default::default();
+        let (volume, warnings) = client
+            .load_series(STUDY_INSTANCE_UID, SERIES_INSTANCE_UID, &options)
+            .unwrap();
+        let (expected, _) = dicom_reader::load_dicom_image(&files, &options).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!((volume.columns, volume.rows, volume.slices), (4, 3, 3));
+        assert_eq!(volume.pixel_spacing, expected.pixel_spacing);
+        assert_eq!(volume.position_patient, expected.position_patient);
+        assert_eq!(volume.volume, expected.volume);
+
+        assert!(client.retrieve_series(STUDY_INSTANCE_UID, "1.2.3").is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+
+use crate::image_volume::ImageVolume;
+use crate::rtdose;
+use crate::rtstruct::Roi;
+
+/// Dose-volume histogram of a structure.
+///
+/// Doses are in `units`, those of the dose grid, and volumes in cm³. Bin `n`
+/// covers doses from `n * bin_width` up to `(n + 1) * bin_width`, and dose
+/// is taken to be spread evenly within a bin when interpolating metrics.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Dvh {
+    pub name: String,
+    /// DoseUnits of the dose, `GY` or `RELATIVE`
+    pub units: String,
+    pub bin_width: f32,
+    /// Volume receiving a dose within each bin
+    pub differential: Vec<f32>,
+    /// Volume receiving at least the lowest dose of each bin
+    pub cumulative: Vec<f32>,
+    /// Total volume of the structure
+    pub volume: f32,
+    pub min_dose: f32,
+    pub max_dose: f32,
+    pub mean_dose: f32,
+}
+
+impl Dvh {
+    /// Histogram the dose of the voxels in `mask`, which shares the grid of
+    /// `dose`, given in `units`.
+    pub fn compute(
+        name: &str,
+        dose: &ImageVolume,
+        units: &str,
+        mask: &[bool],
+        bin_width: f32,
+    ) -> Dvh {
+        let voxel_volume = dose.pixel_spacing.iter().product::<f32>() / 1000.;
+        let doses: Vec<f32> = dose
+            .volume
+            .iter()
+            .zip(mask)
+            .filter(|(_, &inside)| inside)
+            .map(|(&dose, _)| dose.max(0.))
+            .collect();
+
+        let min_dose = doses.iter().copied().reduce(f32::min).unwrap_or(0.);
+        let max_dose = doses.iter().copied().reduce(f32::max).unwrap_or(0.);
+        let mean_dose = if doses.is_empty() {
+            0.
+        } else {
+            (doses.iter().map(|&dose| dose as f64).sum::<f64>() / doses.len() as f64) as f32
+        };
+
+        // Voxels are counted so the cumulative volume adds up exactly
+        let mut counts = vec![0usize; (max_dose / bin_width) as usize + 1];
+        for dose in &doses {
+            counts[(dose / bin_width) as usize] += 1;
+        }
+        let mut cumulative = counts.clone();
+        for n in (0..cumulative.len() - 1).rev() {
+            cumulative[n] += cumulative[n + 1];
+        }
+        let to_volume = |counts: Vec<usize>| {
+            counts
+                .into_iter()
+                .map(|n| n as f32 * voxel_volume)
+                .collect()
+        };
+
+        Dvh {
+            name: name.to_string(),
+            units: units.to_string(),
+            bin_width,
+            differential: to_volume(counts),
+            cumulative: to_volume(cumulative),
+            volume: doses.len() as f32 * voxel_volume,
+            min_dose,
+            max_dose,
+            mean_dose,
+        }
+    }
+
+    /// Units of the doses as written beside values, e.g. `Gy`.
+    pub fn unit_label(&self) -> &str {
+        rtdose::unit_label(&self.units)
+    }
+
+    /// Lowest dose received by the hottest `percent` of the volume, as in
+    /// D95 for `95.`.
+    pub fn dose_at_volume(&self, percent: f32) -> f32 {
+        if self.volume == 0. {
+            return 0.;
+        }
+        let volume = percent.clamp(0., 100.) / 100. * self.volume;
+        let Some(n) = self.cumulative.iter().rposition(|&v| v >= volume) else {
+            return 0.;
+        };
+        let above = self.cumulative.get(n + 1).copied().unwrap_or(0.);
+        let fraction = (self.cumulative[n] - volume) / (self.cumulative[n] - above);
+        ((n as f32 + fraction) * self.bin_width).clamp(self.min_dose, self.max_dose)
+    }
+
+    /// Percentage of the volume receiving at least `dose`, as in V20 for
+    /// `20.`.
+    pub fn volume_at_dose(&self, dose: f32) -> f32 {
+        if self.volume == 0. {
+            return 0.;
+        }
+        let bin = dose.max(0.) / self.bin_width;
+        let n = bin as usize;
+        let Some(&volume) = self.cumulative.get(n) else {
+            return 0.;
+        };
+        let above = self.cumulative.get(n + 1).copied().unwrap_or(0.);
+        (volume - (bin - n as f32) * (volume - above)) / self.volume * 100.
+    }
+}
+
+/// DVH of each ROI enclosing voxels of `dose`, which has been resampled
+/// onto the image the ROIs are drawn on and is given in `units`.
+/// Overlapping ROIs each count the voxels they share.
+pub fn compute_dvhs(dose: &ImageVolume, units: &str, rois: &[Roi], bin_width: f32) -> Vec<Dvh> {
+    rois.par_iter()
+        .map(|roi| (roi, roi.rasterize(dose)))
+        .filter(|(_, mask)| mask.contains(&true))
+        .map(|(roi, mask)| Dvh::compute(&roi.name, dose, units, &mask, bin_width))
+        .collect()
+}
+
+/// Units of a set of histograms computed on the same dose, for headers.
+fn unit_label(dvhs: &[Dvh]) -> &str {
+    dvhs.first().map_or("Gy", Dvh::unit_label)
+}
+
+/// Write the differential and cumulative histograms, one row per structure
+/// and dose bin.
+pub fn write_dvh_csv<P: AsRef<Path>>(path: P, dvhs: &[Dvh]) -> Result<()> {
+    let unit = unit_label(dvhs);
+    write_csv(path.as_ref(), |out| {
+        writeln!(
+            out,
+            "Structure,Dose ({}),Differential (cm3),Cumulative (cm3),Cumulative (%)",
+            unit
+        )?;
+        for dvh in dvhs {
+            for (n, (differential, cumulative)) in
+                dvh.differential.iter().zip(&dvh.cumulative).enumerate()
+            {
+                writeln!(
+                    out,
+                    "{},{:.4},{},{},{}",
+                    csv_field(&dvh.name),
+                    n as f32 * dvh.bin_width,
+                    differential,
+                    cumulative,
+                    cumulative / dvh.volume * 100.
+                )?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Write the volume, Dmin, Dmax and Dmean of each structure, with the dose
+/// received by each of `volume_percents`, e.g. D95, and the volume
+/// receiving each of `doses`, e.g. V20.
+pub fn write_metrics_csv<P: AsRef<Path>>(
+    path: P,
+    dvhs: &[Dvh],
+    volume_percents: &[f32],
+    doses: &[f32],
+) -> Result<()> {
+    let unit = unit_label(dvhs);
+    write_csv(path.as_ref(), |out| {
+        write!(
+            out,
+            "Structure,Volume (cm3),Dmin ({unit}),Dmax ({unit}),Dmean ({unit})"
+        )?;
+        for percent in volume_percents {
+            write!(out, ",D{} ({})", percent, unit)?;
+        }
+        for dose in doses {
+            write!(out, ",V{} {} (%)", dose, unit)?;
+        }
+        writeln!(out)?;
+
+        for dvh in dvhs {
+            write!(
+                out,
+                "{},{},{},{},{}",
+                csv_field(&dvh.name),
+                dvh.volume,
+                dvh.min_dose,
+                dvh.max_dose,
+                dvh.mean_dose
+            )?;
+            for &percent in volume_percents {
+                write!(out, ",{}", dvh.dose_at_volume(percent))?;
+            }
+            for &dose in doses {
+                write!(out, ",{}", dvh.volume_at_dose(dose))?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    })
+}
+
+fn write_csv(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    write(&mut out)
+        .and_then(|_| out.flush())
+        .map_err(|error| anyhow!("Failed to write {}: {}", path.display(), error))
+}
+
+/// Quote a field containing separators or quotes.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{grid, temp_dir};
+
+    /// Doses 0, 1, ... 9 Gy in the first ten of twelve 1 cm³ voxels, the
+    /// last two outside the mask.
+    fn ramp() -> (ImageVolume, Vec<bool>) {
+        let mut dose = grid((4, 3, 1), [10., 10., 10.], [0., 0., 0.]);
+        for (index, value) in dose.volume.iter_mut().enumerate() {
+            *value = index as f32;
+        }
+        let mask = (0..12).map(|index| index < 10).collect();
+        (dose, mask)
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn histograms_the_dose_inside_the_mask() {
+        let (dose, mask) = ramp();
+        let dvh = Dvh::compute("PTV", &dose, "GY", &mask, 1.);
+        assert_eq!(dvh.units, "GY");
+        assert_eq!(dvh.volume, 10.);
+        assert_eq!((dvh.min_dose, dvh.max_dose, dvh.mean_dose), (0., 9., 4.5));
+        assert_eq!(dvh.differential, [1.; 10]);
+        assert_eq!(dvh.cumulative, [10., 9., 8., 7., 6., 5., 4., 3., 2., 1.]);
+    }
+
+    #[test]
+    fn bins_doses_by_width() {
+        let mut dose = grid((5, 1, 1), [10., 10., 10.], [0., 0., 0.]);
+        dose.volume = vec![0.2, 0.7, 0.74, 1.6, -0.3];
+        let dvh = Dvh::compute("PTV", &dose, "GY", &[true; 5], 0.5);
+        // Negative doses count as no dose
+        assert_eq!(dvh.min_dose, 0.);
+        assert_eq!(dvh.differential, [2., 2., 0., 1.]);
+        assert_eq!(dvh.cumulative, [5., 3., 1., 1.]);
+    }
+
+    #[test]
+    fn interpolates_volume_at_dose() {
+        let (dose, mask) = ramp();
+        let dvh = Dvh::compute("PTV", &dose, "GY", &mask, 1.);
+        assert_close(dvh.volume_at_dose(0.), 100.);
+        assert_close(dvh.volume_at_dose(-1.), 100.);
+        assert_close(dvh.volume_at_dose(5.), 50.);
+        assert_close(dvh.volume_at_dose(5.5), 45.);
+        assert_close(dvh.volume_at_dose(9.5), 5.);
+        assert_close(dvh.volume_at_dose(10.), 0.);
+        assert_close(dvh.volume_at_dose(20.), 0.);
+    }
+
+    #[test]
+    fn interpolates_dose_at_volume() {
+        let (dose, mask) = ramp();
+        let dvh = Dvh::compute("PTV", &dose, "GY", &mask, 1.);
+        assert_close(dvh.dose_at_volume(50.), 5.);
+        assert_close(dvh.dose_at_volume(45.), 5.5);
+        assert_close(dvh.dose_at_volume(95.), 0.5);
+        assert_close(dvh.dose_at_volume(100.), 0.);
+        // Clamped to the doses received
+        assert_close(dvh.dose_at_volume(0.), 9.);
+        assert_close(dvh.dose_at_volume(150.), 0.);
+    }
+
+    #[test]
+    fn reports_nothing_for_an_empty_mask() {
+        let (dose, _) = ramp();
+        let dvh = Dvh::compute("Empty", &dose, "GY", &[false; 12], 1.);
+        assert_eq!(dvh.volume, 0.);
+        assert_eq!(dvh.cumulative, [0.]);
+        assert_eq!(dvh.volume_at_dose(0.), 0.);
+        assert_eq!(dvh.dose_at_volume(95.), 0.);
+    }
+
+    #[test]
+    fn computes_histograms_of_rois_enclosing_voxels() {
+        let (dose, _) = ramp();
+        let square = |min: f32, max: f32| Roi {
+            number: 1,
+            name: format!("{}-{}", min, max),
+            interpreted_type: String::new(),
+            color: [255, 0, 0],
+            contours: vec![vec![
+                [min, min, 0.],
+                [max, min, 0.],
+                [max, max, 0.],
+                [min, max, 0.],
+            ]],
+        };
+        // Voxel centres lie 10 mm apart from the origin
+        let rois = [square(-5., 15.), square(100., 120.)];
+        let dvhs = compute_dvhs(&dose, "RELATIVE", &rois, 1.);
+        assert_eq!(dvhs.len(), 1);
+        assert_eq!(dvhs[0].name, "-5-15");
+        assert_eq!(dvhs[0].volume, 4.);
+        assert_eq!((dvhs[0].min_dose, dvhs[0].max_dose), (0., 5.));
+        assert_eq!(dvhs[0].unit_label(), "relative");
+    }
+
+    #[test]
+    fn labels_csv_columns_with_the_dose_units() {
+        let dir = temp_dir("dvh-csv");
+        let (dose, mask) = ramp();
+        let dvhs = [Dvh::compute("PTV, boost", &dose, "GY", &mask, 1.)];
+        dvh_files_contain(&dir, &dvhs, "Dose (Gy)", "D95 (Gy),V5 Gy (%)");
+
+        let dvhs = [Dvh::compute("PTV, boost", &dose, "RELATIVE", &mask, 1.)];
+        dvh_files_contain(
+            &dir,
+            &dvhs,
+            "Dose (relative)",
+            "D95 (relative),V5 relative (%)",
+        );
+    }
+
+    fn dvh_files_contain(dir: &Path, dvhs: &[Dvh], dvh_header: &str, metrics_header: &str) {
+        write_dvh_csv(dir.join("dvh.csv"), dvhs).unwrap();
+        let csv = std::fs::read_to_string(dir.join("dvh.csv")).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].contains(dvh_header), "{}", lines[0]);
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[6], "\"PTV, boost\",5.0000,1,5,50");
+
+        write_metrics_csv(dir.join("metrics.csv"), dvhs, &[95.], &[5.]).unwrap();
+        let csv = std::fs::read_to_string(dir.join("metrics.csv")).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].ends_with(metrics_header), "{}", lines[0]);
+        assert_eq!(lines[1], "\"PTV, boost\",10,0,9,4.5,0.5,50");
+    }
+}
@@ -1,8 +1,15 @@
 use std::{iter, sync::Arc};
 
+use anyhow::{anyhow, Result};
 use winit::window::Window;
 
-use crate::dicom_reader::ImageVolume;
+use wgpu::util::DeviceExt;
+
+use crate::image_volume::{self, ImageVolume, Mat4};
+use crate::rtstruct::LabelVolume;
+
+/// Labels that can be coloured in the overlay
+const OVERLAY_COLORS: usize = 256;
 
 pub struct Graphics {
     surface: wgpu::Surface<'static>,
@@ -11,12 +18,55 @@ pub struct Graphics {
     render_pipeline: wgpu::RenderPipeline,
     pub window: Arc<Window>,
     bind_group: wgpu::BindGroup,
+    bind_group_layout: wgpu::BindGroupLayout,
+    volume_sampler: wgpu::Sampler,
+    overlay_bind_group: wgpu::BindGroup,
+    overlay_bind_group_layout: wgpu::BindGroupLayout,
+    dose_bind_group: wgpu::BindGroup,
+    dose_bind_group_layout: wgpu::BindGroupLayout,
+    dose_thresholds_buffer: wgpu::Buffer,
     uniforms_buffer: wgpu::Buffer,
+    config: wgpu::SurfaceConfiguration,
+    /// Columns, rows and slices of the rendered volume
+    volume_size: (u16, u16, usize),
+}
+
+/// A rendered frame read back from the GPU, 8 bit RGB row by row from the top.
+pub struct RenderedFrame {
+    pub width: u32,
+    pub height: u32,
+    pub rgb: Vec<u8>,
+}
+
+/// Single black voxel bound in place of a volume that has not been set.
+fn empty_volume() -> ImageVolume {
+    ImageVolume {
+        columns: 1,
+        rows: 1,
+        slices: 1,
+        pixel_spacing: [1., 1., 1.],
+        position_patient: [0., 0., 0.],
+        image_orientation_patient: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+        volume: vec![0.],
+        metadata: Default::default(),
+    }
+}
+
+/// Single unlabelled voxel bound in place of labels that have not been set.
+fn no_labels() -> LabelVolume {
+    LabelVolume {
+        columns: 1,
+        rows: 1,
+        slices: 1,
+        labels: vec![0],
+        colors: Vec::new(),
+    }
 }
 
 impl Graphics {
     /// Initialize gpu resources , get device connection, compile shaders etc.
-    pub async fn new(window: Window, image: ImageVolume) -> Self {
+    /// Nothing is rendered until a volume is set.
+    pub async fn new(window: Window) -> Self {
         let window = Arc::new(window);
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
@@ -33,6 +83,8 @@ impl Graphics {
             .request_device(
                 &wgpu::DeviceDescriptor {
                     required_features: wgpu::Features::FLOAT32_FILTERABLE,
+                    // Large volumes need the full texture size of the adapter
+                    required_limits: adapter.limits(),
                     ..Default::default()
                 },
                 None,
@@ -73,40 +125,6 @@ impl Graphics {
             ..Default::default()
         });
 
-        let texture_size = wgpu::Extent3d {
-            width: image.columns as u32,
-            height: image.rows as u32,
-            depth_or_array_layers: image.slices as u32,
-        };
-
-        let volume_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Volume texture"),
-            format: wgpu::TextureFormat::R32Float,
-            dimension: wgpu::TextureDimension::D3,
-            size: texture_size,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            mip_level_count: 1,
-            sample_count: 1,
-            view_formats: &[],
-        });
-
-        // write image data to texture
-        queue.write_texture(
-            wgpu::ImageCopyTextureBase {
-                texture: &volume_texture,
-                mip_level: 0,
-                origin: Default::default(),
-                aspect: Default::default(),
-            },
-            bytemuck::cast_slice(image.volume.as_slice()),
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(image.columns as u32 * 4),
-                rows_per_image: Some(image.rows as u32),
-            },
-            texture_size,
-        );
-
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Bind Group Layout"),
             entries: &[
@@ -136,13 +154,81 @@ impl Graphics {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    count: None,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                },
             ],
         });
 
+        let overlay_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Overlay Bind Group Layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Uint,
+                            view_dimension: wgpu::TextureViewDimension::D3,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                    },
+                ],
+            });
+
+        let dose_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Dose Bind Group Layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D3,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        count: None,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                    },
+                ],
+            });
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render pipeline layout"),
-                bind_group_layouts: &[&bind_group_layout],
+                bind_group_layouts: &[
+                    &bind_group_layout,
+                    &overlay_bind_group_layout,
+                    &dose_bind_group_layout,
+                ],
                 push_constant_ranges: &[],
             });
 
@@ -153,6 +239,14 @@ impl Graphics {
             mapped_at_creation: false,
         });
 
+        // Lowest and highest dose of the colour wash
+        let dose_thresholds_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Dose Thresholds Uniform"),
+            size: 4 * 2,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+            mapped_at_creation: false,
+        });
+
         let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("Render pipeline"),
             layout: Some(&render_pipeline_layout),
@@ -179,30 +273,32 @@ impl Graphics {
             cache: None,
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Volume Render Bindgroup"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: &uniforms_buffer,
-                        offset: Default::default(),
-                        size: Default::default(),
-                    }),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(
-                        &volume_texture.create_view(&wgpu::TextureViewDescriptor::default()),
-                    ),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::Sampler(&volume_sampler),
-                },
-            ],
-        });
+        let bind_group = create_volume_bind_group(
+            &device,
+            &queue,
+            &bind_group_layout,
+            &volume_sampler,
+            &uniforms_buffer,
+            &empty_volume(),
+        );
+
+        // Nothing is overlaid until labels are set
+        let overlay_bind_group =
+            create_overlay_bind_group(&device, &queue, &overlay_bind_group_layout, &no_labels());
+
+        // Nor is any dose washed over the volume until one is set
+        let dose_bind_group = create_dose_bind_group(
+            &device,
+            &queue,
+            &dose_bind_group_layout,
+            &dose_thresholds_buffer,
+            &empty_volume(),
+        );
+        queue.write_buffer(
+            &dose_thresholds_buffer,
+            0,
+            bytemuck::cast_slice(&[f32::INFINITY, f32::INFINITY]),
+        );
 
         Self {
             device,
@@ -211,15 +307,97 @@ impl Graphics {
             window,
             surface,
             bind_group,
+            bind_group_layout,
+            volume_sampler,
+            overlay_bind_group,
+            overlay_bind_group_layout,
+            dose_bind_group,
+            dose_bind_group_layout,
+            dose_thresholds_buffer,
             uniforms_buffer,
+            config,
+            volume_size: (1, 1, 1),
         }
     }
 
-    pub fn render(&mut self, sliders: &[f32]) -> Result<(), wgpu::SurfaceError> {
-        // write uniforms like rotations, slice depth
-        self.queue
-            .write_buffer(&self.uniforms_buffer, 0, bytemuck::cast_slice(sliders));
+    /// Largest side of a volume texture on the device.
+    pub fn max_volume_dimension(&self) -> u32 {
+        self.device.limits().max_texture_dimension_3d
+    }
 
+    /// Replace the rendered volume, keeping the view settings. The labels
+    /// and dose of the previous volume are cleared.
+    pub fn set_volume(&mut self, image: &ImageVolume) {
+        self.bind_group = create_volume_bind_group(
+            &self.device,
+            &self.queue,
+            &self.bind_group_layout,
+            &self.volume_sampler,
+            &self.uniforms_buffer,
+            image,
+        );
+        self.volume_size = (image.columns, image.rows, image.slices);
+        self.overlay_bind_group = create_overlay_bind_group(
+            &self.device,
+            &self.queue,
+            &self.overlay_bind_group_layout,
+            &no_labels(),
+        );
+        self.dose_bind_group = create_dose_bind_group(
+            &self.device,
+            &self.queue,
+            &self.dose_bind_group_layout,
+            &self.dose_thresholds_buffer,
+            &empty_volume(),
+        );
+        self.set_dose_thresholds(f32::INFINITY, f32::INFINITY);
+    }
+
+    /// Colour the labelled voxels of the volume, such as rasterized ROIs.
+    /// The labels must have been rasterized for the rendered volume.
+    pub fn set_labels(&mut self, labels: &LabelVolume) {
+        assert_eq!(
+            (labels.columns, labels.rows, labels.slices),
+            self.volume_size,
+            "Labels rasterized for another volume"
+        );
+        self.overlay_bind_group = create_overlay_bind_group(
+            &self.device,
+            &self.queue,
+            &self.overlay_bind_group_layout,
+            labels,
+        );
+    }
+
+    /// Wash a dose distribution over the volume, coloured from the lowest
+    /// to the highest threshold. The dose must share the grid of the
+    /// rendered volume, see [`ImageVolume::resample_onto`].
+    pub fn set_dose(&mut self, dose: &ImageVolume) {
+        assert_eq!(
+            (dose.columns, dose.rows, dose.slices),
+            self.volume_size,
+            "Dose resampled onto another volume"
+        );
+        self.dose_bind_group = create_dose_bind_group(
+            &self.device,
+            &self.queue,
+            &self.dose_bind_group_layout,
+            &self.dose_thresholds_buffer,
+            dose,
+        );
+    }
+
+    /// Dose below `minimum` is not coloured, and dose above `maximum` has
+    /// the colour of the maximum.
+    pub fn set_dose_thresholds(&mut self, minimum: f32, maximum: f32) {
+        self.queue.write_buffer(
+            &self.dose_thresholds_buffer,
+            0,
+            bytemuck::cast_slice(&[minimum, maximum]),
+        );
+    }
+
+    pub fn render(&mut self, sliders: &[f32]) -> Result<(), wgpu::SurfaceError> {
         let output = self.surface.get_current_texture()?;
         let view = output
             .texture
@@ -228,11 +406,102 @@ impl Graphics {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        self.draw(sliders, &view, &mut encoder);
+
+        let command_buffer = encoder.finish();
+        self.queue.submit(iter::once(command_buffer));
+        output.present();
+
+        Ok(())
+    }
+
+    /// Render a frame the size of the window off screen and read it back.
+    pub fn capture(&mut self, sliders: &[f32]) -> Result<RenderedFrame> {
+        let (width, height) = (self.config.width, self.config.height);
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Capture Texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // Rows of a texture copy must be aligned
+        let unpadded_bytes_per_row = width * 4;
+        let bytes_per_row = unpadded_bytes_per_row.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+            * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Capture Buffer"),
+            size: (bytes_per_row * height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        self.draw(sliders, &view, &mut encoder);
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+        self.queue.submit(iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver.recv()??;
+
+        // Surface formats store either blue or red first
+        let bgra = match self.config.format {
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => false,
+            format => return Err(anyhow!("Cannot capture frames of format {:?}", format)),
+        };
+        let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+        for row in slice.get_mapped_range().chunks(bytes_per_row as usize) {
+            for pixel in row[..unpadded_bytes_per_row as usize].chunks_exact(4) {
+                if bgra {
+                    rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+                } else {
+                    rgb.extend_from_slice(&pixel[..3]);
+                }
+            }
+        }
+        buffer.unmap();
+
+        Ok(RenderedFrame { width, height, rgb })
+    }
+
+    /// Record the volume rendering pass into `view`.
+    fn draw(&self, sliders: &[f32], view: &wgpu::TextureView, encoder: &mut wgpu::CommandEncoder) {
+        // write uniforms like rotations, slice depth
+        self.queue
+            .write_buffer(&self.uniforms_buffer, 0, bytemuck::cast_slice(sliders));
 
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Render Pass"),
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
+                view,
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
@@ -243,13 +512,275 @@ impl Graphics {
         });
         render_pass.set_pipeline(&self.render_pipeline);
         render_pass.set_bind_group(0, Some(&self.bind_group), &[]);
+        render_pass.set_bind_group(1, Some(&self.overlay_bind_group), &[]);
+        render_pass.set_bind_group(2, Some(&self.dose_bind_group), &[]);
         render_pass.draw(0..6, 0..1);
-        drop(render_pass);
+    }
+}
 
-        let command_buffer = encoder.finish();
-        self.queue.submit(iter::once(command_buffer));
-        output.present();
+/// Upload a volume to a new texture and bind it with the view uniforms.
+fn create_volume_bind_group(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    volume_sampler: &wgpu::Sampler,
+    uniforms_buffer: &wgpu::Buffer,
+    image: &ImageVolume,
+) -> wgpu::BindGroup {
+    let texture_size = wgpu::Extent3d {
+        width: image.columns as u32,
+        height: image.rows as u32,
+        depth_or_array_layers: image.slices as u32,
+    };
 
-        Ok(())
+    let volume_texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Volume texture"),
+        format: wgpu::TextureFormat::R32Float,
+        dimension: wgpu::TextureDimension::D3,
+        size: texture_size,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        mip_level_count: 1,
+        sample_count: 1,
+        view_formats: &[],
+    });
+
+    // write image data to texture
+    queue.write_texture(
+        wgpu::ImageCopyTextureBase {
+            texture: &volume_texture,
+            mip_level: 0,
+            origin: Default::default(),
+            aspect: Default::default(),
+        },
+        bytemuck::cast_slice(image.volume.as_slice()),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(image.columns as u32 * 4),
+            rows_per_image: Some(image.rows as u32),
+        },
+        texture_size,
+    );
+
+    let world_to_texture_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("World To Texture Uniform"),
+        contents: bytemuck::cast_slice(&world_to_texture(image)),
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Volume Render Bindgroup"),
+        layout: bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: uniforms_buffer,
+                    offset: Default::default(),
+                    size: Default::default(),
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(
+                    &volume_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(volume_sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: world_to_texture_buffer.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+/// Upload a label volume and the colour of each label.
+fn create_overlay_bind_group(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    labels: &LabelVolume,
+) -> wgpu::BindGroup {
+    let texture_size = wgpu::Extent3d {
+        width: labels.columns as u32,
+        height: labels.rows as u32,
+        depth_or_array_layers: labels.slices as u32,
+    };
+
+    let label_texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Label texture"),
+            format: wgpu::TextureFormat::R8Uint,
+            dimension: wgpu::TextureDimension::D3,
+            size: texture_size,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            mip_level_count: 1,
+            sample_count: 1,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        &labels.labels,
+    );
+
+    // Label zero is transparent, label n has the colour n - 1
+    let mut colors = [[0f32; 4]; OVERLAY_COLORS];
+    for (color, rgb) in colors[1..].iter_mut().zip(&labels.colors) {
+        *color = [rgb[0], rgb[1], rgb[2], 255].map(|c| c as f32 / 255.);
+    }
+    let colors_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Label Colors Uniform"),
+        contents: bytemuck::cast_slice(&colors),
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Overlay Bindgroup"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &label_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: colors_buffer.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+/// Upload a dose grid and bind it with the colour wash thresholds.
+fn create_dose_bind_group(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    thresholds_buffer: &wgpu::Buffer,
+    dose: &ImageVolume,
+) -> wgpu::BindGroup {
+    let dose_texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Dose texture"),
+            format: wgpu::TextureFormat::R32Float,
+            dimension: wgpu::TextureDimension::D3,
+            size: wgpu::Extent3d {
+                width: dose.columns as u32,
+                height: dose.rows as u32,
+                depth_or_array_layers: dose.slices as u32,
+            },
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            mip_level_count: 1,
+            sample_count: 1,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(dose.volume.as_slice()),
+    );
+
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Dose Bindgroup"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &dose_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: thresholds_buffer.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+/// Matrix from the rendered world, a cube of side one centred on the volume
+/// and aligned with the patient axes, to volume texture coordinates. The cube
+/// is scaled to the longest physical extent so the aspect ratio is preserved.
+fn world_to_texture(image: &ImageVolume) -> Mat4 {
+    let dims = [image.columns as f32, image.rows as f32, image.slices as f32];
+
+    // Bounding box of the volume, including half a voxel around the centres
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for corner in 0..8 {
+        let index = [0, 1, 2].map(|axis| {
+            if corner & (1 << axis) == 0 {
+                -0.5
+            } else {
+                dims[axis] - 0.5
+            }
+        });
+        let point = image.index_to_patient(index);
+        for axis in 0..3 {
+            min[axis] = min[axis].min(point[axis]);
+            max[axis] = max[axis].max(point[axis]);
+        }
+    }
+
+    let center = [0, 1, 2].map(|axis| (min[axis] + max[axis]) / 2.);
+    let extent = (0..3).map(|axis| max[axis] - min[axis]).fold(0., f32::max);
+
+    let world_to_patient = [
+        [extent, 0., 0., 0.],
+        [0., extent, 0., 0.],
+        [0., 0., extent, 0.],
+        [center[0], center[1], center[2], 1.],
+    ];
+    let voxel_to_texture = [
+        [1. / dims[0], 0., 0., 0.],
+        [0., 1. / dims[1], 0., 0.],
+        [0., 0., 1. / dims[2], 0.],
+        [0.5 / dims[0], 0.5 / dims[1], 0.5 / dims[2], 1.],
+    ];
+
+    image_volume::mul(
+        &voxel_to_texture,
+        &image_volume::mul(&image.patient_to_voxel(), &world_to_patient),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_volume::{transform_point, Vec3};
+    use crate::test_util::grid;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        for axis in 0..3 {
+            assert!((a[axis] - b[axis]).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn fits_the_longest_extent_into_the_world_cube() {
+        // 256 mm wide and deep, 120 mm high, only the geometry is needed
+        let image = ImageVolume {
+            columns: 512,
+            rows: 512,
+            slices: 40,
+            ..grid((1, 1, 1), [0.5, 0.5, 3.], [-127.75, -127.75, 1.5])
+        };
+        let m = world_to_texture(&image);
+
+        let height = 120. / 256.;
+        assert_close(transform_point(&m, [0., 0., 0.]), [0.5, 0.5, 0.5]);
+        assert_close(
+            transform_point(&m, [-0.5, -0.5, -height / 2.]),
+            [0., 0., 0.],
+        );
+        assert_close(transform_point(&m, [0.5, 0.5, height / 2.]), [1., 1., 1.]);
+        // The world cube reaches past the volume along the shorter axis
+        assert_close(
+            transform_point(&m, [0.5, -0.5, 0.5]),
+            [1., 0., 0.5 / height + 0.5],
+        );
     }
 }
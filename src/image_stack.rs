@@ -0,0 +1,349 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::ColorType;
+
+use crate::image_volume::{ImageVolume, Vec3, VolumeMetadata};
+
+/// Sidecar file giving the column, row and slice spacing of a stack folder
+const SPACING_FILE: &str = "spacing.txt";
+
+/// A single grayscale slice, row by row.
+struct Slice {
+    width: u32,
+    height: u32,
+    pixels: Vec<f32>,
+}
+
+/// Load a folder of grayscale TIFF or PNG slices, or a multi-page TIFF file,
+/// as an axis aligned volume.
+///
+/// Folder slices are stacked in natural filename order, so `slice2.png`
+/// comes before `slice10.png`. Spacing is taken from `spacing`, then from
+/// three numbers in a `spacing.txt` file beside the slices, then defaults
+/// to one unit.
+pub fn load_image_stack<P: AsRef<Path>>(path: P, spacing: Option<Vec3>) -> Result<ImageVolume> {
+    let path = path.as_ref();
+    let (slices, dir) = if path.is_dir() {
+        let files = find_slice_files(path)?;
+        if files.is_empty() {
+            return Err(anyhow!("No TIFF or PNG slices found in {}", path.display()));
+        }
+        let mut slices = Vec::new();
+        for file in &files {
+            if is_png(file) {
+                slices.push(read_png(file)?);
+            } else {
+                slices.extend(read_tiff(file)?);
+            }
+        }
+        (slices, path)
+    } else {
+        (read_tiff(path)?, path.parent().unwrap_or(Path::new(".")))
+    };
+
+    let (width, height) = (slices[0].width, slices[0].height);
+    if let Some(slice) = slices
+        .iter()
+        .find(|slice| slice.width != width || slice.height != height)
+    {
+        return Err(anyhow!(
+            "Slice of {}x{} pixels in a stack of {}x{}",
+            slice.width,
+            slice.height,
+            width,
+            height
+        ));
+    }
+
+    let spacing = match spacing {
+        Some(spacing) => spacing,
+        None => read_spacing_file(dir)?.unwrap_or([1.; 3]),
+    };
+
+    Ok(ImageVolume {
+        columns: u16::try_from(width)?,
+        rows: u16::try_from(height)?,
+        slices: slices.len(),
+        pixel_spacing: spacing,
+        position_patient: [0.; 3],
+        image_orientation_patient: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+        volume: slices.into_iter().flat_map(|slice| slice.pixels).collect(),
+        metadata: VolumeMetadata::default(),
+    })
+}
+
+/// TIFF and PNG files directly inside `dir`, in natural filename order.
+pub fn find_slice_files<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && (is_png(&path) || is_tiff(&path)) {
+            files.push(path);
+        }
+    }
+    files.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    Ok(files)
+}
+
+fn is_tiff(path: &Path) -> bool {
+    has_extension(path, &["tif", "tiff"])
+}
+
+fn is_png(path: &Path) -> bool {
+    has_extension(path, &["png"])
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .is_some_and(|ext| extensions.contains(&ext.as_str()))
+}
+
+/// Compare strings with runs of digits ordered by their numeric value.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_digits(&mut a);
+                let y = take_digits(&mut b);
+                // Compare by length first so long numbers cannot overflow
+                let ordering = x
+                    .trim_start_matches('0')
+                    .len()
+                    .cmp(&y.trim_start_matches('0').len())
+                    .then_with(|| x.trim_start_matches('0').cmp(y.trim_start_matches('0')))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        digits.push(c);
+    }
+    digits
+}
+
+fn read_spacing_file(dir: &Path) -> Result<Option<Vec3>> {
+    let path = dir.join(SPACING_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let text = std::fs::read_to_string(&path)?;
+    let values: Vec<f32> = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("Invalid spacing in {}", path.display()))?;
+    let spacing = values
+        .try_into()
+        .map_err(|_| anyhow!("Expected three spacings in {}", path.display()))?;
+    Ok(Some(spacing))
+}
+
+/// Every page of a grayscale TIFF file.
+fn read_tiff(path: &Path) -> Result<Vec<Slice>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut decoder = Decoder::new(BufReader::new(file))
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut slices = Vec::new();
+    loop {
+        let colortype = decoder.colortype()?;
+        if !matches!(colortype, ColorType::Gray(_)) {
+            return Err(anyhow!(
+                "Unsupported TIFF color type {:?} in {}",
+                colortype,
+                path.display()
+            ));
+        }
+
+        let (width, height) = decoder.dimensions()?;
+        let pixels = match decoder.read_image()? {
+            DecodingResult::U8(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::U16(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::U32(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::U64(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::I8(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::I16(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::I32(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::I64(data) => data.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::F32(data) => data,
+            DecodingResult::F64(data) => data.into_iter().map(|v| v as f32).collect(),
+        };
+        slices.push(Slice {
+            width,
+            height,
+            pixels,
+        });
+
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    Ok(slices)
+}
+
+/// A grayscale PNG, with bit depths below eight expanded to bytes.
+fn read_png(path: &Path) -> Result<Slice> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder
+        .read_info()
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    if info.color_type != png::ColorType::Grayscale {
+        return Err(anyhow!(
+            "Unsupported PNG color type {:?} in {}",
+            info.color_type,
+            path.display()
+        ));
+    }
+
+    let buffer = &buffer[..info.buffer_size()];
+    let pixels = match info.bit_depth {
+        // Sixteen bit samples are stored big endian
+        png::BitDepth::Sixteen => buffer
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as f32)
+            .collect(),
+        _ => buffer.iter().map(|&v| v as f32).collect(),
+    };
+
+    Ok(Slice {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    use super::*;
+    use crate::test_util::{temp_dir, write_png};
+
+    #[test]
+    fn orders_numbers_by_value() {
+        let mut names = ["slice10", "slice2", "slice1", "slice02", "a", "slice"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            ["a", "slice", "slice1", "slice2", "slice02", "slice10"]
+        );
+        assert_eq!(
+            natural_cmp("99999999999999999999999", "100000000000000000000000"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn stacks_png_slices_in_natural_order() {
+        let dir = temp_dir("stack-png");
+        for n in [10, 2, 1] {
+            write_png(&dir.join(format!("slice{}.png", n)), 2, 1, &[n, n + 100]);
+        }
+        std::fs::write(dir.join(SPACING_FILE), "0.5, 0.25\n3\n").unwrap();
+
+        let image = load_image_stack(&dir, None).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (2, 1, 3));
+        assert_eq!(image.pixel_spacing, [0.5, 0.25, 3.]);
+        assert_eq!(image.volume, [1., 101., 2., 102., 10., 110.]);
+
+        let image = load_image_stack(&dir, Some([2., 2., 2.])).unwrap();
+        assert_eq!(image.pixel_spacing, [2., 2., 2.]);
+    }
+
+    #[test]
+    fn reads_sixteen_bit_png() {
+        let path = temp_dir("stack-png16").join("slice.png");
+        let mut encoder = png::Encoder::new(File::create(&path).unwrap(), 2, 1);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        let data: Vec<u8> = [300u16, 65535]
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect();
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&data)
+            .unwrap();
+
+        let slice = read_png(&path).unwrap();
+
+        assert_eq!(slice.pixels, [300., 65535.]);
+    }
+
+    #[test]
+    fn reads_every_page_of_a_tiff() {
+        let path = temp_dir("stack-tiff").join("stack.tif");
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        for page in 0..3u16 {
+            encoder
+                .write_image::<colortype::Gray16>(3, 2, &[page * 1000; 6])
+                .unwrap();
+        }
+        drop(encoder);
+
+        let image = load_image_stack(&path, None).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (3, 2, 3));
+        assert_eq!(image.pixel_spacing, [1., 1., 1.]);
+        for (page, slice) in image.volume.chunks(6).enumerate() {
+            assert_eq!(slice, [page as f32 * 1000.; 6]);
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_and_colour_slices() {
+        let dir = temp_dir("stack-mismatched");
+        write_png(&dir.join("1.png"), 2, 2, &[0; 4]);
+        write_png(&dir.join("2.png"), 3, 2, &[0; 6]);
+        let error = load_image_stack(&dir, None).unwrap_err();
+        assert!(error.to_string().contains("3x2 pixels"), "{}", error);
+
+        let path = temp_dir("stack-rgb").join("rgb.tif");
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        encoder
+            .write_image::<colortype::RGB8>(1, 1, &[1, 2, 3])
+            .unwrap();
+        drop(encoder);
+        let error = load_image_stack(&path, None).unwrap_err();
+        assert!(error.to_string().contains("color type"), "{}", error);
+
+        let empty = temp_dir("stack-empty");
+        assert!(load_image_stack(&empty, None).is_err());
+    }
+}
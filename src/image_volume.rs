@@ -0,0 +1,395 @@
+use rayon::prelude::*;
+
+pub type Vec3 = [f32; 3];
+
+/// Column major 4x4 matrix, `m[column][row]`, matching WGSL `mat4x4f`.
+pub type Mat4 = [[f32; 4]; 4];
+
+/// A scalar volume stored slice by slice, each slice row by row.
+///
+/// Geometry follows the DICOM patient coordinate system (LPS, millimetres):
+/// voxel `[i, j, k]` (column, row, slice) lies at `position_patient` plus the
+/// sum of each index times its `pixel_spacing` along its axis in
+/// `image_orientation_patient`.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct ImageVolume {
+    pub columns: u16,
+    pub rows: u16,
+    pub slices: usize,
+    /// Distance between voxel centres along the column, row and slice axes
+    pub pixel_spacing: Vec3,
+    pub position_patient: Vec3,
+    /// Unit direction of the column, row and slice axes
+    pub image_orientation_patient: [Vec3; 3],
+    pub volume: Vec<f32>,
+    pub metadata: VolumeMetadata,
+}
+
+/// Patient, study and series attributes of the source of a volume.
+/// Empty strings and `None` stand for attributes that were not present.
+#[derive(Debug, Clone, Default)]
+#[allow(dead_code)]
+pub struct VolumeMetadata {
+    pub patient_name: String,
+    pub patient_id: String,
+    pub patient_birth_date: String,
+    pub patient_sex: String,
+    pub study_date: String,
+    pub study_time: String,
+    pub study_id: String,
+    pub study_description: String,
+    pub accession_number: String,
+    pub modality: String,
+    pub series_description: String,
+    pub series_number: String,
+    pub manufacturer: String,
+    pub kvp: Option<f32>,
+    pub slice_thickness: Option<f32>,
+    pub window_center: Option<f32>,
+    pub window_width: Option<f32>,
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+    pub frame_of_reference_uid: String,
+    /// SOP instance UIDs of the source images, in slice order. Empty when
+    /// the slices were resampled and no longer match the source images.
+    pub sop_instance_uids: Vec<String>,
+}
+
+/// Anatomical direction in the patient coordinate system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum AnatomicalDirection {
+    Left,
+    Right,
+    Posterior,
+    Anterior,
+    Superior,
+    Inferior,
+}
+
+impl AnatomicalDirection {
+    /// The direction a patient space vector points to the most.
+    pub fn from_vector(v: Vec3) -> Self {
+        let axis = (0..3)
+            .max_by(|&a, &b| v[a].abs().total_cmp(&v[b].abs()))
+            .unwrap();
+        match (axis, v[axis] >= 0.) {
+            (0, true) => AnatomicalDirection::Left,
+            (0, false) => AnatomicalDirection::Right,
+            (1, true) => AnatomicalDirection::Posterior,
+            (1, false) => AnatomicalDirection::Anterior,
+            (_, true) => AnatomicalDirection::Superior,
+            (_, false) => AnatomicalDirection::Inferior,
+        }
+    }
+
+    pub fn letter(&self) -> char {
+        match self {
+            AnatomicalDirection::Left => 'L',
+            AnatomicalDirection::Right => 'R',
+            AnatomicalDirection::Posterior => 'P',
+            AnatomicalDirection::Anterior => 'A',
+            AnatomicalDirection::Superior => 'S',
+            AnatomicalDirection::Inferior => 'I',
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl ImageVolume {
+    /// Matrix taking homogeneous voxel indices to LPS millimetres.
+    pub fn voxel_to_patient(&self) -> Mat4 {
+        let [x, y, z] = self.image_orientation_patient;
+        let [sx, sy, sz] = self.pixel_spacing;
+        let o = self.position_patient;
+        [
+            [x[0] * sx, x[1] * sx, x[2] * sx, 0.],
+            [y[0] * sy, y[1] * sy, y[2] * sy, 0.],
+            [z[0] * sz, z[1] * sz, z[2] * sz, 0.],
+            [o[0], o[1], o[2], 1.],
+        ]
+    }
+
+    /// Matrix taking LPS millimetres to homogeneous voxel indices.
+    ///
+    /// The axes are not assumed orthogonal, so volumes with an uncorrected
+    /// gantry tilt are inverted exactly.
+    pub fn patient_to_voxel(&self) -> Mat4 {
+        let m = self.voxel_to_patient();
+        let a = [m[0], m[1], m[2]].map(|column| [column[0], column[1], column[2]]);
+
+        // Inverse of the linear part through the adjugate
+        let det = dot(a[0], cross(a[1], a[2]));
+        let rows = [cross(a[1], a[2]), cross(a[2], a[0]), cross(a[0], a[1])]
+            .map(|row| row.map(|v| v / det));
+
+        let o = self.position_patient;
+        let t = rows.map(|row| -dot(row, o));
+        [
+            [rows[0][0], rows[1][0], rows[2][0], 0.],
+            [rows[0][1], rows[1][1], rows[2][1], 0.],
+            [rows[0][2], rows[1][2], rows[2][2], 0.],
+            [t[0], t[1], t[2], 1.],
+        ]
+    }
+
+    /// Position in LPS millimetres of a possibly fractional voxel index.
+    pub fn index_to_patient(&self, index: Vec3) -> Vec3 {
+        transform_point(&self.voxel_to_patient(), index)
+    }
+
+    /// Possibly fractional voxel index of a position in LPS millimetres.
+    pub fn patient_to_index(&self, point: Vec3) -> Vec3 {
+        transform_point(&self.patient_to_voxel(), point)
+    }
+
+    /// Direction each of the column, row and slice axes points to.
+    pub fn axis_directions(&self) -> [AnatomicalDirection; 3] {
+        self.image_orientation_patient
+            .map(AnatomicalDirection::from_vector)
+    }
+
+    /// Three letter orientation code such as `LPS` for an axial volume.
+    pub fn orientation_code(&self) -> String {
+        self.axis_directions().iter().map(|d| d.letter()).collect()
+    }
+
+    /// Trilinear interpolation at a fractional voxel index, or `None` more
+    /// than half a voxel outside the volume.
+    pub fn sample(&self, index: Vec3) -> Option<f32> {
+        let dims = [self.columns as usize, self.rows as usize, self.slices];
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        let mut weight = [0.; 3];
+        for axis in 0..3 {
+            let last = dims[axis] as f32 - 1.;
+            if !(-0.5..=last + 0.5).contains(&index[axis]) {
+                return None;
+            }
+            let i = index[axis].clamp(0., last);
+            lower[axis] = i.floor() as usize;
+            upper[axis] = (lower[axis] + 1).min(dims[axis] - 1);
+            weight[axis] = i - lower[axis] as f32;
+        }
+
+        let voxel = |i: usize, j: usize, k: usize| self.volume[(k * dims[1] + j) * dims[0] + i];
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let [i0, j0, k0] = lower;
+        let [i1, j1, k1] = upper;
+        let [wi, wj, wk] = weight;
+        let plane = |k| {
+            lerp(
+                lerp(voxel(i0, j0, k), voxel(i1, j0, k), wi),
+                lerp(voxel(i0, j1, k), voxel(i1, j1, k), wi),
+                wj,
+            )
+        };
+        Some(lerp(plane(k0), plane(k1), wk))
+    }
+
+    /// Interpolate this volume at the voxel centres of `grid`, which may have
+    /// any geometry in the same patient coordinates. Voxels of `grid` outside
+    /// this volume are zero.
+    pub fn resample_onto(&self, grid: &ImageVolume) -> ImageVolume {
+        let grid_to_self = mul(&self.patient_to_voxel(), &grid.voxel_to_patient());
+        let (columns, rows) = (grid.columns as usize, grid.rows as usize);
+        let volume = (0..grid.volume.len())
+            .into_par_iter()
+            .map(|index| {
+                let i = index % columns;
+                let j = index / columns % rows;
+                let k = index / (columns * rows);
+                let point = transform_point(&grid_to_self, [i as f32, j as f32, k as f32]);
+                self.sample(point).unwrap_or(0.)
+            })
+            .collect();
+
+        ImageVolume {
+            columns: grid.columns,
+            rows: grid.rows,
+            slices: grid.slices,
+            pixel_spacing: grid.pixel_spacing,
+            position_patient: grid.position_patient,
+            image_orientation_patient: grid.image_orientation_patient,
+            volume,
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+pub fn transform_point(m: &Mat4, p: Vec3) -> Vec3 {
+    let mut out = [0.; 3];
+    for (row, value) in out.iter_mut().enumerate() {
+        *value = m[0][row] * p[0] + m[1][row] * p[1] + m[2][row] * p[2] + m[3][row];
+    }
+    out
+}
+
+/// Matrix product `a * b`, applying `b` first.
+pub fn mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut out = [[0.; 4]; 4];
+    for (column, out_column) in out.iter_mut().enumerate() {
+        for (row, value) in out_column.iter_mut().enumerate() {
+            *value = (0..4).map(|i| a[i][row] * b[column][i]).sum();
+        }
+    }
+    out
+}
+
+pub fn dot(a: Vec3, b: Vec3) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::grid;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        for axis in 0..3 {
+            assert!((a[axis] - b[axis]).abs() < 1e-4, "{:?} != {:?}", a, b);
+        }
+    }
+
+    fn with_orientation(orientation: [Vec3; 3]) -> ImageVolume {
+        ImageVolume {
+            image_orientation_patient: orientation,
+            ..grid((3, 3, 3), [0.7, 0.9, 2.5], [-100., 50., 20.])
+        }
+    }
+
+    fn assert_round_trip(volume: &ImageVolume) {
+        for index in [
+            [0., 0., 0.],
+            [2., 1., 0.],
+            [0.5, 2.25, 1.75],
+            [-3., 7., 12.],
+        ] {
+            assert_close(
+                volume.patient_to_index(volume.index_to_patient(index)),
+                index,
+            );
+        }
+        let identity = mul(&volume.patient_to_voxel(), &volume.voxel_to_patient());
+        for (column, values) in identity.iter().enumerate() {
+            for (row, &value) in values.iter().enumerate() {
+                let expected = if row == column { 1. } else { 0. };
+                assert!((value - expected).abs() < 1e-5, "{:?}", identity);
+            }
+        }
+    }
+
+    #[test]
+    fn converts_between_voxels_and_patient_on_an_oblique_volume() {
+        // Rotated 30 degrees around the patient axis, then 20 degrees
+        // around the left-right axis
+        let (sin_a, cos_a) = 30f32.to_radians().sin_cos();
+        let (sin_b, cos_b) = 20f32.to_radians().sin_cos();
+        let x = [cos_a, sin_a * cos_b, sin_a * sin_b];
+        let y = [-sin_a, cos_a * cos_b, cos_a * sin_b];
+        let volume = with_orientation([x, y, cross(x, y)]);
+
+        assert_eq!(volume.index_to_patient([0.; 3]), [-100., 50., 20.]);
+        let expected = [
+            -100. + 0.7 * x[0] + 2. * 0.9 * y[0],
+            50. + 0.7 * x[1] + 2. * 0.9 * y[1],
+            20. + 0.7 * x[2] + 2. * 0.9 * y[2],
+        ];
+        assert_close(volume.index_to_patient([1., 2., 0.]), expected);
+        assert_round_trip(&volume);
+    }
+
+    #[test]
+    fn inverts_a_gantry_tilted_volume_exactly() {
+        // Slices stacked 10 degrees off their normal, towards posterior
+        let (sin, cos) = 10f32.to_radians().sin_cos();
+        let volume = with_orientation([[1., 0., 0.], [0., 1., 0.], [0., sin, cos]]);
+
+        assert_close(
+            volume.index_to_patient([0., 0., 2.]),
+            [-100., 50. + 5. * sin, 20. + 5. * cos],
+        );
+        // A point above the first slice origin lies a row back per slice
+        let index = volume.patient_to_index([-100., 50., 20. + 2.5 * cos]);
+        assert_close(index, [0., -2.5 * sin / 0.9, 1.]);
+        assert_round_trip(&volume);
+    }
+
+    #[test]
+    fn names_the_directions_of_standard_planes() {
+        let axial = with_orientation([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]);
+        assert_eq!(axial.orientation_code(), "LPS");
+        let coronal = with_orientation([[1., 0., 0.], [0., 0., -1.], [0., 1., 0.]]);
+        assert_eq!(coronal.orientation_code(), "LIP");
+        let sagittal = with_orientation([[0., 1., 0.], [0., 0., -1.], [-1., 0., 0.]]);
+        assert_eq!(sagittal.orientation_code(), "PIR");
+        assert_eq!(
+            sagittal.axis_directions(),
+            [
+                AnatomicalDirection::Posterior,
+                AnatomicalDirection::Inferior,
+                AnatomicalDirection::Right,
+            ]
+        );
+        // Oblique axes are named after their largest component
+        let oblique = with_orientation([[0.8, -0.6, 0.], [0.6, 0.8, 0.], [0., 0., 1.]]);
+        assert_eq!(oblique.orientation_code(), "LPS");
+    }
+
+    #[test]
+    fn samples_up_to_half_a_voxel_outside() {
+        let mut volume = grid((2, 1, 1), [1., 1., 1.], [0., 0., 0.]);
+        volume.volume = vec![0., 10.];
+
+        assert_eq!(volume.sample([0.5, 0., 0.]), Some(5.));
+        assert_eq!(volume.sample([-0.5, 0., 0.]), Some(0.));
+        assert_eq!(volume.sample([1.5, 0.5, -0.5]), Some(10.));
+        assert_eq!(volume.sample([-0.51, 0., 0.]), None);
+        assert_eq!(volume.sample([1.51, 0., 0.]), None);
+        assert_eq!(volume.sample([0., 0., 0.51]), None);
+    }
+
+    #[test]
+    fn resamples_onto_a_grid_with_other_spacing_and_origin() {
+        // Trilinear interpolation reproduces a linear function exactly
+        let mut source = grid((4, 4, 4), [1., 1., 1.], [0., 0., 0.]);
+        let value = |[x, y, z]: Vec3| x + 10. * y + 100. * z;
+        source.volume = (0..64)
+            .map(|index| {
+                value([
+                    (index % 4) as f32,
+                    (index / 4 % 4) as f32,
+                    (index / 16) as f32,
+                ])
+            })
+            .collect();
+        let target = grid((3, 3, 2), [0.5, 2., 1.5], [1., 0.5, 0.25]);
+
+        let resampled = source.resample_onto(&target);
+        assert_eq!(
+            (resampled.columns, resampled.rows, resampled.slices),
+            (3, 3, 2)
+        );
+        assert_eq!(resampled.pixel_spacing, [0.5, 2., 1.5]);
+        assert_eq!(resampled.position_patient, [1., 0.5, 0.25]);
+        for (index, &sampled) in resampled.volume.iter().enumerate() {
+            let point = target.index_to_patient([
+                (index % 3) as f32,
+                (index / 3 % 3) as f32,
+                (index / 9) as f32,
+            ]);
+            // The last row, at y = 4.5, is outside the source
+            let expected = if point[1] > 3.5 { 0. } else { value(point) };
+            assert!((sampled - expected).abs() < 1e-3, "voxel {}", index);
+        }
+    }
+}
@@ -1,31 +1,185 @@
 use std::f32::consts::PI;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
 
-use anyhow::{Error, Ok};
-use dicom_reader::ImageVolume;
+use anonymizer::{AnonymizeOptions, Anonymizer};
+use anyhow::{anyhow, Error, Ok};
+use dicom_reader::{CancellationToken, LoadOptions, LoadProgress};
+use dicom_series::DicomSeries;
+use dicom_writer::CaptureSeries;
+use dvh::Dvh;
 use graphics::Graphics;
+use image_volume::ImageVolume;
 use pollster::FutureExt;
+use rtdose::RtDose;
+use rtstruct::{LabelVolume, StructureSet};
+use storage_scp::{ScpOptions, StorageScp};
 use winit::application::ApplicationHandler;
 use winit::event::{ElementState, KeyEvent, WindowEvent};
 use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
 use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::window::WindowId;
 
+mod anonymizer;
+mod blosc;
 mod dicom_reader;
+mod dicom_series;
+mod dicom_writer;
+mod dicomweb;
+mod dvh;
 mod graphics;
+mod image_stack;
+mod image_volume;
+mod metaimage;
+mod nifti_reader;
+mod nrrd;
+mod ome_zarr;
+mod raw_reader;
+mod rtdose;
+mod rtstruct;
+mod storage_scp;
+#[cfg(test)]
+mod test_util;
+
+/// Volume opened when no path is given on the command line
+const DEFAULT_DATA_PATH: &str = "data/eclipse-10.0.42-fsrt-brain";
+/// Folder secondary captures of the rendered view are saved to
+const CAPTURE_DIR: &str = "captures";
+/// Folder the shown volume is exported to as a DICOM series
+const EXPORT_DIR: &str = "exported";
+/// Folder dose-volume histograms are exported to
+const DVH_DIR: &str = "dvh";
+/// Dose resolution of the histograms, in the units of the dose
+const DVH_BIN_WIDTH: f32 = 0.01;
+/// Percentages of volume reported as Dx, and doses reported as Vx, in the
+/// units of the dose
+const DVH_VOLUME_PERCENTS: [f32; 1] = [95.];
+const DVH_DOSES: [f32; 1] = [20.];
 
 #[derive(Default)]
 struct App {
+    args: Args,
     graphics: Option<Graphics>,
     uniforms: [f32; 6],
+    captures: Option<CaptureSeries>,
+    /// The volume shown, kept to export it
+    image_volume: Option<ImageVolume>,
+    /// Series pushed to the storage SCP, with what was pushed beside them
+    received: Option<mpsc::Receiver<Study>>,
+    /// Study being opened on a worker thread, until it is shown
+    loading: Option<Loading>,
+    /// Lowest and highest dose of the colour wash, the maximum dose and the
+    /// units of all three
+    dose_thresholds: [f32; 2],
+    max_dose: f32,
+    dose_units: String,
+    /// Histograms of the structures, when both structures and dose are shown
+    dvhs: Vec<Dvh>,
+}
+
+/// Command line arguments.
+#[derive(Debug, Default)]
+struct Args {
+    path: Option<String>,
+    /// Port the storage SCP listens on, with `--listen <port>`
+    listen_port: Option<u16>,
+    /// Folder a de-identified copy of the series is written to instead of
+    /// showing it, with `--anonymize <dir>`
+    anonymize_dir: Option<PathBuf>,
+    /// Days dates are shifted by when de-identifying, with
+    /// `--shift-dates <days>`
+    date_shift_days: Option<i64>,
+}
+
+/// A volume with the structures and dose found beside it, ready to show.
+struct Study {
+    image_volume: ImageVolume,
+    labels: Option<LabelVolume>,
+    /// Dose resampled onto the volume, with its maximum
+    dose: Option<(RtDose, f32)>,
+    dvhs: Vec<Dvh>,
+}
+
+/// A study being opened on a worker thread.
+struct Loading {
+    path: String,
+    progress: mpsc::Receiver<LoadProgress>,
+    result: mpsc::Receiver<Result<Study, Error>>,
+    cancel: CancellationToken,
+}
+
+impl App {
+    fn show_study(&mut self, study: Study) {
+        let graphics = self.graphics.as_mut().unwrap();
+        self.captures = Some(CaptureSeries::new(&study.image_volume.metadata));
+        graphics.set_volume(&study.image_volume);
+        self.image_volume = Some(study.image_volume);
+        if let Some(labels) = study.labels {
+            graphics.set_labels(&labels);
+        }
+        // The dose of the previous study is cleared with its volume
+        self.max_dose = 0.;
+        self.dose_thresholds = [f32::INFINITY; 2];
+        self.dose_units.clear();
+        if let Some((dose, max_dose)) = study.dose {
+            graphics.set_dose(&dose.dose);
+            self.max_dose = max_dose;
+            self.dose_units = dose.units;
+            self.dose_thresholds = [0.1 * max_dose, max_dose];
+            graphics.set_dose_thresholds(self.dose_thresholds[0], self.dose_thresholds[1]);
+        }
+        self.dvhs = study.dvhs;
+    }
 }
 
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         let window = event_loop.create_window(Default::default()).unwrap();
-        let image_volume = load_image_volume("data/eclipse-10.0.42-fsrt-brain").unwrap();
+        let path = self
+            .args
+            .path
+            .clone()
+            .unwrap_or_else(|| DEFAULT_DATA_PATH.to_string());
+        window.set_title(&format!("Loading {}", path));
+        println!("Loading {}, press Escape to cancel", path);
+        let graphics = Graphics::new(window).block_on();
+        self.loading = Some(start_loading(path, graphics.max_volume_dimension()));
+        self.received = self.args.listen_port.map(start_storage_scp);
         self.uniforms = [0.01, 1., 1., 1., 1., 0.];
-        self.graphics = Some(Graphics::new(window, image_volume).block_on());
+        self.graphics = Some(graphics);
+    }
+
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let Some(loading) = &self.loading else {
+            return;
+        };
+        let window = &self.graphics.as_ref().unwrap().window;
+        if let Some(LoadProgress { done, total }) = loading.progress.try_iter().last() {
+            if !loading.cancel.is_cancelled() {
+                window.set_title(&format!("Loading slice {}/{}", done, total));
+            }
+            eprint!("\rLoading slice {}/{}", done, total);
+            if done == total {
+                eprintln!();
+            }
+        }
+
+        match loading.result.try_recv() {
+            // Loaders of single files finish without checking for cancellation
+            Result::Ok(_) if loading.cancel.is_cancelled() => event_loop.exit(),
+            Result::Ok(Result::Ok(study)) => {
+                window.set_title(&loading.path);
+                self.loading = None;
+                self.show_study(study);
+            }
+            Result::Ok(Err(error)) => {
+                eprintln!("Failed to load {}: {:#}", loading.path, error);
+                event_loop.exit();
+            }
+            Err(mpsc::TryRecvError::Empty) => (),
+            Err(mpsc::TryRecvError::Disconnected) => event_loop.exit(),
+        }
     }
 
     fn window_event(
@@ -35,8 +189,8 @@ impl ApplicationHandler for App {
         event: WindowEvent,
     ) {
         match event {
-            WindowEvent::CloseRequested
-            | WindowEvent::KeyboardInput {
+            WindowEvent::CloseRequested => event_loop.exit(),
+            WindowEvent::KeyboardInput {
                 event:
                     KeyEvent {
                         state: ElementState::Pressed,
@@ -44,14 +198,80 @@ impl ApplicationHandler for App {
                         ..
                     },
                 ..
-            } => {
-                event_loop.exit();
-            }
+            } => match &self.loading {
+                // The window closes once the loader has stopped
+                Some(loading) if !loading.cancel.is_cancelled() => {
+                    loading.cancel.cancel();
+                    let graphics = self.graphics.as_ref().unwrap();
+                    graphics.window.set_title("Cancelling");
+                }
+                _ => event_loop.exit(),
+            },
             WindowEvent::RedrawRequested => {
+                // Show the latest series pushed to the storage SCP, once the
+                // study given on the command line has been shown
+                if let Some(study) = self
+                    .received
+                    .as_ref()
+                    .filter(|_| self.loading.is_none())
+                    .and_then(|received| received.try_iter().last())
+                {
+                    self.show_study(study);
+                }
                 let graphics = self.graphics.as_mut().unwrap();
                 graphics.render(self.uniforms.as_slice()).unwrap();
                 graphics.window.request_redraw();
             }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::KeyS),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } if self.captures.is_some() => {
+                let graphics = self.graphics.as_mut().unwrap();
+                let captures = self.captures.as_mut().unwrap();
+                let saved = graphics
+                    .capture(self.uniforms.as_slice())
+                    .and_then(|frame| captures.save(&frame, CAPTURE_DIR));
+                match saved {
+                    Result::Ok(path) => println!("Saved {}", path.display()),
+                    Err(error) => println!("Failed to save capture: {}", error),
+                }
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::KeyE),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(image_volume) = &self.image_volume {
+                    let dir = next_export_dir(EXPORT_DIR);
+                    match dicom_writer::save_dicom_series(image_volume, &dir) {
+                        Result::Ok(files) => {
+                            println!("Saved {} slices to {}", files.len(), dir.display())
+                        }
+                        Err(error) => println!("Failed to export volume: {:#}", error),
+                    }
+                }
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::KeyD),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } if !self.dvhs.is_empty() => match save_dvhs(&self.dvhs, DVH_DIR) {
+                Result::Ok(()) => println!("Saved dose-volume histograms to {}", DVH_DIR),
+                Err(error) => println!("Failed to save dose-volume histograms: {:#}", error),
+            },
             WindowEvent::KeyboardInput {
                 event:
                     KeyEvent {
@@ -67,6 +287,30 @@ impl ApplicationHandler for App {
             } => {
                 handle_user_input(key, &mut self.uniforms);
             }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key:
+                            key @ (PhysicalKey::Code(KeyCode::BracketLeft)
+                            | PhysicalKey::Code(KeyCode::BracketRight)
+                            | PhysicalKey::Code(KeyCode::Minus)
+                            | PhysicalKey::Code(KeyCode::Equal)),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } if self.max_dose > 0. => {
+                handle_dose_input(key, &mut self.dose_thresholds, self.max_dose);
+                let [minimum, maximum] = self.dose_thresholds;
+                println!(
+                    "Dose wash from {:.2} to {:.2} {}",
+                    minimum,
+                    maximum,
+                    rtdose::unit_label(&self.dose_units)
+                );
+                let graphics = self.graphics.as_mut().unwrap();
+                graphics.set_dose_thresholds(minimum, maximum);
+            }
             _ => (),
         }
     }
@@ -87,24 +331,490 @@ fn handle_user_input(key: PhysicalKey, uniforms: &mut [f32; 6]) {
     uniforms[0] = uniforms[0].clamp(0., 1.);
 }
 
-fn load_image_volume(path: &str) -> Result<ImageVolume, Error> {
-    let data_dir = PathBuf::from(path);
-    let files: Vec<PathBuf> = std::fs::read_dir(data_dir)?
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.path())
-        .collect();
+/// Move the lowest dose threshold with `[` and `]`, and the highest with
+/// `-` and `=`, in steps of 5% of the maximum dose.
+fn handle_dose_input(key: PhysicalKey, thresholds: &mut [f32; 2], max_dose: f32) {
+    let delta = 0.05 * max_dose;
+
+    match key {
+        PhysicalKey::Code(KeyCode::BracketLeft) => thresholds[0] -= delta,
+        PhysicalKey::Code(KeyCode::BracketRight) => thresholds[0] += delta,
+        PhysicalKey::Code(KeyCode::Minus) => thresholds[1] -= delta,
+        PhysicalKey::Code(KeyCode::Equal) => thresholds[1] += delta,
+        _ => (),
+    }
+    thresholds[0] = thresholds[0].clamp(0., max_dose);
+    thresholds[1] = thresholds[1].clamp(thresholds[0], max_dose);
+}
+
+/// Parse the optional volume path and `--listen <port>` from the command line.
+fn parse_args() -> Args {
+    let mut parsed = Args::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--listen" {
+            let port = args.next().and_then(|port| port.parse().ok());
+            parsed.listen_port = Some(port.expect("Expected a port number after --listen"));
+        } else if arg == "--anonymize" {
+            let dir = args.next().expect("Expected a folder after --anonymize");
+            parsed.anonymize_dir = Some(PathBuf::from(dir));
+        } else if arg == "--shift-dates" {
+            let days = args.next().and_then(|days| days.parse().ok());
+            parsed.date_shift_days =
+                Some(days.expect("Expected a number of days after --shift-dates"));
+        } else {
+            parsed.path = Some(arg);
+        }
+    }
+    parsed
+}
 
-    dicom_reader::load_dicom_image(&files)
+/// Write a de-identified copy of the series with the most slices at `path`
+/// to `out_dir`.
+fn anonymize_series(path: &str, out_dir: &Path, date_shift_days: Option<i64>) -> Result<(), Error> {
+    let series = dicom_series::find_series(path)?
+        .into_iter()
+        .max_by_key(|series| series.slice_count())
+        .ok_or_else(|| anyhow!("No image series found in {}", path))?;
+    let anonymizer = Anonymizer::new(AnonymizeOptions {
+        date_shift_days,
+        ..Default::default()
+    });
+    let files = anonymizer.anonymize_files(&series.files, out_dir)?;
+    println!(
+        "Wrote {} de-identified files to {}",
+        files.len(),
+        out_dir.display()
+    );
+    Ok(())
+}
+
+/// Open the study at `path` on a worker thread, which reports the progress
+/// of DICOM series and can be cancelled.
+fn start_loading(path: String, max_dimension: u32) -> Loading {
+    let (progress, progress_receiver) = mpsc::channel();
+    let (sender, result) = mpsc::channel();
+    let cancel = CancellationToken::default();
+    let options = LoadOptions {
+        resample: true,
+        correct_gantry_tilt: true,
+        progress: Some(progress),
+        cancel: Some(cancel.clone()),
+    };
+    let study_path = path.clone();
+    thread::spawn(move || {
+        // The viewer may have closed, which is fine
+        let _ = sender.send(load_study(&study_path, &options, max_dimension));
+    });
+    Loading {
+        path,
+        progress: progress_receiver,
+        result,
+        cancel,
+    }
+}
+
+/// Load a volume with the structures and dose found beside it, and the
+/// dose-volume histograms of the structures.
+fn load_study(path: &str, options: &LoadOptions, max_dimension: u32) -> Result<Study, Error> {
+    let image_volume = load_image_volume(path, options, max_dimension)?;
+    Ok(study_beside(path, image_volume))
+}
+
+/// A volume with the structures and dose found under `path` on its frame
+/// of reference, the structures rasterized for it.
+fn study_beside(path: &str, image_volume: ImageVolume) -> Study {
+    let structure_set = load_structures(path, &image_volume);
+    let dose = load_dose(path, &image_volume);
+    let mut dvhs = Vec::new();
+    if let (Some(structure_set), Some((dose, _))) = (&structure_set, &dose) {
+        dvhs = dvh::compute_dvhs(&dose.dose, &dose.units, &structure_set.rois, DVH_BIN_WIDTH);
+        print_dvh_metrics(&dvhs);
+    }
+    let labels = structure_set
+        .map(|structure_set| rtstruct::label_volume(&image_volume, &structure_set.rois));
+    Study {
+        image_volume,
+        labels,
+        dose,
+        dvhs,
+    }
+}
+
+/// Accept series pushed from a modality or PACS on `port`, sending each one
+/// back as a volume once it has been received completely, with the
+/// structures and dose pushed to the same study.
+fn start_storage_scp(port: u16) -> mpsc::Receiver<Study> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = StorageScp::bind(("0.0.0.0", port), ScpOptions::default()).and_then(|scp| {
+            println!("Listening for DICOM associations on {}", scp.local_addr()?);
+            let options = LoadOptions {
+                resample: true,
+                correct_gantry_tilt: true,
+                ..Default::default()
+            };
+            scp.run(|series| match load_series(&series, &options) {
+                Result::Ok(image_volume) => {
+                    // Instances are stored by study, then series
+                    let study_dir = series.files[0].parent().and_then(Path::parent);
+                    let study_dir = study_dir.and_then(Path::to_str).unwrap_or_default();
+                    // The viewer may have closed, which is fine
+                    let _ = sender.send(study_beside(study_dir, image_volume));
+                }
+                Err(error) => eprintln!(
+                    "Failed to load series {}: {}",
+                    series.series_instance_uid, error
+                ),
+            })
+        });
+        if let Err(error) = result {
+            eprintln!("Storage SCP stopped: {:#}", error);
+        }
+    });
+    receiver
+}
+
+/// Open a volume file by its extension, or a DICOM series from a directory.
+/// `options` apply to DICOM series, and multiscale images are read at the
+/// finest level that fits in `max_dimension` voxels.
+fn load_image_volume(
+    path: &str,
+    options: &LoadOptions,
+    max_dimension: u32,
+) -> Result<ImageVolume, Error> {
+    let name = path.to_lowercase();
+    if name.starts_with("http://") || name.starts_with("https://") {
+        return load_dicomweb_series(path, options);
+    }
+    if name.ends_with(".nii") || name.ends_with(".nii.gz") || name.ends_with(".hdr") {
+        return nifti_reader::load_nifti_image(path);
+    }
+    if name.ends_with(".nrrd") || name.ends_with(".nhdr") {
+        return nrrd::load_nrrd_image(path);
+    }
+    if name.ends_with(".mhd") || name.ends_with(".mha") {
+        return metaimage::load_metaimage(path);
+    }
+    if name.trim_end_matches('/').ends_with(".zarr") {
+        return ome_zarr::load_ome_zarr(path, max_dimension);
+    }
+    if name.ends_with(".tif") || name.ends_with(".tiff") {
+        return image_stack::load_image_stack(path, None);
+    }
+    if name.ends_with(".raw") {
+        let description = raw_reader::RawVolumeDescription::from_file_name(path)?;
+        return raw_reader::load_raw_image(path, &description);
+    }
+
+    // Folders of DICOM files, otherwise of TIFF or PNG slices, so previews
+    // exported beside a series are not mistaken for the volume
+    let series = dicom_series::find_series(path)?;
+    if series.is_empty() && !image_stack::find_slice_files(path)?.is_empty() {
+        return image_stack::load_image_stack(path, None);
+    }
+    load_dicom_series(path, series, options)
+}
+
+fn load_dicom_series(
+    path: &str,
+    series: Vec<DicomSeries>,
+    options: &LoadOptions,
+) -> Result<ImageVolume, Error> {
+    // Open the series with the most slices
+    let series = series
+        .into_iter()
+        .max_by_key(|series| series.slice_count())
+        .ok_or_else(|| anyhow!("No image series found in {}", path))?;
+    load_series(&series, options)
+}
+
+fn load_series(series: &DicomSeries, options: &LoadOptions) -> Result<ImageVolume, Error> {
+    let (image_volume, warnings) = series.load(options)?;
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    Ok(image_volume)
+}
+
+/// Retrieve a series from a DICOMweb study or series URL. For a study, the
+/// series with the most instances is opened.
+fn load_dicomweb_series(url: &str, options: &LoadOptions) -> Result<ImageVolume, Error> {
+    let (base_url, study, series) = dicomweb::parse_url(url)
+        .ok_or_else(|| anyhow!("Expected a DICOMweb study or series URL, got {}", url))?;
+    let client = dicomweb::DicomWebClient::new(base_url);
+    let series = match series {
+        Some(series) => series.to_string(),
+        None => {
+            client
+                .search_series(study, &[])?
+                .into_iter()
+                .max_by_key(|series| series.number_of_instances)
+                .ok_or_else(|| anyhow!("No series found in study {}", study))?
+                .series_instance_uid
+        }
+    };
+
+    let (image_volume, warnings) = client.load_series(study, &series, options)?;
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    Ok(image_volume)
+}
+
+/// Load the first RT Structure Set found beside a DICOM series that is drawn
+/// on the same frame of reference, to overlay its ROIs.
+fn load_structures(path: &str, image_volume: &ImageVolume) -> Option<StructureSet> {
+    let frame_of_reference_uid = &image_volume.metadata.frame_of_reference_uid;
+    if frame_of_reference_uid.is_empty() || !Path::new(path).is_dir() {
+        return None;
+    }
+
+    let structure_set = rtstruct::find_structure_sets(path, frame_of_reference_uid)
+        .and_then(|files| files.first().map(rtstruct::load_structure_set).transpose());
+    match structure_set {
+        Result::Ok(Some(structure_set)) => {
+            let names: Vec<&str> = structure_set
+                .rois
+                .iter()
+                .map(|roi| roi.name.as_str())
+                .collect();
+            println!(
+                "Showing structure set {}: {}",
+                structure_set.label,
+                names.join(", ")
+            );
+            Some(structure_set)
+        }
+        Result::Ok(None) => None,
+        Err(error) => {
+            eprintln!("Failed to load structures: {:#}", error);
+            None
+        }
+    }
+}
+
+/// Load the first RT Dose found beside a DICOM series on the same frame of
+/// reference, resampled onto the grid of the series, with its maximum dose.
+fn load_dose(path: &str, image_volume: &ImageVolume) -> Option<(RtDose, f32)> {
+    let frame_of_reference_uid = &image_volume.metadata.frame_of_reference_uid;
+    if frame_of_reference_uid.is_empty() || !Path::new(path).is_dir() {
+        return None;
+    }
+
+    let dose = rtdose::find_rt_doses(path, frame_of_reference_uid)
+        .and_then(|files| files.first().map(rtdose::load_rt_dose).transpose());
+    match dose {
+        Result::Ok(Some((dose, warnings))) => {
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            let max_dose = dose.max_dose();
+            println!(
+                "Showing {} dose up to {:.2} {}, adjust with [ ] and - =",
+                dose.summation_type.to_lowercase(),
+                max_dose,
+                rtdose::unit_label(&dose.units)
+            );
+            let resampled = RtDose {
+                dose: dose.dose.resample_onto(image_volume),
+                ..dose
+            };
+            Some((resampled, max_dose))
+        }
+        Result::Ok(None) => None,
+        Err(error) => {
+            eprintln!("Failed to load dose: {:#}", error);
+            None
+        }
+    }
+}
+
+fn print_dvh_metrics(dvhs: &[Dvh]) {
+    for dvh in dvhs {
+        let unit = dvh.unit_label();
+        let mut metrics = format!(
+            "{}: {:.1} cm3, Dmin {:.2} {unit}, Dmax {:.2} {unit}, Dmean {:.2} {unit}",
+            dvh.name, dvh.volume, dvh.min_dose, dvh.max_dose, dvh.mean_dose
+        );
+        for percent in DVH_VOLUME_PERCENTS {
+            let dose = dvh.dose_at_volume(percent);
+            metrics += &format!(", D{} {:.2} {}", percent, dose, unit);
+        }
+        for dose in DVH_DOSES {
+            metrics += &format!(", V{} {} {:.1}%", dose, unit, dvh.volume_at_dose(dose));
+        }
+        println!("{}", metrics);
+    }
+    println!("Press D to save dose-volume histograms to {}", DVH_DIR);
+}
+
+/// The first of `series1`, `series2` and so on in `dir` that does not exist.
+fn next_export_dir(dir: &str) -> PathBuf {
+    (1..)
+        .map(|n| Path::new(dir).join(format!("series{}", n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Export the histograms and their metrics as CSV.
+fn save_dvhs(dvhs: &[Dvh], dir: &str) -> Result<(), Error> {
+    std::fs::create_dir_all(dir)?;
+    let dir = Path::new(dir);
+    dvh::write_dvh_csv(dir.join("dvh.csv"), dvhs)?;
+    dvh::write_metrics_csv(
+        dir.join("metrics.csv"),
+        dvhs,
+        &DVH_VOLUME_PERCENTS,
+        &DVH_DOSES,
+    )?;
+    Ok(())
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    pollster::block_on(run());
+    let args = parse_args();
+    if let Some(out_dir) = &args.anonymize_dir {
+        let path = args.path.as_deref().unwrap_or(DEFAULT_DATA_PATH);
+        return anonymize_series(path, out_dir, args.date_shift_days);
+    }
+    pollster::block_on(run(args));
     Ok(())
 }
 
-async fn run() {
+async fn run(args: Args) {
     let event_loop = EventLoop::new().expect("Failed to create event loop");
     event_loop.set_control_flow(ControlFlow::Poll);
-    let mut app = App::default();
+    let mut app = App {
+        args,
+        ..Default::default()
+    };
     event_loop.run_app(&mut app).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        temp_dir, write_ct_series, write_png, write_rt_dose, write_structure_set, SliceGeometry,
+        FRAME_OF_REFERENCE_UID,
+    };
+    use rtstruct::Roi;
+
+    #[test]
+    fn rasterizes_structures_found_beside_a_series() {
+        let dir = temp_dir("study-beside");
+        let geometry = SliceGeometry {
+            columns: 4,
+            rows: 4,
+            pixel_spacing: [1., 1.],
+            position: [0., 0., 0.],
+            orientation: [1., 0., 0., 0., 1., 0.],
+        };
+        let files = write_ct_series(&dir.join("CT"), 2, &geometry);
+        let load = || {
+            dicom_reader::load_dicom_image(&files, &Default::default())
+                .unwrap()
+                .0
+        };
+        let path = dir.to_str().unwrap();
+        let study = study_beside(path, load());
+        assert!(study.labels.is_none());
+        assert!(study.dose.is_none());
+
+        let square = vec![
+            [0.5, 0.5, 2.],
+            [2.5, 0.5, 2.],
+            [2.5, 2.5, 2.],
+            [0.5, 2.5, 2.],
+        ];
+        let roi = Roi {
+            number: 1,
+            name: "PTV".to_string(),
+            interpreted_type: "PTV".to_string(),
+            color: [255, 0, 0],
+            contours: vec![square],
+        };
+        write_structure_set(&dir.join("RS.dcm"), FRAME_OF_REFERENCE_UID, &[roi]);
+        let study = study_beside(path, load());
+        let labels = study.labels.unwrap();
+        assert_eq!((labels.columns, labels.rows, labels.slices), (4, 4, 2));
+        let labelled: Vec<usize> = (0..labels.labels.len())
+            .filter(|&index| labels.labels[index] == 1)
+            .collect();
+        assert_eq!(labelled, [21, 22, 25, 26]);
+    }
+
+    #[test]
+    fn resamples_dose_found_beside_a_series() {
+        let dir = temp_dir("dose-beside");
+        let geometry = SliceGeometry {
+            columns: 4,
+            rows: 2,
+            pixel_spacing: [1., 1.],
+            position: [0., 0., 0.],
+            orientation: [1., 0., 0., 0., 1., 0.],
+        };
+        let files = write_ct_series(&dir.join("CT"), 2, &geometry);
+        let (image_volume, _) =
+            dicom_reader::load_dicom_image(&files, &Default::default()).unwrap();
+        // The dose grid covers the image with coarser columns
+        let dose_geometry = SliceGeometry {
+            columns: 2,
+            pixel_spacing: [1., 3.],
+            ..geometry
+        };
+        let stored = [0, 3000, 0, 3000, 1000, 4000, 1000, 4000];
+        write_rt_dose(
+            &dir.join("RD.dcm"),
+            &dose_geometry,
+            &[0., 2.],
+            &stored,
+            0.001,
+        );
+
+        let study = study_beside(dir.to_str().unwrap(), image_volume);
+        let (dose, max_dose) = study.dose.unwrap();
+        assert_eq!(dose.units, "GY");
+        let dose = dose.dose;
+        assert_eq!((dose.columns, dose.rows, dose.slices), (4, 2, 2));
+        assert_eq!(max_dose, 4.);
+        let expected = [
+            0., 1., 2., 3., 0., 1., 2., 3., 1., 2., 3., 4., 1., 2., 3., 4.,
+        ];
+        for (value, expected) in dose.volume.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-4, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn opens_dicom_before_image_slices_in_a_folder() {
+        let dir = temp_dir("dispatch-dicom");
+        let geometry = SliceGeometry {
+            columns: 4,
+            rows: 3,
+            pixel_spacing: [0.5, 0.5],
+            position: [0., 0., 0.],
+            orientation: [1., 0., 0., 0., 1., 0.],
+        };
+        write_ct_series(&dir, 3, &geometry);
+        write_png(&dir.join("preview.png"), 8, 8, &[0; 64]);
+
+        let image = load_image_volume(dir.to_str().unwrap(), &Default::default(), 2048).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (4, 3, 3));
+    }
+
+    #[test]
+    fn opens_image_slices_in_a_folder_without_dicom() {
+        let dir = temp_dir("dispatch-slices");
+        write_png(&dir.join("slice1.png"), 2, 2, &[0; 4]);
+        write_png(&dir.join("slice2.png"), 2, 2, &[1; 4]);
+        std::fs::write(dir.join("notes.txt"), "not DICOM").unwrap();
+
+        let image = load_image_volume(dir.to_str().unwrap(), &Default::default(), 2048).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (2, 2, 2));
+    }
+}
@@ -0,0 +1,302 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::ZlibDecoder;
+
+use crate::image_volume::{ImageVolume, Vec3, VolumeMetadata};
+use crate::raw_reader::ScalarType;
+
+/// Load an ITK MetaImage, either a `.mhd` header with its data file or a
+/// single `.mha` file. MetaImage coordinates are already LPS.
+pub fn load_metaimage<P: AsRef<Path>>(path: P) -> Result<ImageVolume> {
+    let path = path.as_ref();
+    let mut bytes = Vec::new();
+    File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?
+        .read_to_end(&mut bytes)?;
+
+    // Header lines run up to and including ElementDataFile
+    let mut header = MetaImageHeader::default();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let end = bytes[offset..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |i| offset + i + 1);
+        let line = String::from_utf8_lossy(&bytes[offset..end]);
+        offset = end;
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim();
+        header.set(key, value.trim())?;
+        if key == "ElementDataFile" {
+            break;
+        }
+    }
+
+    let data_file = header
+        .data_file
+        .as_deref()
+        .ok_or_else(|| anyhow!("{} has no ElementDataFile", path.display()))?;
+    let data = if data_file == "LOCAL" {
+        bytes.split_off(offset)
+    } else {
+        let data_path = path.parent().unwrap_or(Path::new(".")).join(data_file);
+        let mut data = Vec::new();
+        File::open(&data_path)
+            .with_context(|| format!("Failed to open {}", data_path.display()))?
+            .read_to_end(&mut data)?;
+        data
+    };
+
+    let data = if header.compressed {
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(data.as_slice()).read_to_end(&mut decoded)?;
+        decoded
+    } else {
+        data
+    };
+
+    let [columns, rows, slices] = header.dimensions()?;
+    let count = columns * rows * slices;
+    let scalar_type = header
+        .scalar_type
+        .ok_or_else(|| anyhow!("{} has no ElementType", path.display()))?;
+
+    // A header size of -1 means the data is at the end of the file
+    let data = match header.header_size {
+        -1 => &data[data.len().saturating_sub(count * scalar_type.size())..],
+        skip => data.get(skip.max(0) as usize..).unwrap_or_default(),
+    };
+    let volume = scalar_type.decode(data, header.big_endian, count)?;
+
+    Ok(ImageVolume {
+        columns: u16::try_from(columns)?,
+        rows: u16::try_from(rows)?,
+        slices,
+        pixel_spacing: header.spacing,
+        position_patient: header.offset,
+        image_orientation_patient: header.directions,
+        volume,
+        metadata: VolumeMetadata::default(),
+    })
+}
+
+struct MetaImageHeader {
+    dim_size: Vec<usize>,
+    spacing: Vec3,
+    offset: Vec3,
+    /// Direction of the column, row and slice axes
+    directions: [Vec3; 3],
+    scalar_type: Option<ScalarType>,
+    big_endian: bool,
+    compressed: bool,
+    header_size: i64,
+    data_file: Option<String>,
+}
+
+impl Default for MetaImageHeader {
+    fn default() -> Self {
+        MetaImageHeader {
+            dim_size: Vec::new(),
+            spacing: [1.; 3],
+            offset: [0.; 3],
+            directions: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+            scalar_type: None,
+            big_endian: false,
+            compressed: false,
+            header_size: 0,
+            data_file: None,
+        }
+    }
+}
+
+impl MetaImageHeader {
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "NDims" if value != "3" => {
+                return Err(anyhow!(
+                    "Only 3D MetaImages are supported, NDims = {}",
+                    value
+                ))
+            }
+            "ElementNumberOfChannels" if value != "1" => {
+                return Err(anyhow!("Only scalar MetaImages are supported"))
+            }
+            "DimSize" => {
+                self.dim_size = value
+                    .split_whitespace()
+                    .map(|size| size.parse())
+                    .collect::<Result<_, _>>()?
+            }
+            "ElementSpacing" => self.spacing = parse_floats(value)?,
+            "Offset" | "Position" | "Origin" => self.offset = parse_floats(value)?,
+            "TransformMatrix" | "Rotation" | "Orientation" => {
+                // Each consecutive triple is the direction of one axis
+                let m: [f32; 9] = parse_floats(value)?;
+                self.directions = [[m[0], m[1], m[2]], [m[3], m[4], m[5]], [m[6], m[7], m[8]]];
+            }
+            "ElementType" => self.scalar_type = Some(parse_element_type(value)?),
+            "ElementByteOrderMSB" | "BinaryDataByteOrderMSB" => {
+                self.big_endian = value.eq_ignore_ascii_case("true")
+            }
+            "CompressedData" => self.compressed = value.eq_ignore_ascii_case("true"),
+            "HeaderSize" => self.header_size = value.parse()?,
+            "ElementDataFile" => {
+                if value == "LIST" || value.contains('%') {
+                    return Err(anyhow!("MetaImage slice file lists are not supported"));
+                }
+                self.data_file = Some(value.to_string());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn dimensions(&self) -> Result<[usize; 3]> {
+        self.dim_size
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Expected three DimSize values"))
+    }
+}
+
+fn parse_floats<const N: usize>(value: &str) -> Result<[f32; N]> {
+    let values: Vec<f32> = value
+        .split_whitespace()
+        .map(|v| v.parse())
+        .collect::<Result<_, _>>()?;
+    values
+        .try_into()
+        .map_err(|_| anyhow!("Expected {} values, found {}", N, value))
+}
+
+fn parse_element_type(value: &str) -> Result<ScalarType> {
+    Ok(match value {
+        "MET_CHAR" => ScalarType::I8,
+        "MET_UCHAR" => ScalarType::U8,
+        "MET_SHORT" => ScalarType::I16,
+        "MET_USHORT" => ScalarType::U16,
+        "MET_INT" | "MET_LONG" => ScalarType::I32,
+        "MET_UINT" | "MET_ULONG" => ScalarType::U32,
+        "MET_LONG_LONG" => ScalarType::I64,
+        "MET_ULONG_LONG" => ScalarType::U64,
+        "MET_FLOAT" => ScalarType::F32,
+        "MET_DOUBLE" => ScalarType::F64,
+        _ => return Err(anyhow!("Unsupported MetaImage element type {}", value)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn reads_header_with_detached_data() {
+        let dir = temp_dir("metaimage-mhd");
+        let header = "ObjectType = Image\r\n\
+                      NDims = 3\r\n\
+                      BinaryData = True\r\n\
+                      BinaryDataByteOrderMSB = True\r\n\
+                      TransformMatrix = 0 1 0 -1 0 0 0 0 1\r\n\
+                      Offset = -12.5 4 100\r\n\
+                      ElementSpacing = 0.5 0.25 3\r\n\
+                      DimSize = 2 1 2\r\n\
+                      ElementType = MET_SHORT\r\n\
+                      HeaderSize = 4\r\n\
+                      ElementDataFile = image.raw\r\n";
+        std::fs::write(dir.join("image.mhd"), header).unwrap();
+        let mut data = vec![0xff; 4];
+        data.extend([-1024i16, 0, 7, 2000].iter().flat_map(|v| v.to_be_bytes()));
+        std::fs::write(dir.join("image.raw"), data).unwrap();
+
+        let image = load_metaimage(dir.join("image.mhd")).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (2, 1, 2));
+        assert_eq!(image.pixel_spacing, [0.5, 0.25, 3.]);
+        assert_eq!(image.position_patient, [-12.5, 4., 100.]);
+        assert_eq!(
+            image.image_orientation_patient,
+            [[0., 1., 0.], [-1., 0., 0.], [0., 0., 1.]]
+        );
+        assert_eq!(image.volume, [-1024., 0., 7., 2000.]);
+    }
+
+    #[test]
+    fn reads_local_compressed_data() {
+        let mut file = b"NDims = 3\n\
+                         DimSize = 3 1 1\n\
+                         ElementType = MET_FLOAT\n\
+                         CompressedData = True\n\
+                         ElementDataFile = LOCAL\n"
+            .to_vec();
+        let data: Vec<u8> = [0.5f32, -1., 1e6]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&data).unwrap();
+        file.extend(encoder.finish().unwrap());
+        let path = temp_dir("metaimage-mha").join("image.mha");
+        std::fs::write(&path, file).unwrap();
+
+        let image = load_metaimage(&path).unwrap();
+
+        assert_eq!(image.pixel_spacing, [1., 1., 1.]);
+        assert_eq!(image.position_patient, [0., 0., 0.]);
+        assert_eq!(image.volume, [0.5, -1., 1e6]);
+    }
+
+    #[test]
+    fn reads_data_at_the_end_of_the_file() {
+        let dir = temp_dir("metaimage-end");
+        let header = "NDims = 3\n\
+                      DimSize = 2 1 1\n\
+                      ElementType = MET_UCHAR\n\
+                      HeaderSize = -1\n\
+                      ElementDataFile = image.dat\n";
+        std::fs::write(dir.join("image.mhd"), header).unwrap();
+        std::fs::write(dir.join("image.dat"), b"unknown header\x10\x20").unwrap();
+
+        let image = load_metaimage(dir.join("image.mhd")).unwrap();
+
+        assert_eq!(image.volume, [16., 32.]);
+    }
+
+    #[test]
+    fn rejects_unsupported_headers() {
+        let dir = temp_dir("metaimage-invalid");
+        let cases = [
+            ("NDims = 2\nDimSize = 2 2\n", "NDims = 2"),
+            ("NDims = 3\nElementNumberOfChannels = 3\n", "Only scalar"),
+            ("NDims = 3\nElementDataFile = LIST\n", "slice file lists"),
+            (
+                "NDims = 3\nElementType = MET_UCHAR_ARRAY\n",
+                "MET_UCHAR_ARRAY",
+            ),
+            (
+                "NDims = 3\nDimSize = 2 2 2\nElementType = MET_UCHAR\n",
+                "no ElementDataFile",
+            ),
+            (
+                "NDims = 3\nDimSize = 2 2 2\nElementType = MET_UCHAR\nElementDataFile = LOCAL\n",
+                "too short",
+            ),
+        ];
+        for (n, (contents, message)) in cases.into_iter().enumerate() {
+            let path = dir.join(format!("{}.mha", n));
+            std::fs::write(&path, contents).unwrap();
+            let error = load_metaimage(&path).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+}
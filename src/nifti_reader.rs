@@ -0,0 +1,527 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+
+use crate::image_volume::{ImageVolume, Vec3, VolumeMetadata};
+use crate::raw_reader::ScalarType;
+
+const NIFTI1_HEADER_SIZE: i32 = 348;
+const NIFTI2_HEADER_SIZE: i32 = 540;
+
+/// Load a NIfTI-1 or NIfTI-2 image, either a single `.nii` file or a
+/// `.hdr`/`.img` pair, optionally gzip compressed.
+///
+/// The RAS+ world coordinates of NIfTI are converted to the LPS patient
+/// coordinates used for DICOM. Only the first volume of 4D images is read.
+pub fn load_nifti_image<P: AsRef<Path>>(path: P) -> Result<ImageVolume> {
+    let path = path.as_ref();
+    let bytes = read_maybe_gzip(path)?;
+    let header = NiftiHeader::parse(&bytes)?;
+
+    let data = if header.single_file {
+        bytes
+            .get(header.vox_offset..)
+            .ok_or_else(|| anyhow!("vox_offset {} past end of file", header.vox_offset))?
+            .to_vec()
+    } else {
+        read_maybe_gzip(&image_file_path(path))?
+    };
+
+    let [columns, rows, slices] = [header.dim[1], header.dim[2], header.dim[3]].map(|d| d.max(1));
+    let count = columns * rows * slices;
+    let mut volume = scalar_type(header.datatype)?.decode(&data, header.big_endian, count)?;
+
+    if header.scl_slope != 0. && header.scl_slope.is_finite() {
+        for value in &mut volume {
+            *value = *value * header.scl_slope + header.scl_inter;
+        }
+    }
+
+    // Flip x and y to go from RAS to LPS
+    let affine = header.affine();
+    let lps = |row: usize, column: usize| {
+        if row < 2 {
+            -affine[row][column]
+        } else {
+            affine[row][column]
+        }
+    };
+
+    let mut pixel_spacing = [0.; 3];
+    let mut image_orientation_patient = [[0.; 3]; 3];
+    for axis in 0..3 {
+        let direction = [lps(0, axis), lps(1, axis), lps(2, axis)];
+        let length = direction.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if length > 0. {
+            pixel_spacing[axis] = length;
+            image_orientation_patient[axis] = direction.map(|v| v / length);
+        } else {
+            // Without a transform and with a zero pixdim, assume unit spacing
+            // along the matching RAS+ axis
+            pixel_spacing[axis] = 1.;
+            image_orientation_patient[axis][axis] = if axis < 2 { -1. } else { 1. };
+        }
+    }
+
+    Ok(ImageVolume {
+        columns: u16::try_from(columns)?,
+        rows: u16::try_from(rows)?,
+        slices,
+        pixel_spacing,
+        position_patient: [lps(0, 3), lps(1, 3), lps(2, 3)],
+        image_orientation_patient,
+        volume,
+        metadata: VolumeMetadata {
+            series_description: header.descrip,
+            ..Default::default()
+        },
+    })
+}
+
+/// The fields of a NIfTI-1 or NIfTI-2 header needed to build a volume.
+struct NiftiHeader {
+    big_endian: bool,
+    single_file: bool,
+    dim: [usize; 8],
+    datatype: i16,
+    pixdim: [f32; 8],
+    vox_offset: usize,
+    scl_slope: f32,
+    scl_inter: f32,
+    xyzt_units: u8,
+    qform_code: i32,
+    sform_code: i32,
+    quatern: Vec3,
+    qoffset: Vec3,
+    srow: [[f32; 4]; 3],
+    descrip: String,
+}
+
+impl NiftiHeader {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < NIFTI1_HEADER_SIZE as usize {
+            return Err(anyhow!("File too short for a NIfTI header"));
+        }
+
+        let size = i32::from_le_bytes(bytes[0..4].try_into()?);
+        let (big_endian, size) = if size == NIFTI1_HEADER_SIZE || size == NIFTI2_HEADER_SIZE {
+            (false, size)
+        } else {
+            (true, i32::from_be_bytes(bytes[0..4].try_into()?))
+        };
+
+        let reader = HeaderReader { bytes, big_endian };
+        match size {
+            NIFTI1_HEADER_SIZE => Self::parse_nifti1(reader),
+            NIFTI2_HEADER_SIZE if bytes.len() >= NIFTI2_HEADER_SIZE as usize => {
+                Self::parse_nifti2(reader)
+            }
+            _ => Err(anyhow!("Not a NIfTI header")),
+        }
+    }
+
+    fn parse_nifti1(r: HeaderReader) -> Result<Self> {
+        let magic = &r.bytes[344..348];
+        if magic != b"n+1\0" && magic != b"ni1\0" {
+            return Err(anyhow!("Invalid NIfTI-1 magic"));
+        }
+
+        Ok(NiftiHeader {
+            big_endian: r.big_endian,
+            single_file: magic == b"n+1\0",
+            dim: std::array::from_fn(|i| r.i16(40 + 2 * i).max(0) as usize),
+            datatype: r.i16(70),
+            pixdim: std::array::from_fn(|i| r.f32(76 + 4 * i)),
+            vox_offset: r.f32(108) as usize,
+            scl_slope: r.f32(112),
+            scl_inter: r.f32(116),
+            xyzt_units: r.bytes[123],
+            qform_code: r.i16(252) as i32,
+            sform_code: r.i16(254) as i32,
+            quatern: [r.f32(256), r.f32(260), r.f32(264)],
+            qoffset: [r.f32(268), r.f32(272), r.f32(276)],
+            srow: std::array::from_fn(|row| std::array::from_fn(|i| r.f32(280 + 16 * row + 4 * i))),
+            descrip: r.string(148, 80),
+        })
+    }
+
+    fn parse_nifti2(r: HeaderReader) -> Result<Self> {
+        let magic = &r.bytes[4..8];
+        if magic != b"n+2\0" && magic != b"ni2\0" {
+            return Err(anyhow!("Invalid NIfTI-2 magic"));
+        }
+
+        Ok(NiftiHeader {
+            big_endian: r.big_endian,
+            single_file: magic == b"n+2\0",
+            dim: std::array::from_fn(|i| r.i64(16 + 8 * i).max(0) as usize),
+            datatype: r.i16(12),
+            pixdim: std::array::from_fn(|i| r.f64(104 + 8 * i) as f32),
+            vox_offset: r.i64(168).max(0) as usize,
+            scl_slope: r.f64(176) as f32,
+            scl_inter: r.f64(184) as f32,
+            xyzt_units: r.i32(500) as u8,
+            qform_code: r.i32(344),
+            sform_code: r.i32(348),
+            quatern: [r.f64(352) as f32, r.f64(360) as f32, r.f64(368) as f32],
+            qoffset: [r.f64(376) as f32, r.f64(384) as f32, r.f64(392) as f32],
+            srow: std::array::from_fn(|row| {
+                std::array::from_fn(|i| r.f64(400 + 32 * row + 8 * i) as f32)
+            }),
+            descrip: r.string(240, 80),
+        })
+    }
+
+    /// Voxel to RAS+ millimetre matrix as three rows of a 3x4 affine,
+    /// following the sform, then the qform, then plain pixdim scaling.
+    fn affine(&self) -> [[f32; 4]; 3] {
+        // Spatial units are in the lowest three bits
+        let scale = match self.xyzt_units & 0x07 {
+            1 => 1000.,
+            3 => 0.001,
+            _ => 1.,
+        };
+
+        let affine = if self.sform_code > 0 {
+            self.srow
+        } else if self.qform_code > 0 {
+            let [b, c, d] = self.quatern;
+            let a = (1. - (b * b + c * c + d * d)).max(0.).sqrt();
+            let rotation = [
+                [
+                    a * a + b * b - c * c - d * d,
+                    2. * (b * c - a * d),
+                    2. * (b * d + a * c),
+                ],
+                [
+                    2. * (b * c + a * d),
+                    a * a + c * c - b * b - d * d,
+                    2. * (c * d - a * b),
+                ],
+                [
+                    2. * (b * d - a * c),
+                    2. * (c * d + a * b),
+                    a * a + d * d - c * c - b * b,
+                ],
+            ];
+            let qfac = if self.pixdim[0] < 0. { -1. } else { 1. };
+            let spacing = [self.pixdim[1], self.pixdim[2], self.pixdim[3] * qfac];
+            std::array::from_fn(|row| {
+                [
+                    rotation[row][0] * spacing[0],
+                    rotation[row][1] * spacing[1],
+                    rotation[row][2] * spacing[2],
+                    self.qoffset[row],
+                ]
+            })
+        } else {
+            [
+                [self.pixdim[1], 0., 0., 0.],
+                [0., self.pixdim[2], 0., 0.],
+                [0., 0., self.pixdim[3], 0.],
+            ]
+        };
+
+        affine.map(|row| row.map(|v| v * scale))
+    }
+}
+
+/// Reads fixed offset header fields in the byte order of the file.
+struct HeaderReader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl HeaderReader<'_> {
+    fn array<const N: usize>(&self, offset: usize) -> [u8; N] {
+        let mut array: [u8; N] = self.bytes[offset..offset + N].try_into().unwrap();
+        if self.big_endian {
+            array.reverse();
+        }
+        array
+    }
+
+    fn i16(&self, offset: usize) -> i16 {
+        i16::from_le_bytes(self.array(offset))
+    }
+
+    fn i32(&self, offset: usize) -> i32 {
+        i32::from_le_bytes(self.array(offset))
+    }
+
+    fn i64(&self, offset: usize) -> i64 {
+        i64::from_le_bytes(self.array(offset))
+    }
+
+    fn f32(&self, offset: usize) -> f32 {
+        f32::from_le_bytes(self.array(offset))
+    }
+
+    fn f64(&self, offset: usize) -> f64 {
+        f64::from_le_bytes(self.array(offset))
+    }
+
+    fn string(&self, offset: usize, len: usize) -> String {
+        let field = &self.bytes[offset..offset + len];
+        let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+        String::from_utf8_lossy(&field[..end]).trim().to_string()
+    }
+}
+
+/// Voxel type of a NIfTI datatype code.
+fn scalar_type(datatype: i16) -> Result<ScalarType> {
+    Ok(match datatype {
+        2 => ScalarType::U8,
+        4 => ScalarType::I16,
+        8 => ScalarType::I32,
+        16 => ScalarType::F32,
+        64 => ScalarType::F64,
+        256 => ScalarType::I8,
+        512 => ScalarType::U16,
+        768 => ScalarType::U32,
+        1024 => ScalarType::I64,
+        1280 => ScalarType::U64,
+        _ => return Err(anyhow!("Unsupported NIfTI datatype {}", datatype)),
+    })
+}
+
+/// Read a whole file, decompressing it if it starts with the gzip magic.
+fn read_maybe_gzip(path: &Path) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    File::open(path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?
+        .read_to_end(&mut bytes)?;
+
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = Vec::new();
+        GzDecoder::new(bytes.as_slice()).read_to_end(&mut decoded)?;
+        return Ok(decoded);
+    }
+
+    Ok(bytes)
+}
+
+/// The `.img` file paired with a `.hdr` header.
+fn image_file_path(header_path: &Path) -> PathBuf {
+    let name = header_path.to_string_lossy();
+    if let Some(stem) = name.strip_suffix(".hdr.gz") {
+        PathBuf::from(format!("{}.img.gz", stem))
+    } else {
+        header_path.with_extension("img")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    /// Header fields written at fixed offsets in the byte order of the file.
+    struct HeaderWriter {
+        bytes: Vec<u8>,
+        big_endian: bool,
+    }
+
+    impl HeaderWriter {
+        /// A single file NIfTI-1 header with unit spacing and no transform,
+        /// followed by the voxels.
+        fn nifti1(big_endian: bool, dims: [i16; 3], datatype: i16) -> Self {
+            let mut header = HeaderWriter {
+                bytes: vec![0; 352],
+                big_endian,
+            };
+            header.put(0, &348i32.to_le_bytes());
+            for (i, dim) in [3, dims[0], dims[1], dims[2]].into_iter().enumerate() {
+                header.put(40 + 2 * i, &dim.to_le_bytes());
+            }
+            header.put(70, &datatype.to_le_bytes());
+            for i in 0..4 {
+                header.put(76 + 4 * i, &1f32.to_le_bytes());
+            }
+            header.put(108, &352f32.to_le_bytes());
+            header.bytes[344..348].copy_from_slice(b"n+1\0");
+            header
+        }
+
+        fn put(&mut self, offset: usize, little_endian: &[u8]) {
+            let mut value = little_endian.to_vec();
+            if self.big_endian {
+                value.reverse();
+            }
+            self.bytes[offset..offset + value.len()].copy_from_slice(&value);
+        }
+
+        fn put_f32s(&mut self, offset: usize, values: &[f32]) {
+            for (i, value) in values.iter().enumerate() {
+                self.put(offset + 4 * i, &value.to_le_bytes());
+            }
+        }
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn converts_sform_to_lps_and_rescales() {
+        let mut header = HeaderWriter::nifti1(false, [3, 2, 2], 4);
+        header.put(112, &2f32.to_le_bytes());
+        header.put(116, &(-1f32).to_le_bytes());
+        header.bytes[148..157].copy_from_slice(b"synthetic");
+        // The sform takes precedence over the qform
+        header.put(252, &1i16.to_le_bytes());
+        header.put(254, &1i16.to_le_bytes());
+        header.put_f32s(268, &[100., 100., 100.]);
+        header.put_f32s(280, &[-2., 0., 0., 10., 0., 3., 0., -20., 0., 0., 4., 30.]);
+        for value in -6i16..6 {
+            header.bytes.extend(value.to_le_bytes());
+        }
+        let path = temp_dir("nifti-sform").join("image.nii");
+        std::fs::write(&path, &header.bytes).unwrap();
+
+        let image = load_nifti_image(&path).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (3, 2, 2));
+        assert_eq!(image.pixel_spacing, [2., 3., 4.]);
+        assert_eq!(
+            image.image_orientation_patient,
+            [[1., 0., 0.], [0., -1., 0.], [0., 0., 1.]]
+        );
+        assert_eq!(image.position_patient, [-10., 20., 30.]);
+        let expected: Vec<f32> = (-6..6).map(|value| value as f32 * 2. - 1.).collect();
+        assert_eq!(image.volume, expected);
+        assert_eq!(image.metadata.series_description, "synthetic");
+    }
+
+    #[test]
+    fn reads_big_endian_gzip_with_qform() {
+        let mut header = HeaderWriter::nifti1(true, [2, 2, 1], 512);
+        // 90 degrees about z, with a negative qfac flipping the slice axis
+        header.put_f32s(76, &[-1., 0.5, 0.25, 2.]);
+        header.put(252, &1i16.to_le_bytes());
+        header.put_f32s(256, &[0., 0., std::f32::consts::FRAC_1_SQRT_2]);
+        header.put_f32s(268, &[1., 2., 3.]);
+        for value in [1u16, 2, 300, 65535] {
+            header.bytes.extend(value.to_be_bytes());
+        }
+        let path = temp_dir("nifti-qform").join("image.nii.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&header.bytes).unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let image = load_nifti_image(&path).unwrap();
+
+        assert_close(image.pixel_spacing, [0.5, 0.25, 2.]);
+        let [columns, rows, slices] = image.image_orientation_patient;
+        assert_close(columns, [0., -1., 0.]);
+        assert_close(rows, [1., 0., 0.]);
+        assert_close(slices, [0., 0., -1.]);
+        assert_eq!(image.position_patient, [-1., -2., 3.]);
+        assert_eq!(image.volume, [1., 2., 300., 65535.]);
+    }
+
+    #[test]
+    fn reads_header_and_image_pair_in_microns() {
+        let mut header = HeaderWriter::nifti1(false, [2, 1, 2], 2);
+        header.bytes.truncate(348);
+        header.bytes[344..348].copy_from_slice(b"ni1\0");
+        header.put_f32s(76, &[1., 1000., 2000., 3000.]);
+        header.bytes[123] = 3;
+        let dir = temp_dir("nifti-pair");
+        std::fs::write(dir.join("image.hdr"), &header.bytes).unwrap();
+        std::fs::write(dir.join("image.img"), [0u8, 1, 254, 255]).unwrap();
+
+        let image = load_nifti_image(dir.join("image.hdr")).unwrap();
+
+        assert_close(image.pixel_spacing, [1., 2., 3.]);
+        assert_eq!(
+            image.image_orientation_patient,
+            [[-1., 0., 0.], [0., -1., 0.], [0., 0., 1.]]
+        );
+        assert_eq!(image.volume, [0., 1., 254., 255.]);
+    }
+
+    #[test]
+    fn assumes_unit_spacing_when_pixdim_is_zero() {
+        let mut header = HeaderWriter::nifti1(false, [2, 1, 2], 2);
+        header.put_f32s(76, &[1., 0.5, 0., 0.]);
+        header.bytes.extend([0u8, 1, 2, 3]);
+        let path = temp_dir("nifti-zero-pixdim").join("image.nii");
+        std::fs::write(&path, &header.bytes).unwrap();
+
+        let image = load_nifti_image(&path).unwrap();
+
+        assert_eq!(image.pixel_spacing, [0.5, 1., 1.]);
+        assert_eq!(
+            image.image_orientation_patient,
+            [[-1., 0., 0.], [0., -1., 0.], [0., 0., 1.]]
+        );
+        assert!(image
+            .index_to_patient([1., 0., 1.])
+            .iter()
+            .all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn reads_nifti2() {
+        let mut header = HeaderWriter {
+            bytes: vec![0; 544],
+            big_endian: false,
+        };
+        header.put(0, &540i32.to_le_bytes());
+        header.bytes[4..8].copy_from_slice(b"n+2\0");
+        header.put(12, &64i16.to_le_bytes());
+        for (i, dim) in [3i64, 2, 1, 1].into_iter().enumerate() {
+            header.put(16 + 8 * i, &dim.to_le_bytes());
+        }
+        header.put(168, &544i64.to_le_bytes());
+        header.put(348, &1i32.to_le_bytes());
+        let srow = [1.5f64, 0., 0., -5., 0., 1., 0., 0., 0., 0., 1., 7.];
+        for (i, value) in srow.into_iter().enumerate() {
+            header.put(400 + 8 * i, &value.to_le_bytes());
+        }
+        for value in [0.25f64, -8.5] {
+            header.bytes.extend(value.to_le_bytes());
+        }
+        let path = temp_dir("nifti2").join("image.nii");
+        std::fs::write(&path, &header.bytes).unwrap();
+
+        let image = load_nifti_image(&path).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (2, 1, 1));
+        assert_eq!(image.pixel_spacing, [1.5, 1., 1.]);
+        assert_eq!(image.position_patient, [5., 0., 7.]);
+        assert_eq!(image.volume, [0.25, -8.5]);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let dir = temp_dir("nifti-invalid");
+
+        let mut header = HeaderWriter::nifti1(false, [2, 2, 1], 4);
+        header.bytes.extend([0; 6]);
+        std::fs::write(dir.join("short.nii"), &header.bytes).unwrap();
+        let error = load_nifti_image(dir.join("short.nii")).unwrap_err();
+        assert!(error.to_string().contains("too short"), "{}", error);
+
+        let mut header = HeaderWriter::nifti1(false, [1, 1, 1], 128);
+        header.bytes.extend([0; 3]);
+        std::fs::write(dir.join("rgb.nii"), &header.bytes).unwrap();
+        let error = load_nifti_image(dir.join("rgb.nii")).unwrap_err();
+        assert!(error.to_string().contains("datatype 128"), "{}", error);
+
+        let mut header = HeaderWriter::nifti1(false, [1, 1, 1], 2);
+        header.bytes[344..348].copy_from_slice(b"xyz\0");
+        std::fs::write(dir.join("magic.nii"), &header.bytes).unwrap();
+        assert!(load_nifti_image(dir.join("magic.nii")).is_err());
+    }
+}
@@ -0,0 +1,520 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::image_volume::{ImageVolume, Vec3, VolumeMetadata};
+use crate::raw_reader::ScalarType;
+
+/// How the voxel data of a NRRD file is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum NrrdEncoding {
+    Raw,
+    Gzip,
+}
+
+/// Load a `.nrrd` file, or a `.nhdr` header with its detached data file.
+///
+/// Volumes in a right-anterior-superior space are converted to LPS.
+pub fn load_nrrd_image<P: AsRef<Path>>(path: P) -> Result<ImageVolume> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("NRRD000") {
+        return Err(anyhow!("{} is not a NRRD file", path.display()));
+    }
+
+    // Header fields end at the first empty line
+    let mut header = NrrdHeader::default();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with('#') || line.contains(":=") {
+            continue;
+        }
+        let (field, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid NRRD header line: {}", line))?;
+        header.set(field.trim(), value.trim())?;
+    }
+
+    let sizes = header.spatial_sizes()?;
+    let count = sizes.iter().product::<usize>();
+
+    // Data follows the header, or lives in a detached file next to it
+    let mut data: Box<dyn Read> = match &header.data_file {
+        Some(data_file) => {
+            let data_path = path.parent().unwrap_or(Path::new(".")).join(data_file);
+            Box::new(BufReader::new(File::open(&data_path).with_context(
+                || format!("Failed to open {}", data_path.display()),
+            )?))
+        }
+        None => Box::new(reader),
+    };
+
+    for _ in 0..header.line_skip {
+        let mut byte = [0u8];
+        while data.read(&mut byte)? == 1 && byte[0] != b'\n' {}
+    }
+
+    if header.encoding == NrrdEncoding::Gzip {
+        data = Box::new(GzDecoder::new(data));
+    }
+
+    let mut bytes = Vec::new();
+    data.read_to_end(&mut bytes)?;
+    let bytes = match header.byte_skip {
+        // A byte skip of -1 means the data is at the end of the file
+        -1 => {
+            let len = count * header.scalar_type.size();
+            &bytes[bytes.len().saturating_sub(len)..]
+        }
+        skip => bytes.get(skip.max(0) as usize..).unwrap_or_default(),
+    };
+
+    let volume = header.scalar_type.decode(bytes, header.big_endian, count)?;
+
+    // Directions default to the axis aligned spacings
+    let directions = match header.space_directions {
+        Some(directions) => directions,
+        None => {
+            let spacings = header.spacings.unwrap_or([1.; 3]);
+            [
+                [spacings[0], 0., 0.],
+                [0., spacings[1], 0.],
+                [0., 0., spacings[2]],
+            ]
+        }
+    };
+    let flip = header.space_flip;
+    let to_lps = |v: Vec3| [v[0] * flip[0], v[1] * flip[1], v[2] * flip[2]];
+
+    let mut pixel_spacing = [0.; 3];
+    let mut image_orientation_patient = [[0.; 3]; 3];
+    for axis in 0..3 {
+        let direction = to_lps(directions[axis]);
+        let length = direction.iter().map(|v| v * v).sum::<f32>().sqrt();
+        pixel_spacing[axis] = length;
+        image_orientation_patient[axis] = direction.map(|v| v / length);
+    }
+
+    Ok(ImageVolume {
+        columns: u16::try_from(sizes[0])?,
+        rows: u16::try_from(sizes[1])?,
+        slices: sizes[2],
+        pixel_spacing,
+        position_patient: to_lps(header.space_origin.unwrap_or_default()),
+        image_orientation_patient,
+        volume,
+        metadata: VolumeMetadata {
+            series_description: header.content,
+            ..Default::default()
+        },
+    })
+}
+
+/// Write a volume as float NRRD in LPS space. A `.nhdr` path writes the
+/// header alone and the data to a detached `.raw` or `.raw.gz` file beside it.
+#[allow(dead_code)]
+pub fn save_nrrd_image<P: AsRef<Path>>(
+    image: &ImageVolume,
+    path: P,
+    encoding: NrrdEncoding,
+) -> Result<()> {
+    let path = path.as_ref();
+    let detached = path.extension().is_some_and(|ext| ext == "nhdr");
+
+    let vector = |v: Vec3| format!("({},{},{})", v[0], v[1], v[2]);
+    let directions: Vec<String> = (0..3)
+        .map(|axis| {
+            vector(image.image_orientation_patient[axis].map(|v| v * image.pixel_spacing[axis]))
+        })
+        .collect();
+
+    let mut header = format!(
+        "NRRD0004\n\
+         # Complete NRRD file format specification at:\n\
+         # http://teem.sourceforge.net/nrrd/format.html\n\
+         type: float\n\
+         dimension: 3\n\
+         space: left-posterior-superior\n\
+         sizes: {} {} {}\n\
+         space directions: {}\n\
+         kinds: domain domain domain\n\
+         endian: little\n\
+         encoding: {}\n\
+         space origin: {}\n",
+        image.columns,
+        image.rows,
+        image.slices,
+        directions.join(" "),
+        match encoding {
+            NrrdEncoding::Raw => "raw",
+            NrrdEncoding::Gzip => "gzip",
+        },
+        vector(image.position_patient),
+    );
+
+    if !image.metadata.series_description.is_empty() {
+        header += &format!("content: {}\n", image.metadata.series_description);
+    }
+
+    let data_path = path.with_extension(match encoding {
+        NrrdEncoding::Raw => "raw",
+        NrrdEncoding::Gzip => "raw.gz",
+    });
+    if detached {
+        let data_file = data_path
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid path {}", path.display()))?;
+        header += &format!("data file: {}\n", data_file.to_string_lossy());
+    }
+    header += "\n";
+
+    let mut file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(header.as_bytes())?;
+    if detached {
+        file = File::create(&data_path)
+            .with_context(|| format!("Failed to create {}", data_path.display()))?;
+    }
+
+    let bytes: Vec<u8> = image.volume.iter().flat_map(|v| v.to_le_bytes()).collect();
+    match encoding {
+        NrrdEncoding::Raw => file.write_all(&bytes)?,
+        NrrdEncoding::Gzip => {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            encoder.write_all(&bytes)?;
+            encoder.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+struct NrrdHeader {
+    scalar_type: ScalarType,
+    sizes: Vec<usize>,
+    encoding: NrrdEncoding,
+    big_endian: bool,
+    /// Sign applied to each axis to convert the header space to LPS
+    space_flip: Vec3,
+    space_directions: Option<[Vec3; 3]>,
+    space_origin: Option<Vec3>,
+    spacings: Option<Vec3>,
+    data_file: Option<String>,
+    line_skip: usize,
+    byte_skip: i64,
+    content: String,
+}
+
+impl Default for NrrdHeader {
+    fn default() -> Self {
+        NrrdHeader {
+            scalar_type: ScalarType::F32,
+            sizes: Vec::new(),
+            encoding: NrrdEncoding::Raw,
+            big_endian: false,
+            space_flip: [1., 1., 1.],
+            space_directions: None,
+            space_origin: None,
+            spacings: None,
+            data_file: None,
+            line_skip: 0,
+            byte_skip: 0,
+            content: String::new(),
+        }
+    }
+}
+
+impl NrrdHeader {
+    fn set(&mut self, field: &str, value: &str) -> Result<()> {
+        match field {
+            "type" => self.scalar_type = parse_scalar_type(value)?,
+            "sizes" => {
+                self.sizes = value
+                    .split_whitespace()
+                    .map(|size| size.parse())
+                    .collect::<Result<_, _>>()?
+            }
+            "encoding" => {
+                self.encoding = match value {
+                    "raw" => NrrdEncoding::Raw,
+                    "gzip" | "gz" => NrrdEncoding::Gzip,
+                    _ => return Err(anyhow!("Unsupported NRRD encoding {}", value)),
+                }
+            }
+            "endian" => self.big_endian = value == "big",
+            "space" => {
+                self.space_flip = match value {
+                    "left-posterior-superior" | "LPS" => [1., 1., 1.],
+                    "right-anterior-superior" | "RAS" => [-1., -1., 1.],
+                    "left-anterior-superior" | "LAS" => [1., -1., 1.],
+                    _ => return Err(anyhow!("Unsupported NRRD space {}", value)),
+                }
+            }
+            "space directions" => {
+                // Non spatial axes are marked with none
+                let directions: Vec<Vec3> = value
+                    .split_whitespace()
+                    .filter(|direction| *direction != "none")
+                    .map(parse_vector)
+                    .collect::<Result<_>>()?;
+                self.space_directions = Some(
+                    directions
+                        .try_into()
+                        .map_err(|_| anyhow!("Expected three space directions"))?,
+                );
+            }
+            "space origin" => self.space_origin = Some(parse_vector(value)?),
+            "spacings" => {
+                let spacings: Vec<f32> = value
+                    .split_whitespace()
+                    .filter_map(|spacing| spacing.parse().ok())
+                    .collect();
+                self.spacings = spacings.try_into().ok();
+            }
+            "data file" | "datafile" => self.data_file = Some(value.to_string()),
+            "line skip" | "lineskip" => self.line_skip = value.parse()?,
+            "byte skip" | "byteskip" => self.byte_skip = value.parse()?,
+            "content" => self.content = value.to_string(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Sizes of the three spatial axes, skipping leading axes of size one.
+    fn spatial_sizes(&self) -> Result<[usize; 3]> {
+        let extra = self.sizes.len().saturating_sub(3);
+        if self.sizes[..extra].iter().any(|&size| size != 1) {
+            return Err(anyhow!("Only scalar 3D NRRD volumes are supported"));
+        }
+        self.sizes[extra..]
+            .try_into()
+            .map_err(|_| anyhow!("Expected three NRRD sizes, found {}", self.sizes.len()))
+    }
+}
+
+fn parse_vector(value: &str) -> Result<Vec3> {
+    let components: Vec<f32> = value
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<_, _>>()?;
+    components
+        .try_into()
+        .map_err(|_| anyhow!("Invalid NRRD vector {}", value))
+}
+
+fn parse_scalar_type(value: &str) -> Result<ScalarType> {
+    Ok(match value {
+        "signed char" | "int8" | "int8_t" => ScalarType::I8,
+        "uchar" | "unsigned char" | "uint8" | "uint8_t" => ScalarType::U8,
+        "short" | "short int" | "signed short" | "signed short int" | "int16" | "int16_t" => {
+            ScalarType::I16
+        }
+        "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => {
+            ScalarType::U16
+        }
+        "int" | "signed int" | "int32" | "int32_t" => ScalarType::I32,
+        "uint" | "unsigned int" | "uint32" | "uint32_t" => ScalarType::U32,
+        "longlong"
+        | "long long"
+        | "long long int"
+        | "signed long long"
+        | "signed long long int"
+        | "int64"
+        | "int64_t" => ScalarType::I64,
+        "ulonglong" | "unsigned long long" | "unsigned long long int" | "uint64" | "uint64_t" => {
+            ScalarType::U64
+        }
+        "float" => ScalarType::F32,
+        "double" => ScalarType::F64,
+        _ => return Err(anyhow!("Unsupported NRRD type {}", value)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn nrrd_file(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = header.as_bytes().to_vec();
+        bytes.extend(data);
+        bytes
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reads_attached_raw_in_ras_space() {
+        let header = "NRRD0005\r\n\
+                      # comment\r\n\
+                      type: short\r\n\
+                      dimension: 4\r\n\
+                      space: right-anterior-superior\r\n\
+                      sizes: 1 2 1 2\r\n\
+                      space directions: none (0.5,0,0) (0,0.75,0) (0,0,2)\r\n\
+                      space origin: (10,-20,30)\r\n\
+                      endian: big\r\n\
+                      encoding: raw\r\n\
+                      content: synthetic\r\n\
+                      modality:=CT\r\n\
+                      \r\n";
+        let data: Vec<u8> = [-1000i16, 0, 1, 3000]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        let path = temp_dir("nrrd-raw").join("image.nrrd");
+        std::fs::write(&path, nrrd_file(header, &data)).unwrap();
+
+        let image = load_nrrd_image(&path).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (2, 1, 2));
+        assert_eq!(image.pixel_spacing, [0.5, 0.75, 2.]);
+        assert_eq!(
+            image.image_orientation_patient,
+            [[-1., 0., 0.], [0., -1., 0.], [0., 0., 1.]]
+        );
+        assert_eq!(image.position_patient, [-10., 20., 30.]);
+        assert_eq!(image.volume, [-1000., 0., 1., 3000.]);
+        assert_eq!(image.metadata.series_description, "synthetic");
+    }
+
+    #[test]
+    fn reads_attached_gzip_with_spacings() {
+        let header = "NRRD0004\n\
+                      type: uchar\n\
+                      dimension: 3\n\
+                      sizes: 3 1 1\n\
+                      spacings: 0.5 2 4\n\
+                      encoding: gzip\n\
+                      \n";
+        let path = temp_dir("nrrd-gzip").join("image.nrrd");
+        std::fs::write(&path, nrrd_file(header, &gzip(&[0, 128, 255]))).unwrap();
+
+        let image = load_nrrd_image(&path).unwrap();
+
+        assert_eq!(image.pixel_spacing, [0.5, 2., 4.]);
+        assert_eq!(
+            image.image_orientation_patient,
+            [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]
+        );
+        assert_eq!(image.position_patient, [0., 0., 0.]);
+        assert_eq!(image.volume, [0., 128., 255.]);
+    }
+
+    #[test]
+    fn skips_lines_and_bytes_of_detached_data() {
+        let dir = temp_dir("nrrd-skip");
+        let header = "NRRD0004\n\
+                      type: float\n\
+                      dimension: 3\n\
+                      sizes: 2 1 1\n\
+                      encoding: raw\n\
+                      line skip: 1\n\
+                      byte skip: -1\n\
+                      data file: image.dat\n\
+                      \n";
+        std::fs::write(dir.join("image.nhdr"), header).unwrap();
+        let mut data = b"text header\nmore padding".to_vec();
+        data.extend(1.5f32.to_le_bytes());
+        data.extend((-2f32).to_le_bytes());
+        std::fs::write(dir.join("image.dat"), data).unwrap();
+
+        let image = load_nrrd_image(dir.join("image.nhdr")).unwrap();
+
+        assert_eq!(image.volume, [1.5, -2.]);
+    }
+
+    #[test]
+    fn saves_and_loads_every_layout() {
+        let dir = temp_dir("nrrd-roundtrip");
+        let (sin, cos) = 0.3f32.sin_cos();
+        for (name, encoding) in [
+            ("raw.nrrd", NrrdEncoding::Raw),
+            ("gzip.nrrd", NrrdEncoding::Gzip),
+            ("raw.nhdr", NrrdEncoding::Raw),
+            ("gzip.nhdr", NrrdEncoding::Gzip),
+        ] {
+            let image = ImageVolume {
+                columns: 3,
+                rows: 2,
+                slices: 2,
+                pixel_spacing: [0.5, 0.75, 2.5],
+                position_patient: [-100.25, 50., 12.5],
+                image_orientation_patient: [[cos, sin, 0.], [-sin, cos, 0.], [0., 0., 1.]],
+                volume: (0..12).map(|value| value as f32 * 0.5 - 3.).collect(),
+                metadata: VolumeMetadata {
+                    series_description: "Head CT".to_string(),
+                    ..Default::default()
+                },
+            };
+            let path = dir.join(name);
+            save_nrrd_image(&image, &path, encoding).unwrap();
+
+            let loaded = load_nrrd_image(&path).unwrap();
+
+            assert_eq!((loaded.columns, loaded.rows, loaded.slices), (3, 2, 2));
+            for axis in 0..3 {
+                assert!((loaded.pixel_spacing[axis] - image.pixel_spacing[axis]).abs() < 1e-5);
+                for i in 0..3 {
+                    let difference = loaded.image_orientation_patient[axis][i]
+                        - image.image_orientation_patient[axis][i];
+                    assert!(difference.abs() < 1e-5, "{}", name);
+                }
+            }
+            assert_eq!(loaded.position_patient, image.position_patient);
+            assert_eq!(loaded.volume, image.volume);
+            assert_eq!(loaded.metadata.series_description, "Head CT");
+        }
+        assert!(dir.join("raw.raw").exists());
+        assert!(dir.join("gzip.raw.gz").exists());
+    }
+
+    #[test]
+    fn rejects_unsupported_files() {
+        let dir = temp_dir("nrrd-invalid");
+        let cases = [
+            ("P5 2 2 255\n", "not a NRRD file"),
+            (
+                "NRRD0004\ntype: float\nsizes: 3 2 2 2\nencoding: raw\n\n",
+                "Only scalar",
+            ),
+            (
+                "NRRD0004\ntype: float\nsizes: 2 2 2\nencoding: bzip2\n\n",
+                "encoding bzip2",
+            ),
+            (
+                "NRRD0004\ntype: block\nsizes: 2 2 2\nencoding: raw\n\n",
+                "type block",
+            ),
+        ];
+        for (n, (contents, message)) in cases.into_iter().enumerate() {
+            let path = dir.join(format!("{}.nrrd", n));
+            std::fs::write(&path, contents).unwrap();
+            let error = load_nrrd_image(&path).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+}
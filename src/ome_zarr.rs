@@ -0,0 +1,585 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use rayon::prelude::*;
+use serde_json::Value;
+
+use crate::blosc;
+use crate::image_volume::{ImageVolume, Vec3, VolumeMetadata};
+use crate::raw_reader::ScalarType;
+
+/// Load a local OME-Zarr image, or a plain Zarr v2 array, as a volume.
+///
+/// Of the multiscale pyramid levels, the finest whose spatial axes all fit
+/// in `max_dimension` voxels is read, or the coarsest if none does. Leading
+/// time and channel axes are read at index zero. Spacing and origin come
+/// from the scale and translation transformations, converted to millimetres.
+pub fn load_ome_zarr<P: AsRef<Path>>(path: P, max_dimension: u32) -> Result<ImageVolume> {
+    let path = path.as_ref();
+    if path.join(".zarray").is_file() {
+        let array = ZarrArray::open(path)?;
+        let spatial = array.spatial_axes()?;
+        return array.read_volume(spatial, [1.; 3], [0.; 3], String::new());
+    }
+
+    let attributes = read_json(&path.join(".zattrs"))?;
+    let multiscale = attributes["multiscales"]
+        .get(0)
+        .ok_or_else(|| anyhow!("No multiscales in {}", path.join(".zattrs").display()))?;
+
+    let axes = Axis::parse_all(&multiscale["axes"]);
+    let datasets = multiscale["datasets"]
+        .as_array()
+        .filter(|datasets| !datasets.is_empty())
+        .ok_or_else(|| anyhow!("No datasets in OME-Zarr multiscales"))?;
+
+    // Levels run from the finest to the coarsest
+    let mut levels = Vec::new();
+    for dataset in datasets {
+        let level_path = dataset["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("OME-Zarr dataset without a path"))?;
+        levels.push((dataset, ZarrArray::open(&path.join(level_path))?));
+    }
+
+    let ndim = levels[0].1.shape.len();
+    let axes = axes.unwrap_or_else(|| Axis::default_axes(ndim));
+    if axes.len() != ndim {
+        return Err(anyhow!(
+            "OME-Zarr has {} axes for an array of {} dimensions",
+            axes.len(),
+            ndim
+        ));
+    }
+
+    // Spatial axes come last, ordered z, y, x
+    let spatial: [usize; 3] = (0..ndim)
+        .filter(|&axis| axes[axis].kind == "space")
+        .collect::<Vec<_>>()
+        .try_into()
+        .map_err(|_| anyhow!("Expected three spatial OME-Zarr axes"))?;
+
+    let index = levels
+        .iter()
+        .position(|(_, array)| {
+            spatial
+                .iter()
+                .all(|&axis| array.shape[axis] <= max_dimension as usize)
+        })
+        .unwrap_or(levels.len() - 1);
+    let (dataset, array) = &levels[index];
+
+    // Transformations of the dataset, then of the whole multiscale
+    let mut scale = vec![1.; ndim];
+    let mut translation = vec![0.; ndim];
+    for transformations in [
+        &dataset["coordinateTransformations"],
+        &multiscale["coordinateTransformations"],
+    ] {
+        apply_transformations(transformations, &mut scale, &mut translation)?;
+    }
+
+    let to_mm = spatial.map(|axis| axes[axis].millimetres_per_unit());
+    // Volumes are ordered x, y, z
+    let spacing = [2, 1, 0].map(|i| scale[spatial[i]] as f32 * to_mm[i]);
+    let origin = [2, 1, 0].map(|i| translation[spatial[i]] as f32 * to_mm[i]);
+    let name = multiscale["name"].as_str().unwrap_or_default().to_string();
+
+    array.read_volume(spatial, spacing, origin, name)
+}
+
+/// Name and unit of an OME-Zarr axis.
+struct Axis {
+    kind: String,
+    unit: String,
+}
+
+impl Axis {
+    /// Axes are objects since OME-Zarr 0.4 and plain names in 0.3.
+    fn parse_all(value: &Value) -> Option<Vec<Axis>> {
+        let axes = value.as_array()?;
+        Some(
+            axes.iter()
+                .map(|axis| match axis.as_str() {
+                    Some(name) => Axis::from_name(name, ""),
+                    None => {
+                        let name = axis["name"].as_str().unwrap_or_default();
+                        let mut parsed = Axis::from_name(name, axis["unit"].as_str().unwrap_or(""));
+                        if let Some(kind) = axis["type"].as_str() {
+                            parsed.kind = kind.to_string();
+                        }
+                        parsed
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    fn from_name(name: &str, unit: &str) -> Self {
+        let kind = match name {
+            "x" | "y" | "z" => "space",
+            "t" => "time",
+            _ => "channel",
+        };
+        Axis {
+            kind: kind.to_string(),
+            unit: unit.to_string(),
+        }
+    }
+
+    /// Before OME-Zarr 0.3 the axes were always t, c, z, y, x.
+    fn default_axes(ndim: usize) -> Vec<Axis> {
+        ["t", "c", "z", "y", "x"][5 - ndim.min(5)..]
+            .iter()
+            .map(|name| Axis::from_name(name, ""))
+            .collect()
+    }
+
+    fn millimetres_per_unit(&self) -> f32 {
+        match self.unit.as_str() {
+            "kilometer" => 1e6,
+            "meter" => 1e3,
+            "centimeter" => 10.,
+            "micrometer" => 1e-3,
+            "nanometer" => 1e-6,
+            "angstrom" => 1e-7,
+            _ => 1.,
+        }
+    }
+}
+
+fn apply_transformations(
+    transformations: &Value,
+    scale: &mut [f64],
+    translation: &mut [f64],
+) -> Result<()> {
+    let Some(transformations) = transformations.as_array() else {
+        return Ok(());
+    };
+    for transformation in transformations {
+        let values = |key: &str| -> Result<Vec<f64>> {
+            let values: Vec<f64> = transformation[key]
+                .as_array()
+                .map(|values| values.iter().filter_map(|v| v.as_f64()).collect())
+                .unwrap_or_default();
+            if values.len() != scale.len() {
+                return Err(anyhow!("OME-Zarr {} has {} values", key, values.len()));
+            }
+            Ok(values)
+        };
+        match transformation["type"].as_str() {
+            Some("scale") => {
+                let values = values("scale")?;
+                for i in 0..scale.len() {
+                    scale[i] *= values[i];
+                    translation[i] *= values[i];
+                }
+            }
+            Some("translation") => {
+                let values = values("translation")?;
+                for i in 0..translation.len() {
+                    translation[i] += values[i];
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A chunked Zarr v2 array described by its `.zarray` file.
+struct ZarrArray {
+    path: PathBuf,
+    shape: Vec<usize>,
+    chunks: Vec<usize>,
+    scalar_type: ScalarType,
+    big_endian: bool,
+    fortran_order: bool,
+    compressor: Value,
+    fill_value: f32,
+    separator: String,
+}
+
+impl ZarrArray {
+    fn open(path: &Path) -> Result<Self> {
+        let metadata = read_json(&path.join(".zarray"))?;
+        if metadata["zarr_format"].as_u64() != Some(2) {
+            return Err(anyhow!("{} is not a Zarr v2 array", path.display()));
+        }
+        if metadata["filters"]
+            .as_array()
+            .is_some_and(|f| !f.is_empty())
+        {
+            return Err(anyhow!("Zarr filters are not supported"));
+        }
+
+        let sizes = |key: &str| -> Result<Vec<usize>> {
+            metadata[key]
+                .as_array()
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|v| v.as_u64().map(|v| v as usize))
+                        .collect()
+                })
+                .ok_or_else(|| anyhow!("Invalid Zarr {} in {}", key, path.display()))
+        };
+        let shape = sizes("shape")?;
+        let chunks = sizes("chunks")?;
+        if shape.len() != chunks.len() {
+            return Err(anyhow!("Zarr shape and chunks differ in length"));
+        }
+
+        let dtype = metadata["dtype"].as_str().unwrap_or_default();
+        let (scalar_type, big_endian) = parse_dtype(dtype)?;
+
+        // Fill values are numbers, or strings for special floats
+        let fill_value = match &metadata["fill_value"] {
+            Value::Number(number) => number.as_f64().unwrap_or(0.) as f32,
+            Value::String(value) if value == "NaN" => f32::NAN,
+            Value::String(value) if value == "Infinity" => f32::INFINITY,
+            Value::String(value) if value == "-Infinity" => f32::NEG_INFINITY,
+            _ => 0.,
+        };
+
+        Ok(ZarrArray {
+            path: path.to_path_buf(),
+            shape,
+            chunks,
+            scalar_type,
+            big_endian,
+            fortran_order: metadata["order"].as_str() == Some("F"),
+            compressor: metadata["compressor"].clone(),
+            fill_value,
+            separator: metadata["dimension_separator"]
+                .as_str()
+                .unwrap_or(".")
+                .to_string(),
+        })
+    }
+
+    /// Indices of the z, y and x axes, the last three of the array.
+    fn spatial_axes(&self) -> Result<[usize; 3]> {
+        let ndim = self.shape.len();
+        if ndim < 3 {
+            return Err(anyhow!("Zarr array of {} dimensions is not a volume", ndim));
+        }
+        Ok([ndim - 3, ndim - 2, ndim - 1])
+    }
+
+    fn read_volume(
+        &self,
+        spatial: [usize; 3],
+        spacing: Vec3,
+        origin: Vec3,
+        name: String,
+    ) -> Result<ImageVolume> {
+        let [slices, rows, columns] = spatial.map(|axis| self.shape[axis]);
+        let grid = spatial.map(|axis| self.shape[axis].div_ceil(self.chunks[axis]));
+
+        // Every chunk holding spatial data at index zero of the other axes
+        let mut keys = Vec::new();
+        for z in 0..grid[0] {
+            for y in 0..grid[1] {
+                for x in 0..grid[2] {
+                    let mut key = vec![0; self.shape.len()];
+                    key[spatial[0]] = z;
+                    key[spatial[1]] = y;
+                    key[spatial[2]] = x;
+                    keys.push(key);
+                }
+            }
+        }
+
+        let decoded = keys
+            .par_iter()
+            .map(|key| self.read_chunk(key))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Element strides within a chunk
+        let ndim = self.shape.len();
+        let mut strides = vec![1; ndim];
+        if self.fortran_order {
+            for axis in 1..ndim {
+                strides[axis] = strides[axis - 1] * self.chunks[axis - 1];
+            }
+        } else {
+            for axis in (0..ndim - 1).rev() {
+                strides[axis] = strides[axis + 1] * self.chunks[axis + 1];
+            }
+        }
+        let [stride_z, stride_y, stride_x] = spatial.map(|axis| strides[axis]);
+        let [chunk_z, chunk_y, chunk_x] = spatial.map(|axis| self.chunks[axis]);
+
+        let mut volume = vec![self.fill_value; columns * rows * slices];
+        for (key, chunk) in keys.iter().zip(decoded) {
+            let [z0, y0, x0] = [0, 1, 2].map(|i| key[spatial[i]] * self.chunks[spatial[i]]);
+            let Some(chunk) = chunk else {
+                continue;
+            };
+            for z in 0..chunk_z.min(slices - z0) {
+                for y in 0..chunk_y.min(rows - y0) {
+                    for x in 0..chunk_x.min(columns - x0) {
+                        volume[((z0 + z) * rows + y0 + y) * columns + x0 + x] =
+                            chunk[z * stride_z + y * stride_y + x * stride_x];
+                    }
+                }
+            }
+        }
+
+        Ok(ImageVolume {
+            columns: u16::try_from(columns)?,
+            rows: u16::try_from(rows)?,
+            slices,
+            pixel_spacing: spacing,
+            position_patient: origin,
+            image_orientation_patient: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+            volume,
+            metadata: VolumeMetadata {
+                series_description: name,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Decoded values of a chunk, or `None` when the chunk was never written.
+    fn read_chunk(&self, key: &[usize]) -> Result<Option<Vec<f32>>> {
+        let name = key
+            .iter()
+            .map(|index| index.to_string())
+            .collect::<Vec<_>>()
+            .join(&self.separator);
+        let path = self.path.join(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?
+            .read_to_end(&mut bytes)?;
+        let bytes = self
+            .decompress(bytes)
+            .with_context(|| format!("Failed to decompress {}", path.display()))?;
+
+        let count = self.chunks.iter().product();
+        let values = self.scalar_type.decode(&bytes, self.big_endian, count)?;
+        Ok(Some(values))
+    }
+
+    fn decompress(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        if self.compressor.is_null() {
+            return Ok(bytes);
+        }
+
+        let mut out = Vec::new();
+        match self.compressor["id"].as_str().unwrap_or_default() {
+            "blosc" => out = blosc::decompress(&bytes)?,
+            "zstd" => out = zstd::stream::decode_all(bytes.as_slice())?,
+            "gzip" => {
+                GzDecoder::new(bytes.as_slice()).read_to_end(&mut out)?;
+            }
+            "zlib" => {
+                ZlibDecoder::new(bytes.as_slice()).read_to_end(&mut out)?;
+            }
+            // numcodecs prefixes LZ4 blocks with their decompressed size
+            "lz4" => out = lz4_flex::block::decompress_size_prepended(&bytes)?,
+            id => return Err(anyhow!("Unsupported Zarr compressor {}", id)),
+        }
+        Ok(out)
+    }
+}
+
+/// Scalar type and byte order of a NumPy dtype string such as `<u2`.
+fn parse_dtype(dtype: &str) -> Result<(ScalarType, bool)> {
+    let unsupported = || anyhow!("Unsupported Zarr dtype {}", dtype);
+    let mut chars = dtype.chars();
+    let big_endian = match chars.next() {
+        Some('>') => true,
+        Some('<' | '|' | '=') => false,
+        _ => return Err(unsupported()),
+    };
+    let scalar_type = match chars.as_str() {
+        "b1" | "u1" => ScalarType::U8,
+        "i1" => ScalarType::I8,
+        "u2" => ScalarType::U16,
+        "i2" => ScalarType::I16,
+        "u4" => ScalarType::U32,
+        "i4" => ScalarType::I32,
+        "u8" => ScalarType::U64,
+        "i8" => ScalarType::I64,
+        "f4" => ScalarType::F32,
+        "f8" => ScalarType::F64,
+        _ => return Err(unsupported()),
+    };
+    Ok((scalar_type, big_endian))
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    serde_json::from_str(&text).with_context(|| format!("Invalid JSON in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn write_json(path: &Path, value: Value) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, value.to_string()).unwrap();
+    }
+
+    /// A Zarr v2 array of `<u2` values with one chunk file per entry of
+    /// `chunks`, keyed by chunk indices.
+    fn write_array(dir: &Path, metadata: Value, chunks: &[(&str, Vec<u16>)]) {
+        write_json(&dir.join(".zarray"), metadata);
+        for (key, values) in chunks {
+            let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            let path = dir.join(key);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, bytes).unwrap();
+        }
+    }
+
+    fn array_metadata(shape: &[usize], chunks: &[usize]) -> Value {
+        json!({
+            "zarr_format": 2,
+            "shape": shape,
+            "chunks": chunks,
+            "dtype": "<u2",
+            "compressor": null,
+            "fill_value": 7,
+            "order": "C",
+            "filters": null,
+        })
+    }
+
+    #[test]
+    fn assembles_edge_and_missing_chunks() {
+        let dir = temp_dir("zarr-chunks");
+        // A 1x2x3 volume in chunks of 1x2x2, of which the second is cut
+        // short at the edge, and a 1x2x3 one with a chunk never written
+        write_array(
+            &dir.join("edges"),
+            array_metadata(&[1, 2, 3], &[1, 2, 2]),
+            &[("0.0.0", vec![1, 2, 4, 5]), ("0.0.1", vec![3, 0, 6, 0])],
+        );
+        write_array(
+            &dir.join("missing"),
+            array_metadata(&[1, 2, 3], &[1, 2, 2]),
+            &[("0.0.0", vec![1, 2, 4, 5])],
+        );
+
+        let image = load_ome_zarr(dir.join("edges"), 2048).unwrap();
+        assert_eq!((image.columns, image.rows, image.slices), (3, 2, 1));
+        assert_eq!(image.volume, [1., 2., 3., 4., 5., 6.]);
+
+        let image = load_ome_zarr(dir.join("missing"), 2048).unwrap();
+        assert_eq!(image.volume, [1., 2., 7., 4., 5., 7.]);
+    }
+
+    #[test]
+    fn reads_fortran_order_nested_gzip_chunks() {
+        let dir = temp_dir("zarr-fortran");
+        let mut metadata = array_metadata(&[2, 1, 2], &[2, 1, 2]);
+        metadata["order"] = json!("F");
+        metadata["dimension_separator"] = json!("/");
+        metadata["compressor"] = json!({ "id": "gzip", "level": 1 });
+        write_json(&dir.join(".zarray"), metadata);
+        // Fortran order runs fastest along z
+        let bytes: Vec<u8> = [1u16, 3, 2, 4]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&bytes).unwrap();
+        std::fs::create_dir_all(dir.join("0/0")).unwrap();
+        std::fs::write(dir.join("0/0/0"), encoder.finish().unwrap()).unwrap();
+
+        let image = load_ome_zarr(&dir, 2048).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (2, 1, 2));
+        assert_eq!(image.volume, [1., 2., 3., 4.]);
+    }
+
+    #[test]
+    fn reads_finest_fitting_multiscale_level_in_millimetres() {
+        let dir = temp_dir("ome-zarr");
+        write_json(
+            &dir.join(".zattrs"),
+            json!({
+                "multiscales": [{
+                    "version": "0.4",
+                    "name": "cells",
+                    "axes": [
+                        { "name": "c", "type": "channel" },
+                        { "name": "z", "type": "space", "unit": "micrometer" },
+                        { "name": "y", "type": "space", "unit": "micrometer" },
+                        { "name": "x", "type": "space", "unit": "micrometer" },
+                    ],
+                    "datasets": [
+                        {
+                            "path": "0",
+                            "coordinateTransformations": [
+                                { "type": "scale", "scale": [1, 2, 0.5, 0.5] },
+                            ],
+                        },
+                        {
+                            "path": "1",
+                            "coordinateTransformations": [
+                                { "type": "scale", "scale": [1, 2, 1, 1] },
+                                { "type": "translation", "translation": [0, 0, 0.25, 0.25] },
+                            ],
+                        },
+                    ],
+                    "coordinateTransformations": [
+                        { "type": "translation", "translation": [0, 1000, 0, 0] },
+                    ],
+                }],
+            }),
+        );
+        write_array(
+            &dir.join("0"),
+            array_metadata(&[2, 1, 4, 4], &[1, 1, 4, 4]),
+            &[("0.0.0.0", vec![1; 16]), ("1.0.0.0", vec![2; 16])],
+        );
+        write_array(
+            &dir.join("1"),
+            array_metadata(&[2, 1, 2, 2], &[1, 1, 2, 2]),
+            &[("0.0.0.0", vec![1, 2, 3, 4]), ("1.0.0.0", vec![9; 4])],
+        );
+
+        let image = load_ome_zarr(&dir, 4).unwrap();
+        assert_eq!((image.columns, image.rows, image.slices), (4, 4, 1));
+        assert_eq!(image.pixel_spacing, [0.0005, 0.0005, 0.002]);
+        assert_eq!(image.position_patient, [0., 0., 1.]);
+        assert_eq!(image.volume, [1.; 16]);
+        assert_eq!(image.metadata.series_description, "cells");
+
+        // The coarser level fits when the finest does not
+        let image = load_ome_zarr(&dir, 3).unwrap();
+        assert_eq!((image.columns, image.rows, image.slices), (2, 2, 1));
+        assert_eq!(image.pixel_spacing, [0.001, 0.001, 0.002]);
+        assert_eq!(image.position_patient, [0.00025, 0.00025, 1.]);
+        assert_eq!(image.volume, [1., 2., 3., 4.]);
+    }
+
+    #[test]
+    fn parses_dtypes() {
+        assert_eq!(parse_dtype("<u2").unwrap(), (ScalarType::U16, false));
+        assert_eq!(parse_dtype(">f8").unwrap(), (ScalarType::F64, true));
+        assert_eq!(parse_dtype("|u1").unwrap(), (ScalarType::U8, false));
+        assert!(parse_dtype("<c8").is_err());
+        assert!(parse_dtype("u2").is_err());
+    }
+}
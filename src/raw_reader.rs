@@ -0,0 +1,252 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::image_volume::{ImageVolume, Vec3, VolumeMetadata};
+
+/// Layout of a headerless volume file, supplied by the caller.
+#[derive(Debug, Clone)]
+pub struct RawVolumeDescription {
+    /// Number of voxels along the column, row and slice axes
+    pub dimensions: [usize; 3],
+    pub spacing: Vec3,
+    pub scalar_type: ScalarType,
+    pub big_endian: bool,
+    /// Bytes to skip before the voxel data
+    pub header_size: usize,
+}
+
+impl RawVolumeDescription {
+    /// Read the layout from a file name such as `skull_256x256x256_uint8.raw`,
+    /// the convention of the Open SciVis datasets. Spacing defaults to 1 mm
+    /// and data to little endian.
+    pub fn from_file_name<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+
+        let mut dimensions = None;
+        let mut scalar_type = None;
+        for part in stem.split('_') {
+            if let Some(dims) = parse_dimensions(part) {
+                dimensions = Some(dims);
+            } else if let Some(t) = ScalarType::from_name(part) {
+                scalar_type = Some(t);
+            }
+        }
+
+        Ok(RawVolumeDescription {
+            dimensions: dimensions.ok_or_else(|| {
+                anyhow!("No dimensions such as 256x256x256 in {}", path.display())
+            })?,
+            spacing: [1.; 3],
+            scalar_type: scalar_type
+                .ok_or_else(|| anyhow!("No scalar type such as uint8 in {}", path.display()))?,
+            big_endian: false,
+            header_size: 0,
+        })
+    }
+}
+
+/// Load a headerless volume laid out as described by the caller. The volume
+/// is axis aligned with its first voxel at the origin.
+pub fn load_raw_image<P: AsRef<Path>>(
+    path: P,
+    description: &RawVolumeDescription,
+) -> Result<ImageVolume> {
+    let path = path.as_ref();
+    let mut bytes = Vec::new();
+    File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?
+        .read_to_end(&mut bytes)?;
+
+    let [columns, rows, slices] = description.dimensions;
+    let data = bytes.get(description.header_size..).unwrap_or_default();
+    let volume =
+        description
+            .scalar_type
+            .decode(data, description.big_endian, columns * rows * slices)?;
+
+    Ok(ImageVolume {
+        columns: u16::try_from(columns)?,
+        rows: u16::try_from(rows)?,
+        slices,
+        pixel_spacing: description.spacing,
+        position_patient: [0.; 3],
+        image_orientation_patient: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+        volume,
+        metadata: VolumeMetadata::default(),
+    })
+}
+
+fn parse_dimensions(value: &str) -> Option<[usize; 3]> {
+    let dims: Vec<usize> = value
+        .split('x')
+        .map(|dim| dim.parse().ok())
+        .collect::<Option<_>>()?;
+    dims.try_into().ok()
+}
+
+/// Voxel types of headerless and lightly described volume files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+}
+
+impl ScalarType {
+    /// Parse a short type name such as `uint8` or `float32`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "int8" => ScalarType::I8,
+            "uint8" => ScalarType::U8,
+            "int16" => ScalarType::I16,
+            "uint16" => ScalarType::U16,
+            "int32" => ScalarType::I32,
+            "uint32" => ScalarType::U32,
+            "int64" => ScalarType::I64,
+            "uint64" => ScalarType::U64,
+            "float32" | "float" => ScalarType::F32,
+            "float64" | "double" => ScalarType::F64,
+            _ => return None,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            ScalarType::I8 | ScalarType::U8 => 1,
+            ScalarType::I16 | ScalarType::U16 => 2,
+            ScalarType::I32 | ScalarType::U32 | ScalarType::F32 => 4,
+            ScalarType::I64 | ScalarType::U64 | ScalarType::F64 => 8,
+        }
+    }
+
+    /// Convert the first `count` values of `data` to f32.
+    pub fn decode(&self, data: &[u8], big_endian: bool, count: usize) -> Result<Vec<f32>> {
+        let size = self.size();
+        if data.len() < count * size {
+            return Err(anyhow!(
+                "Image data too short: expected {} bytes, found {}",
+                count * size,
+                data.len()
+            ));
+        }
+
+        let voxels = data[..count * size]
+            .chunks_exact(size)
+            .map(|chunk| {
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(chunk);
+                if big_endian {
+                    bytes[..size].reverse();
+                }
+                let b2 = [bytes[0], bytes[1]];
+                let b4 = [bytes[0], bytes[1], bytes[2], bytes[3]];
+                match self {
+                    ScalarType::I8 => bytes[0] as i8 as f32,
+                    ScalarType::U8 => bytes[0] as f32,
+                    ScalarType::I16 => i16::from_le_bytes(b2) as f32,
+                    ScalarType::U16 => u16::from_le_bytes(b2) as f32,
+                    ScalarType::I32 => i32::from_le_bytes(b4) as f32,
+                    ScalarType::U32 => u32::from_le_bytes(b4) as f32,
+                    ScalarType::I64 => i64::from_le_bytes(bytes) as f32,
+                    ScalarType::U64 => u64::from_le_bytes(bytes) as f32,
+                    ScalarType::F32 => f32::from_le_bytes(b4),
+                    ScalarType::F64 => f64::from_le_bytes(bytes) as f32,
+                }
+            })
+            .collect();
+
+        Ok(voxels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn describes_volume_from_file_name() {
+        let description =
+            RawVolumeDescription::from_file_name("data/skull_256x128x64_uint16.raw").unwrap();
+        assert_eq!(description.dimensions, [256, 128, 64]);
+        assert_eq!(description.scalar_type, ScalarType::U16);
+        assert_eq!(description.spacing, [1.; 3]);
+        assert!(!description.big_endian);
+
+        assert!(RawVolumeDescription::from_file_name("skull_uint8.raw").is_err());
+        assert!(RawVolumeDescription::from_file_name("skull_256x256x256.raw").is_err());
+    }
+
+    #[test]
+    fn decodes_every_scalar_type() {
+        let cases: [(ScalarType, Vec<u8>, f32); 10] = [
+            (ScalarType::I8, vec![0x80], -128.),
+            (ScalarType::U8, vec![0xff], 255.),
+            (ScalarType::I16, (-300i16).to_le_bytes().to_vec(), -300.),
+            (ScalarType::U16, 60000u16.to_le_bytes().to_vec(), 60000.),
+            (ScalarType::I32, (-70000i32).to_le_bytes().to_vec(), -70000.),
+            (ScalarType::U32, 4_000_000u32.to_le_bytes().to_vec(), 4e6),
+            (ScalarType::I64, (-5i64).to_le_bytes().to_vec(), -5.),
+            (
+                ScalarType::U64,
+                (1u64 << 40).to_le_bytes().to_vec(),
+                (1u64 << 40) as f32,
+            ),
+            (ScalarType::F32, 0.25f32.to_le_bytes().to_vec(), 0.25),
+            (ScalarType::F64, (-1.5f64).to_le_bytes().to_vec(), -1.5),
+        ];
+        for (scalar_type, little_endian, expected) in cases {
+            assert_eq!(little_endian.len(), scalar_type.size());
+            assert_eq!(
+                scalar_type.decode(&little_endian, false, 1).unwrap(),
+                [expected],
+                "{:?}",
+                scalar_type
+            );
+            let big_endian: Vec<u8> = little_endian.iter().rev().copied().collect();
+            assert_eq!(
+                scalar_type.decode(&big_endian, true, 1).unwrap(),
+                [expected],
+                "{:?}",
+                scalar_type
+            );
+        }
+
+        assert!(ScalarType::I16.decode(&[0; 3], false, 2).is_err());
+    }
+
+    #[test]
+    fn loads_raw_volume_after_header() {
+        let path = temp_dir("raw").join("volume_2x1x2_int16.raw");
+        let mut data = b"HEAD".to_vec();
+        data.extend([1i16, -2, 3, -4].iter().flat_map(|v| v.to_be_bytes()));
+        std::fs::write(&path, data).unwrap();
+        let description = RawVolumeDescription {
+            spacing: [0.5, 1., 2.],
+            big_endian: true,
+            header_size: 4,
+            ..RawVolumeDescription::from_file_name(&path).unwrap()
+        };
+
+        let image = load_raw_image(&path, &description).unwrap();
+
+        assert_eq!((image.columns, image.rows, image.slices), (2, 1, 2));
+        assert_eq!(image.pixel_spacing, [0.5, 1., 2.]);
+        assert_eq!(image.position_patient, [0., 0., 0.]);
+        assert_eq!(image.volume, [1., -2., 3., -4.]);
+    }
+}
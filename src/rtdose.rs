@@ -0,0 +1,137 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use dicom::object::OpenFileOptions;
+use dicom_dictionary_std::{tags, uids};
+
+use crate::dicom_reader::{self, read_string, LoadOptions, LoadWarning};
+use crate::dicom_series::collect_files;
+use crate::image_volume::ImageVolume;
+
+/// A dose distribution on its own grid.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct RtDose {
+    /// Dose per voxel, in `units`
+    pub dose: ImageVolume,
+    /// `GY`, or `RELATIVE` for doses relative to an implicit reference
+    pub units: String,
+    /// Whether the grid holds a whole `PLAN`, one `BEAM`, a `FRACTION`...
+    pub summation_type: String,
+}
+
+/// Units of doses as written beside values, e.g. `Gy` for `GY`.
+pub fn unit_label(units: &str) -> &str {
+    match units {
+        "GY" => "Gy",
+        "RELATIVE" => "relative",
+        "" => "unknown units",
+        units => units,
+    }
+}
+
+impl RtDose {
+    /// Highest dose in the grid.
+    pub fn max_dose(&self) -> f32 {
+        self.dose.volume.iter().copied().fold(0., f32::max)
+    }
+}
+
+/// Load a multi-frame RT Dose file. Frames are placed by
+/// GridFrameOffsetVector and scaled by DoseGridScaling.
+pub fn load_rt_dose<P: AsRef<Path>>(path: P) -> Result<(RtDose, Vec<LoadWarning>)> {
+    let path = path.as_ref();
+    let obj = OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    if obj.meta().media_storage_sop_class_uid() != uids::RT_DOSE_STORAGE {
+        return Err(anyhow!("{} is not an RT Dose", path.display()));
+    }
+
+    // Irregular offsets are resampled onto a regular grid
+    let options = LoadOptions {
+        resample: true,
+        ..Default::default()
+    };
+    let (dose, warnings) = dicom_reader::load_dicom_image(&[path], &options)?;
+
+    Ok((
+        RtDose {
+            dose,
+            units: read_string(&obj, "DoseUnits"),
+            summation_type: read_string(&obj, "DoseSummationType"),
+        },
+        warnings,
+    ))
+}
+
+/// RT Dose files under `dir` on `frame_of_reference_uid`.
+pub fn find_rt_doses<P: AsRef<Path>>(dir: P, frame_of_reference_uid: &str) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    collect_files(dir.as_ref(), &mut files)?;
+    files.sort();
+
+    Ok(files
+        .into_iter()
+        .filter(|file| {
+            OpenFileOptions::new()
+                .read_until(tags::PIXEL_DATA)
+                .open_file(file)
+                .is_ok_and(|obj| {
+                    obj.meta().media_storage_sop_class_uid() == uids::RT_DOSE_STORAGE
+                        && read_string(&obj, "FrameOfReferenceUID") == frame_of_reference_uid
+                })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_dir, write_rt_dose, SliceGeometry, FRAME_OF_REFERENCE_UID};
+
+    #[test]
+    fn loads_scaled_dose_frames_by_offset() {
+        let dir = temp_dir("rtdose");
+        let geometry = SliceGeometry {
+            columns: 3,
+            rows: 2,
+            pixel_spacing: [2.5, 2.],
+            position: [-3., -2., 40.],
+            orientation: [1., 0., 0., 0., 1., 0.],
+        };
+        let stored: Vec<u32> = (0..18).map(|value| value * 1000).collect();
+        let path = dir.join("RD.dcm");
+        // Offsets may start at the position of the first frame
+        write_rt_dose(&path, &geometry, &[40., 43., 46.], &stored, 0.001);
+
+        assert_eq!(
+            find_rt_doses(&dir, FRAME_OF_REFERENCE_UID).unwrap(),
+            std::slice::from_ref(&path)
+        );
+        assert!(find_rt_doses(&dir, "1.2.3").unwrap().is_empty());
+
+        let (rt_dose, warnings) = load_rt_dose(&path).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(rt_dose.units, "GY");
+        assert_eq!(rt_dose.summation_type, "PLAN");
+        let dose = &rt_dose.dose;
+        assert_eq!((dose.columns, dose.rows, dose.slices), (3, 2, 3));
+        assert_eq!(dose.pixel_spacing, [2., 2.5, 3.]);
+        assert_eq!(dose.position_patient, [-3., -2., 40.]);
+        let expected: Vec<f32> = (0..18).map(|value| value as f32).collect();
+        for (value, expected) in dose.volume.iter().zip(&expected) {
+            assert!((value - expected).abs() < 1e-4, "{} != {}", value, expected);
+        }
+        assert!((rt_dose.max_dose() - 17.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn labels_dose_units() {
+        assert_eq!(unit_label("GY"), "Gy");
+        assert_eq!(unit_label("RELATIVE"), "relative");
+        assert_eq!(unit_label(""), "unknown units");
+        assert_eq!(unit_label("CGY"), "CGY");
+    }
+}
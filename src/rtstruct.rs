@@ -0,0 +1,406 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use dicom::object::{open_file, InMemDicomObject, OpenFileOptions};
+use dicom_dictionary_std::{tags, uids};
+use rayon::prelude::*;
+
+use crate::dicom_reader::read_string;
+use crate::dicom_series::collect_files;
+use crate::image_volume::{ImageVolume, Vec3};
+
+/// Colour of ROIs without a display colour
+const DEFAULT_COLOR: [u8; 3] = [255, 255, 0];
+
+/// Regions of interest outlined on the images of a frame of reference.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct StructureSet {
+    pub label: String,
+    pub frame_of_reference_uid: String,
+    pub rois: Vec<Roi>,
+}
+
+/// A region of interest, outlined by closed planar contours.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Roi {
+    pub number: i32,
+    pub name: String,
+    /// RT ROI interpreted type, such as `PTV` or `ORGAN`
+    pub interpreted_type: String,
+    pub color: [u8; 3],
+    /// Polygons in LPS millimetres, each implicitly closed
+    pub contours: Vec<Vec<Vec3>>,
+}
+
+/// Voxels of an image labelled by the ROI covering them.
+///
+/// Label zero is background and label `n` is the ROI coloured by
+/// `colors[n - 1]`. Where ROIs overlap the smallest one is kept, so organs
+/// and targets stay visible inside the body outline.
+#[derive(Debug, Clone)]
+pub struct LabelVolume {
+    pub columns: u16,
+    pub rows: u16,
+    pub slices: usize,
+    pub labels: Vec<u8>,
+    pub colors: Vec<[u8; 3]>,
+}
+
+/// Load the ROIs of an RT Structure Set file.
+pub fn load_structure_set<P: AsRef<Path>>(path: P) -> Result<StructureSet> {
+    let path = path.as_ref();
+    let obj = open_file(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    if obj.meta().media_storage_sop_class_uid() != uids::RT_STRUCTURE_SET_STORAGE {
+        return Err(anyhow!("{} is not an RT Structure Set", path.display()));
+    }
+
+    let mut rois: Vec<Roi> = items(&obj, tags::STRUCTURE_SET_ROI_SEQUENCE)
+        .iter()
+        .map(|item| Roi {
+            number: read_int(item, tags::ROI_NUMBER),
+            name: read_string(item, "ROIName"),
+            interpreted_type: String::new(),
+            color: DEFAULT_COLOR,
+            contours: Vec::new(),
+        })
+        .collect();
+
+    for item in items(&obj, tags::ROI_CONTOUR_SEQUENCE) {
+        let number = read_int(item, tags::REFERENCED_ROI_NUMBER);
+        let Some(roi) = rois.iter_mut().find(|roi| roi.number == number) else {
+            continue;
+        };
+        if let Some(color) = item
+            .element(tags::ROI_DISPLAY_COLOR)
+            .ok()
+            .and_then(|elem| elem.to_multi_int::<i32>().ok())
+            .filter(|color| color.len() == 3)
+        {
+            roi.color = [0, 1, 2].map(|c| color[c].clamp(0, 255) as u8);
+        }
+
+        for contour in items(item, tags::CONTOUR_SEQUENCE) {
+            // Points and open polylines enclose no voxels
+            if read_string(contour, "ContourGeometricType") != "CLOSED_PLANAR" {
+                continue;
+            }
+            let data = contour
+                .element(tags::CONTOUR_DATA)
+                .ok()
+                .and_then(|elem| elem.to_multi_float32().ok())
+                .ok_or_else(|| anyhow!("Invalid contour of {} in {}", roi.name, path.display()))?;
+            roi.contours.push(
+                data.chunks_exact(3)
+                    .map(|point| [point[0], point[1], point[2]])
+                    .collect(),
+            );
+        }
+    }
+
+    for item in items(&obj, tags::RTROI_OBSERVATIONS_SEQUENCE) {
+        let number = read_int(item, tags::REFERENCED_ROI_NUMBER);
+        if let Some(roi) = rois.iter_mut().find(|roi| roi.number == number) {
+            roi.interpreted_type = read_string(item, "RTROIInterpretedType");
+        }
+    }
+
+    Ok(StructureSet {
+        label: read_string(&obj, "StructureSetLabel"),
+        frame_of_reference_uid: referenced_frame_of_reference(&obj),
+        rois,
+    })
+}
+
+/// RT Structure Set files under `dir` drawn on `frame_of_reference_uid`.
+pub fn find_structure_sets<P: AsRef<Path>>(
+    dir: P,
+    frame_of_reference_uid: &str,
+) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    collect_files(dir.as_ref(), &mut files)?;
+    files.sort();
+
+    Ok(files
+        .into_iter()
+        .filter(|file| {
+            OpenFileOptions::new()
+                .read_until(tags::ROI_CONTOUR_SEQUENCE)
+                .open_file(file)
+                .is_ok_and(|obj| {
+                    obj.meta().media_storage_sop_class_uid() == uids::RT_STRUCTURE_SET_STORAGE
+                        && referenced_frame_of_reference(&obj) == frame_of_reference_uid
+                })
+        })
+        .collect())
+}
+
+impl Roi {
+    /// Voxels of `image` whose centre lies inside the ROI, slice by slice
+    /// and row by row.
+    ///
+    /// Each contour is assigned to the nearest slice, and the contours of a
+    /// slice are filled together with the even-odd rule so inner contours
+    /// cut holes.
+    pub fn rasterize(&self, image: &ImageVolume) -> Vec<bool> {
+        let (columns, rows) = (image.columns as usize, image.rows as usize);
+        let mut mask = vec![false; columns * rows * image.slices];
+
+        // Contours in voxel coordinates, grouped by slice
+        let mut slices: Vec<Vec<Vec<[f32; 2]>>> = vec![Vec::new(); image.slices];
+        for contour in &self.contours {
+            if contour.len() < 3 {
+                continue;
+            }
+            let points: Vec<Vec3> = contour
+                .iter()
+                .map(|&point| image.patient_to_index(point))
+                .collect();
+            let k = points.iter().map(|point| point[2]).sum::<f32>() / points.len() as f32;
+            let k = k.round();
+            if k < 0. || k >= image.slices as f32 {
+                continue;
+            }
+            slices[k as usize].push(points.iter().map(|p| [p[0], p[1]]).collect());
+        }
+
+        let slice_size = columns * rows;
+        mask.par_chunks_mut(slice_size)
+            .zip(slices.par_iter())
+            .filter(|(_, polygons)| !polygons.is_empty())
+            .for_each(|(slice, polygons)| fill_polygons(slice, columns, rows, polygons));
+        mask
+    }
+}
+
+/// Rasterize every ROI of a structure set on the grid of `image`.
+pub fn label_volume(image: &ImageVolume, rois: &[Roi]) -> LabelVolume {
+    let mut masks: Vec<(usize, Vec<bool>)> = rois
+        .par_iter()
+        .map(|roi| roi.rasterize(image))
+        .enumerate()
+        .collect();
+    // Largest first, so smaller ROIs are written over them
+    masks.sort_by_cached_key(|(_, mask)| std::cmp::Reverse(mask.iter().filter(|&&v| v).count()));
+
+    let mut labels = vec![0u8; image.volume.len()];
+    for (index, mask) in masks.iter().filter(|(index, _)| *index < u8::MAX as usize) {
+        for (label, _) in labels.iter_mut().zip(mask).filter(|(_, &inside)| inside) {
+            *label = *index as u8 + 1;
+        }
+    }
+
+    LabelVolume {
+        columns: image.columns,
+        rows: image.rows,
+        slices: image.slices,
+        labels,
+        colors: rois
+            .iter()
+            .take(u8::MAX as usize)
+            .map(|roi| roi.color)
+            .collect(),
+    }
+}
+
+/// Fill the pixels of a slice whose centre is inside the polygons, in
+/// column and row coordinates, by scanning each row.
+fn fill_polygons(slice: &mut [bool], columns: usize, rows: usize, polygons: &[Vec<[f32; 2]>]) {
+    let mut crossings = Vec::new();
+    for row in 0..rows {
+        let y = row as f32;
+        crossings.clear();
+        for polygon in polygons {
+            for (i, &[x0, y0]) in polygon.iter().enumerate() {
+                let [x1, y1] = polygon[(i + 1) % polygon.len()];
+                // Half open so vertices on the scanline are counted once
+                if (y0 <= y) != (y1 <= y) {
+                    crossings.push(x0 + (y - y0) * (x1 - x0) / (y1 - y0));
+                }
+            }
+        }
+        crossings.sort_by(f32::total_cmp);
+
+        for span in crossings.chunks_exact(2) {
+            let start = span[0].ceil().max(0.) as usize;
+            let end = (span[1].floor() + 1.).clamp(0., columns as f32) as usize;
+            for inside in slice[row * columns..(row + 1) * columns]
+                .iter_mut()
+                .take(end)
+                .skip(start)
+            {
+                *inside = true;
+            }
+        }
+    }
+}
+
+fn items(obj: &InMemDicomObject, tag: dicom::core::Tag) -> &[InMemDicomObject] {
+    obj.element(tag)
+        .ok()
+        .and_then(|elem| elem.items())
+        .unwrap_or_default()
+}
+
+fn read_int(obj: &InMemDicomObject, tag: dicom::core::Tag) -> i32 {
+    obj.element(tag)
+        .ok()
+        .and_then(|elem| elem.to_int().ok())
+        .unwrap_or(-1)
+}
+
+fn referenced_frame_of_reference(obj: &InMemDicomObject) -> String {
+    items(obj, tags::REFERENCED_FRAME_OF_REFERENCE_SEQUENCE)
+        .first()
+        .map(|item| read_string(item, "FrameOfReferenceUID"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{grid, temp_dir, write_structure_set, FRAME_OF_REFERENCE_UID};
+
+    fn roi(number: i32, name: &str, contours: Vec<Vec<Vec3>>) -> Roi {
+        Roi {
+            number,
+            name: name.to_string(),
+            interpreted_type: "ORGAN".to_string(),
+            color: [number as u8, 128, 255],
+            contours,
+        }
+    }
+
+    /// Rectangle from `min` to `max` on the axial plane at `z`.
+    fn rectangle(min: [f32; 2], max: [f32; 2], z: f32) -> Vec<Vec3> {
+        vec![
+            [min[0], min[1], z],
+            [max[0], min[1], z],
+            [max[0], max[1], z],
+            [min[0], max[1], z],
+        ]
+    }
+
+    /// Voxels set in a mask, as column, row and slice indices.
+    fn voxels(mask: &[bool], image: &ImageVolume) -> Vec<[usize; 3]> {
+        let (columns, rows) = (image.columns as usize, image.rows as usize);
+        (0..mask.len())
+            .filter(|&index| mask[index])
+            .map(|index| {
+                [
+                    index % columns,
+                    index / columns % rows,
+                    index / (columns * rows),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rasterizes_voxels_whose_centre_is_inside() {
+        // Voxel centres lie at x = -10, -8, ... and y = -5, -4, ...
+        let image = grid((6, 5, 3), [2., 1., 3.], [-10., -5., 30.]);
+        let square = roi(1, "Square", vec![rectangle([-9., -4.5], [-4., -2.5], 33.)]);
+        let expected = [
+            [1, 1, 1],
+            [2, 1, 1],
+            [3, 1, 1],
+            [1, 2, 1],
+            [2, 2, 1],
+            [3, 2, 1],
+        ];
+        assert_eq!(voxels(&square.rasterize(&image), &image), expected);
+    }
+
+    #[test]
+    fn cuts_holes_with_inner_contours() {
+        let image = grid((5, 5, 1), [1., 1., 1.], [0., 0., 0.]);
+        let ring = roi(
+            1,
+            "Ring",
+            vec![
+                rectangle([0.5, 0.5], [3.5, 3.5], 0.),
+                rectangle([1.5, 1.5], [2.5, 2.5], 0.),
+            ],
+        );
+        let mask = ring.rasterize(&image);
+        assert_eq!(mask.iter().filter(|&&inside| inside).count(), 8);
+        assert!(!mask[2 * 5 + 2]);
+        assert!(mask[5 + 1] && mask[3 * 5 + 3]);
+    }
+
+    #[test]
+    fn assigns_contours_to_the_nearest_slice() {
+        let image = grid((3, 3, 3), [1., 1., 2.], [0., 0., 10.]);
+        let square = |z| rectangle([-0.5, -0.5], [0.5, 0.5], z);
+        let slices = |contours| {
+            let mask = roi(1, "Square", contours).rasterize(&image);
+            voxels(&mask, &image)
+                .iter()
+                .map(|voxel| voxel[2])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(slices(vec![square(12.9)]), [1]);
+        assert_eq!(slices(vec![square(13.1)]), [2]);
+        // Contours beyond the volume and degenerate ones are ignored
+        assert!(slices(vec![square(8.9), square(15.1)]).is_empty());
+        assert!(slices(vec![vec![[0., 0., 10.], [1., 1., 10.]]]).is_empty());
+    }
+
+    #[test]
+    fn labels_overlaps_with_the_smallest_roi() {
+        let image = grid((4, 4, 1), [1., 1., 1.], [0., 0., 0.]);
+        let rois = [
+            roi(1, "Target", vec![rectangle([0.5, 0.5], [2.5, 2.5], 0.)]),
+            roi(2, "Body", vec![rectangle([-0.5, -0.5], [2.5, 2.5], 0.)]),
+        ];
+        let labels = label_volume(&image, &rois);
+        assert_eq!((labels.columns, labels.rows, labels.slices), (4, 4, 1));
+        assert_eq!(labels.colors, [[1, 128, 255], [2, 128, 255]]);
+        #[rustfmt::skip]
+        let expected = [
+            2, 2, 2, 0,
+            2, 1, 1, 0,
+            2, 1, 1, 0,
+            0, 0, 0, 0,
+        ];
+        assert_eq!(labels.labels, expected);
+    }
+
+    #[test]
+    fn finds_and_loads_structure_sets_by_frame_of_reference() {
+        let dir = temp_dir("rtstruct");
+        let rois = [
+            roi(3, "PTV", vec![rectangle([0., 0.], [1., 1.], 2.)]),
+            roi(
+                7,
+                "Brain",
+                vec![
+                    rectangle([0., 0.], [2., 2.], 0.),
+                    rectangle([0., 0.], [3., 3.], 2.),
+                ],
+            ),
+        ];
+        let path = dir.join("RS.dcm");
+        write_structure_set(&path, FRAME_OF_REFERENCE_UID, &rois);
+
+        assert_eq!(
+            find_structure_sets(&dir, FRAME_OF_REFERENCE_UID).unwrap(),
+            std::slice::from_ref(&path)
+        );
+        assert!(find_structure_sets(&dir, "1.2.3").unwrap().is_empty());
+
+        let structure_set = load_structure_set(&path).unwrap();
+        assert_eq!(structure_set.label, "Test");
+        assert_eq!(structure_set.frame_of_reference_uid, FRAME_OF_REFERENCE_UID);
+        assert_eq!(structure_set.rois.len(), 2);
+        for (loaded, roi) in structure_set.rois.iter().zip(&rois) {
+            assert_eq!(loaded.number, roi.number);
+            assert_eq!(loaded.name, roi.name);
+            assert_eq!(loaded.interpreted_type, roi.interpreted_type);
+            assert_eq!(loaded.color, roi.color);
+            assert_eq!(loaded.contours, roi.contours);
+        }
+    }
+}
@@ -31,6 +31,38 @@ struct Out {
   @group(0) @binding(0) var<uniform> uniforms: Uniforms;
   @group(0) @binding(1) var volumeTexture: texture_3d<f32>;
   @group(0) @binding(2) var volumeSampler: sampler;
+  // maps the normalized patient space cube [-.5,.5]^3 to texture coordinates
+  @group(0) @binding(3) var<uniform> worldToTexture: mat4x4f;
+  // label of each voxel, zero where nothing is overlaid
+  @group(1) @binding(0) var labelTexture: texture_3d<u32>;
+  @group(1) @binding(1) var<uniform> labelColors: array<vec4f, 256>;
+  // dose on the grid of the volume, coloured between the thresholds
+  @group(2) @binding(0) var doseTexture: texture_3d<f32>;
+  @group(2) @binding(1) var<uniform> doseThresholds: vec2f;
+
+  // opacity added by each step through a labelled voxel
+  const labelOpacity = .04;
+
+  fn overlay (point: vec3f) -> vec4f {
+    let dims = textureDimensions(labelTexture);
+    let voxel = min(vec3u(point * vec3f(dims)), dims - 1);
+    let color = labelColors[textureLoad(labelTexture, voxel, 0).r];
+    return vec4f(color.rgb, 1.) * color.a * labelOpacity;
+  }
+
+  // opacity added by each step through a dose above the lowest threshold
+  const doseOpacity = .02;
+
+  fn doseWash (point: vec3f) -> vec4f {
+    let dose = textureSample(doseTexture, volumeSampler, point).r;
+    if dose < doseThresholds.x {
+      return vec4f(0.);
+    }
+    // jet colour map, blue at the lowest threshold and red at the highest
+    let t = clamp((dose - doseThresholds.x) / max(doseThresholds.y - doseThresholds.x, 1e-6), 0., 1.);
+    let color = clamp(1.5 - abs(4. * t - vec3f(3., 2., 1.)), vec3f(0.), vec3f(1.));
+    return vec4f(color, 1.) * doseOpacity;
+  }
 
 
 
@@ -76,24 +108,10 @@ struct Out {
   @fragment fn fs_main(@location(0) pos: vec2f) -> @location(0) vec4f {
     let uInput = uniforms.rotation;
 
-    let center = vec2f(.5, .5);
-
-    var ray = vec3f((pos.x + 1.) / 2., uniforms.slice, (pos.y+1.)/2.);
+    // look from anterior to posterior, patient left to the right and superior up
+    var ray = vec3f(pos.x / 2., uniforms.slice - .5, pos.y / 2.);
     let ds = 1./512.;
 
-    let translate = mat4x4f(
-              1, 0, 0, 0,
-              0, 1, 0, 0,
-              0, 0, 1, 0,
-              -center.x, -center.y, 0, 1,
-    );
-    let invtranslate = mat4x4f(
-              1, 0, 0, 0,
-              0, 1, 0, 0,
-              0, 0, 1, 0,
-              center.x, center.y, 0, 1,
-    );
-
     let rotate = mat4x4f(
             cos(uInput), -sin(uInput),  0, 0,
             sin(uInput), cos(uInput),   0, 0,
@@ -101,13 +119,13 @@ struct Out {
             0, 0, 0, 1
     );
 
-    let M = invtranslate * rotate * translate;
+    let M = worldToTexture * rotate;
 
     let rayStep = vec3f(0., ds, 0.);
 
     var outColor = vec4f(0);
 
-    let light = (M * normalize(vec4f(1,0,0,0))).xyz;
+    let light = normalize((M * vec4f(1,0,0,0)).xyz);
 
     for (var i=0; i<512; i++) {
 
@@ -119,7 +137,9 @@ struct Out {
         textureSample(volumeTexture, volumeSampler, point.xyz + vec3f(0,ds,0)).r - hu,
         textureSample(volumeTexture, volumeSampler, point.xyz + vec3f(0,0,ds)).r - hu
       );
-      let c = transfer(hu, light.xyz, dhu);
+      // the cube is larger than the volume along all but its longest side
+      let inside = all(point.xyz >= vec3f(0.)) && all(point.xyz <= vec3f(1.));
+      let c = (transfer(hu, light.xyz, dhu) + overlay(point.xyz) + doseWash(point.xyz)) * f32(inside);
       outColor = outColor + (1. - outColor.a) * c;
 
       ray += rayStep;
@@ -0,0 +1,585 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+use dicom::transfer_syntax::entries::IMPLICIT_VR_LITTLE_ENDIAN;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom::ul::association::server::AcceptAny;
+use dicom::ul::pdu::{PDataValue, PDataValueType};
+use dicom::ul::{Pdu, ServerAssociation, ServerAssociationOptions};
+use dicom_dictionary_std::{tags, uids};
+
+use crate::dicom_series::{self, DicomSeries, IMAGE_SOP_CLASSES};
+
+const C_STORE_RQ: u16 = 0x0001;
+const C_STORE_RSP: u16 = 0x8001;
+const C_ECHO_RQ: u16 = 0x0030;
+const C_ECHO_RSP: u16 = 0x8030;
+/// Command data set type of messages without a data set
+const NO_DATA_SET: u16 = 0x0101;
+
+const STATUS_SUCCESS: u16 = 0x0000;
+const STATUS_OUT_OF_RESOURCES: u16 = 0xA700;
+const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+
+/// How often pending series are checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone)]
+pub struct ScpOptions {
+    /// Title this application entity answers to
+    pub ae_title: String,
+    /// Received instances are stored under `<study uid>/<series uid>` here
+    pub storage_dir: PathBuf,
+    /// A series is complete once the associations sending it have ended and
+    /// no instance of it has arrived for this long
+    pub series_timeout: Duration,
+    /// Associations idle for longer than this are dropped
+    pub association_timeout: Duration,
+}
+
+impl Default for ScpOptions {
+    fn default() -> Self {
+        ScpOptions {
+            ae_title: "VOLUME-VIEWER".to_string(),
+            storage_dir: PathBuf::from("received"),
+            series_timeout: Duration::from_secs(2),
+            association_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A series still receiving instances.
+struct PendingSeries {
+    dir: PathBuf,
+    /// Open associations that have sent instances of the series
+    associations: usize,
+    last_received: Instant,
+}
+
+/// Series being received, by study and series instance UID.
+type Pending = Arc<Mutex<HashMap<(String, String), PendingSeries>>>;
+
+/// DIMSE Storage service class provider accepting C-STORE and C-ECHO
+/// requests for the image SOP classes that can be loaded as volumes, and
+/// for the structure sets and dose drawn on them.
+pub struct StorageScp {
+    listener: TcpListener,
+    options: ScpOptions,
+}
+
+impl StorageScp {
+    pub fn bind<A: ToSocketAddrs>(addr: A, options: ScpOptions) -> Result<Self> {
+        let listener = TcpListener::bind(addr).context("Failed to listen for associations")?;
+        Ok(StorageScp { listener, options })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept associations until the listener fails, storing the instances
+    /// received. Each series is grouped and handed to `on_series` once
+    /// complete, on the calling thread.
+    pub fn run<F: FnMut(DicomSeries)>(self, mut on_series: F) -> Result<()> {
+        let mut association_options = ServerAssociationOptions::new()
+            .accept_any()
+            .ae_title(self.options.ae_title.clone())
+            .timeout(self.options.association_timeout)
+            .with_abstract_syntax(uids::VERIFICATION);
+        for uid in IMAGE_SOP_CLASSES
+            .iter()
+            .chain(&[uids::RT_STRUCTURE_SET_STORAGE, uids::RT_DOSE_STORAGE])
+        {
+            association_options = association_options.with_abstract_syntax(*uid);
+        }
+        let association_options = Arc::new(association_options);
+
+        let pending: Pending = Arc::default();
+        let listener = self.listener;
+        let storage_dir = self.options.storage_dir.clone();
+        let accepting = {
+            let pending = pending.clone();
+            thread::spawn(move || -> Result<()> {
+                for stream in listener.incoming() {
+                    let stream = stream?;
+                    let association_options = association_options.clone();
+                    let storage_dir = storage_dir.clone();
+                    let pending = pending.clone();
+                    thread::spawn(move || {
+                        let peer = stream.peer_addr().map(|addr| addr.to_string());
+                        if let Err(error) =
+                            handle_association(stream, &association_options, storage_dir, &pending)
+                        {
+                            eprintln!(
+                                "Association with {} failed: {:#}",
+                                peer.unwrap_or_default(),
+                                error
+                            );
+                        }
+                    });
+                }
+                Ok(())
+            })
+        };
+
+        while !accepting.is_finished() {
+            thread::sleep(POLL_INTERVAL);
+            for dir in take_complete(&pending, self.options.series_timeout) {
+                match dicom_series::find_series(&dir) {
+                    Ok(series) => series.into_iter().for_each(&mut on_series),
+                    Err(error) => eprintln!("Failed to group {}: {:#}", dir.display(), error),
+                }
+            }
+        }
+
+        accepting
+            .join()
+            .map_err(|_| anyhow!("Listener thread panicked"))?
+    }
+}
+
+/// Remove and return the folders of the series that are complete.
+fn take_complete(pending: &Pending, series_timeout: Duration) -> Vec<PathBuf> {
+    let mut pending = pending.lock().unwrap();
+    let complete: Vec<(String, String)> = pending
+        .iter()
+        .filter(|(_, series)| {
+            series.associations == 0 && series.last_received.elapsed() >= series_timeout
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+    complete
+        .into_iter()
+        .filter_map(|key| pending.remove(&key))
+        .map(|series| series.dir)
+        .collect()
+}
+
+fn handle_association(
+    stream: TcpStream,
+    options: &ServerAssociationOptions<'static, AcceptAny>,
+    storage_dir: PathBuf,
+    pending: &Pending,
+) -> Result<()> {
+    let mut association = options.establish(stream)?;
+    let mut received = HashSet::new();
+    let result = receive_messages(&mut association, &storage_dir, pending, &mut received);
+
+    // The series sent may now complete
+    let mut pending = pending.lock().unwrap();
+    for key in received {
+        if let Some(series) = pending.get_mut(&key) {
+            series.associations -= 1;
+            series.last_received = Instant::now();
+        }
+    }
+    result
+}
+
+/// Answer DIMSE requests until the association is released or aborted,
+/// collecting the series that instances were received for.
+fn receive_messages(
+    association: &mut ServerAssociation<TcpStream>,
+    storage_dir: &Path,
+    pending: &Pending,
+    received: &mut HashSet<(String, String)>,
+) -> Result<()> {
+    let mut command_bytes = Vec::new();
+    let mut data_bytes = Vec::new();
+    let mut command: Option<(u8, InMemDicomObject)> = None;
+
+    loop {
+        match association.receive()? {
+            Pdu::PData { data } => {
+                for value in data {
+                    let context_id = value.presentation_context_id;
+                    match value.value_type {
+                        PDataValueType::Command => {
+                            command_bytes.extend_from_slice(&value.data);
+                            if !value.is_last {
+                                continue;
+                            }
+                            let obj = InMemDicomObject::read_dataset_with_ts(
+                                command_bytes.as_slice(),
+                                &IMPLICIT_VR_LITTLE_ENDIAN.erased(),
+                            )?;
+                            command_bytes.clear();
+                            if read_u16(&obj, tags::COMMAND_DATA_SET_TYPE)? == NO_DATA_SET {
+                                answer(association, context_id, &obj, None)?;
+                            } else {
+                                command = Some((context_id, obj));
+                            }
+                        }
+                        PDataValueType::Data => {
+                            data_bytes.extend_from_slice(&value.data);
+                            if !value.is_last {
+                                continue;
+                            }
+                            let (context_id, obj) = command
+                                .take()
+                                .ok_or_else(|| anyhow!("Data set received without a command"))?;
+                            let status = store(
+                                association,
+                                context_id,
+                                &data_bytes,
+                                storage_dir,
+                                pending,
+                                received,
+                            );
+                            data_bytes.clear();
+                            answer(association, context_id, &obj, Some(status))?;
+                        }
+                    }
+                }
+            }
+            Pdu::ReleaseRQ => {
+                association.send(&Pdu::ReleaseRP)?;
+                return Ok(());
+            }
+            Pdu::AbortRQ { .. } => return Ok(()),
+            pdu => return Err(anyhow!("Unexpected {}", pdu.short_description())),
+        }
+    }
+}
+
+/// Store a received data set, returning the C-STORE status.
+fn store(
+    association: &ServerAssociation<TcpStream>,
+    context_id: u8,
+    data: &[u8],
+    storage_dir: &Path,
+    pending: &Pending,
+    received: &mut HashSet<(String, String)>,
+) -> u16 {
+    let transfer_syntax = association
+        .presentation_contexts()
+        .iter()
+        .find(|context| context.id == context_id)
+        .and_then(|context| TransferSyntaxRegistry.get(&context.transfer_syntax));
+    let Some(transfer_syntax) = transfer_syntax else {
+        return STATUS_CANNOT_UNDERSTAND;
+    };
+    let Ok(obj) = InMemDicomObject::read_dataset_with_ts(data, transfer_syntax) else {
+        return STATUS_CANNOT_UNDERSTAND;
+    };
+
+    // The UIDs name the files, so anything else could escape the storage
+    // folder
+    let study = read_uid(&obj, tags::STUDY_INSTANCE_UID);
+    let series = read_uid(&obj, tags::SERIES_INSTANCE_UID);
+    let instance = read_uid(&obj, tags::SOP_INSTANCE_UID);
+    if let Some(uid) = [&study, &series, &instance]
+        .into_iter()
+        .find(|uid| !is_valid_uid(uid))
+    {
+        eprintln!("Refused an instance with the invalid UID {:?}", uid);
+        return STATUS_CANNOT_UNDERSTAND;
+    }
+
+    let dir = storage_dir.join(&study).join(&series);
+    let path = dir.join(format!("{}.dcm", instance));
+    let key = (study, series);
+    let written = std::fs::create_dir_all(&dir)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| {
+            obj.with_meta(FileMetaTableBuilder::new().transfer_syntax(transfer_syntax.uid()))?
+                .write_to_file(&path)?;
+            Ok(())
+        });
+    if let Err(error) = written {
+        eprintln!("Failed to store {}: {:#}", path.display(), error);
+        return STATUS_OUT_OF_RESOURCES;
+    }
+
+    let mut pending = pending.lock().unwrap();
+    let series = pending.entry(key.clone()).or_insert_with(|| PendingSeries {
+        dir,
+        associations: 0,
+        last_received: Instant::now(),
+    });
+    if received.insert(key) {
+        series.associations += 1;
+    }
+    series.last_received = Instant::now();
+    STATUS_SUCCESS
+}
+
+/// Send the response to a C-ECHO or C-STORE request.
+fn answer(
+    association: &mut ServerAssociation<TcpStream>,
+    context_id: u8,
+    request: &InMemDicomObject,
+    status: Option<u16>,
+) -> Result<()> {
+    let command_field = match read_u16(request, tags::COMMAND_FIELD)? {
+        C_ECHO_RQ => C_ECHO_RSP,
+        C_STORE_RQ => C_STORE_RSP,
+        other => return Err(anyhow!("Unsupported DIMSE command {:#06x}", other)),
+    };
+    let message_id = read_u16(request, tags::MESSAGE_ID)?;
+    let sop_class_uid = read_uid(request, tags::AFFECTED_SOP_CLASS_UID);
+
+    let mut elements = vec![
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            PrimitiveValue::from(sop_class_uid),
+        ),
+        DataElement::new(
+            tags::COMMAND_FIELD,
+            VR::US,
+            PrimitiveValue::from(command_field),
+        ),
+        DataElement::new(
+            tags::MESSAGE_ID_BEING_RESPONDED_TO,
+            VR::US,
+            PrimitiveValue::from(message_id),
+        ),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            PrimitiveValue::from(NO_DATA_SET),
+        ),
+        DataElement::new(
+            tags::STATUS,
+            VR::US,
+            PrimitiveValue::from(status.unwrap_or(STATUS_SUCCESS)),
+        ),
+    ];
+    if command_field == C_STORE_RSP {
+        elements.push(DataElement::new(
+            tags::AFFECTED_SOP_INSTANCE_UID,
+            VR::UI,
+            PrimitiveValue::from(read_uid(request, tags::AFFECTED_SOP_INSTANCE_UID)),
+        ));
+    }
+
+    let mut data = Vec::new();
+    InMemDicomObject::command_from_element_iter(elements)
+        .write_dataset_with_ts(&mut data, &IMPLICIT_VR_LITTLE_ENDIAN.erased())?;
+    association.send(&Pdu::PData {
+        data: vec![PDataValue {
+            presentation_context_id: context_id,
+            value_type: PDataValueType::Command,
+            is_last: true,
+            data,
+        }],
+    })?;
+    Ok(())
+}
+
+/// Whether a UID is made of at most 64 digits and dots, with no empty
+/// component.
+fn is_valid_uid(uid: &str) -> bool {
+    uid.len() <= 64
+        && uid
+            .split('.')
+            .all(|component| !component.is_empty() && component.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn read_u16(obj: &InMemDicomObject, tag: Tag) -> Result<u16> {
+    Ok(obj
+        .element(tag)
+        .with_context(|| format!("Missing {} in command", tag))?
+        .uint16()?)
+}
+
+fn read_uid(obj: &InMemDicomObject, tag: Tag) -> String {
+    obj.element(tag)
+        .ok()
+        .and_then(|elem| elem.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        put, temp_dir, write_ct_series, SliceGeometry, SERIES_INSTANCE_UID, STUDY_INSTANCE_UID,
+    };
+    use dicom::object::open_file;
+    use dicom::ul::{ClientAssociation, ClientAssociationOptions};
+    use std::sync::mpsc;
+
+    #[test]
+    fn validates_uids() {
+        assert!(is_valid_uid("1.2.840.10008.5.1.4.1.1.2"));
+        assert!(is_valid_uid(&format!("2.25.{}", "1".repeat(59))));
+        assert!(!is_valid_uid(&format!("2.25.{}", "1".repeat(60))));
+        for uid in [
+            "", ".", "..", "1..2", "1.2.", "../1.2", "1.2/3", "1.2\\3", "1.2 ",
+        ] {
+            assert!(!is_valid_uid(uid), "{:?}", uid);
+        }
+    }
+
+    /// Send a request, with its data set if any, and return the status of
+    /// the response.
+    fn request(
+        association: &mut ClientAssociation<TcpStream>,
+        context_id: u8,
+        command: Vec<DataElement<InMemDicomObject>>,
+        data_set: Option<Vec<u8>>,
+    ) -> u16 {
+        let mut data = Vec::new();
+        InMemDicomObject::command_from_element_iter(command)
+            .write_dataset_with_ts(&mut data, &IMPLICIT_VR_LITTLE_ENDIAN.erased())
+            .unwrap();
+        let mut values = vec![PDataValue {
+            presentation_context_id: context_id,
+            value_type: PDataValueType::Command,
+            is_last: true,
+            data,
+        }];
+        if let Some(data) = data_set {
+            values.push(PDataValue {
+                presentation_context_id: context_id,
+                value_type: PDataValueType::Data,
+                is_last: true,
+                data,
+            });
+        }
+        association.send(&Pdu::PData { data: values }).unwrap();
+        let Pdu::PData { data } = association.receive().unwrap() else {
+            panic!("Expected a response");
+        };
+        let response = InMemDicomObject::read_dataset_with_ts(
+            data[0].data.as_slice(),
+            &IMPLICIT_VR_LITTLE_ENDIAN.erased(),
+        )
+        .unwrap();
+        read_u16(&response, tags::STATUS).unwrap()
+    }
+
+    fn command(
+        field: u16,
+        message_id: u16,
+        sop_class: &str,
+        data_set_type: u16,
+    ) -> Vec<DataElement<InMemDicomObject>> {
+        vec![
+            DataElement::new(
+                tags::AFFECTED_SOP_CLASS_UID,
+                VR::UI,
+                PrimitiveValue::from(sop_class),
+            ),
+            DataElement::new(tags::COMMAND_FIELD, VR::US, PrimitiveValue::from(field)),
+            DataElement::new(tags::MESSAGE_ID, VR::US, PrimitiveValue::from(message_id)),
+            DataElement::new(
+                tags::COMMAND_DATA_SET_TYPE,
+                VR::US,
+                PrimitiveValue::from(data_set_type),
+            ),
+        ]
+    }
+
+    fn store_request(
+        association: &mut ClientAssociation<TcpStream>,
+        context_id: u8,
+        message_id: u16,
+        obj: &InMemDicomObject,
+    ) -> u16 {
+        let mut command = command(C_STORE_RQ, message_id, uids::CT_IMAGE_STORAGE, 0x0000);
+        command.push(DataElement::new(
+            tags::AFFECTED_SOP_INSTANCE_UID,
+            VR::UI,
+            PrimitiveValue::from(read_uid(obj, tags::SOP_INSTANCE_UID)),
+        ));
+        let mut data = Vec::new();
+        obj.write_dataset_with_ts(&mut data, &IMPLICIT_VR_LITTLE_ENDIAN.erased())
+            .unwrap();
+        request(association, context_id, command, Some(data))
+    }
+
+    #[test]
+    fn stores_series_pushed_over_an_association() {
+        let dir = temp_dir("storage-scp");
+        let geometry = SliceGeometry {
+            columns: 4,
+            rows: 3,
+            pixel_spacing: [0.5, 0.5],
+            position: [0., 0., 0.],
+            orientation: [1., 0., 0., 0., 1., 0.],
+        };
+        let files = write_ct_series(&dir, 2, &geometry);
+        let storage_dir = dir.join("received");
+        let options = ScpOptions {
+            storage_dir: storage_dir.clone(),
+            series_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let scp = StorageScp::bind("127.0.0.1:0", options).unwrap();
+        let addr = scp.local_addr().unwrap();
+        let (sender, series) = mpsc::channel();
+        thread::spawn(move || scp.run(|series| sender.send(series).unwrap()));
+
+        let mut association = ClientAssociationOptions::new()
+            .called_ae_title("VOLUME-VIEWER")
+            .with_presentation_context(uids::VERIFICATION, vec![IMPLICIT_VR_LITTLE_ENDIAN.uid()])
+            .with_presentation_context(
+                uids::CT_IMAGE_STORAGE,
+                vec![IMPLICIT_VR_LITTLE_ENDIAN.uid()],
+            )
+            .establish(addr)
+            .unwrap();
+        let contexts: Vec<u8> = association
+            .presentation_contexts()
+            .iter()
+            .map(|context| context.id)
+            .collect();
+        let echo = command(C_ECHO_RQ, 1, uids::VERIFICATION, NO_DATA_SET);
+        assert_eq!(
+            request(&mut association, contexts[0], echo, None),
+            STATUS_SUCCESS
+        );
+
+        let objects: Vec<_> = files.iter().map(|file| open_file(file).unwrap()).collect();
+        for (message_id, obj) in (2..).zip(&objects) {
+            let status = store_request(&mut association, contexts[1], message_id, obj);
+            assert_eq!(status, STATUS_SUCCESS);
+        }
+
+        // Instances whose UIDs could name a path outside the storage folder
+        // are refused before anything is written
+        let mut escaping = (*objects[0]).clone();
+        let uid = PrimitiveValue::from("../../escaped");
+        put(&mut escaping, tags::SERIES_INSTANCE_UID, VR::UI, uid);
+        let status = store_request(&mut association, contexts[1], 9, &escaping);
+        assert_eq!(status, STATUS_CANNOT_UNDERSTAND);
+        assert!(!dir.join("escaped").exists());
+        association.release().unwrap();
+
+        let received = series.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(received.study_instance_uid, STUDY_INSTANCE_UID);
+        assert_eq!(received.series_instance_uid, SERIES_INSTANCE_UID);
+        assert_eq!(received.modality, "CT");
+        assert_eq!(received.files.len(), 2);
+        for obj in &objects {
+            let path = storage_dir
+                .join(STUDY_INSTANCE_UID)
+                .join(SERIES_INSTANCE_UID)
+                .join(format!("{}.dcm", read_uid(obj, tags::SOP_INSTANCE_UID)));
+            assert!(received.files.contains(&path), "{}", path.display());
+            let stored = open_file(&path).unwrap();
+            let pixels = |obj: &InMemDicomObject| {
+                obj.element(tags::PIXEL_DATA)
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap()
+                    .to_vec()
+            };
+            assert_eq!(pixels(&stored), pixels(obj));
+        }
+        let (volume, _) = received.load(&Default::default()).unwrap();
+        assert_eq!((volume.columns, volume.rows, volume.slices), (4, 3, 2));
+    }
+}
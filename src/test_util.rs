@@ -0,0 +1,390 @@
+//! Helpers shared by the unit tests.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use dicom::core::value::DataSetSequence;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::object::mem::InMemElement;
+use dicom::object::{DefaultDicomObject, FileMetaTableBuilder, InMemDicomObject};
+use dicom_dictionary_std::{tags, uids};
+
+use crate::image_volume::{ImageVolume, Vec3};
+use crate::rtstruct::Roi;
+
+pub const STUDY_INSTANCE_UID: &str = "1.2.826.0.1.3680043.2.1125.1";
+pub const SERIES_INSTANCE_UID: &str = "1.2.826.0.1.3680043.2.1125.2";
+pub const FRAME_OF_REFERENCE_UID: &str = "1.2.826.0.1.3680043.2.1125.3";
+
+/// Empty directory for a test, unique to the test run.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("volume-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn put(obj: &mut InMemDicomObject, tag: dicom::core::Tag, vr: VR, value: PrimitiveValue) {
+    obj.put(DataElement::new(tag, vr, value));
+}
+
+pub fn strings<T: ToString>(values: &[T]) -> PrimitiveValue {
+    PrimitiveValue::Strs(values.iter().map(|value| value.to_string()).collect())
+}
+
+/// Geometry of a synthetic image slice. `pixel_spacing` is given as in
+/// DICOM, between rows first.
+#[derive(Clone, Copy)]
+pub struct SliceGeometry {
+    pub columns: u16,
+    pub rows: u16,
+    pub pixel_spacing: [f32; 2],
+    pub position: Vec3,
+    pub orientation: [f32; 6],
+}
+
+/// A signed 16 bit CT slice with an intercept of -1024, holding `stored`
+/// values row by row.
+pub fn ct_slice(index: usize, geometry: &SliceGeometry, stored: &[i16]) -> InMemDicomObject {
+    let mut obj = InMemDicomObject::new_empty();
+    let sop_instance_uid = format!("{}.{}", SERIES_INSTANCE_UID, index + 1);
+    put(
+        &mut obj,
+        tags::SOP_CLASS_UID,
+        VR::UI,
+        uids::CT_IMAGE_STORAGE.into(),
+    );
+    put(
+        &mut obj,
+        tags::SOP_INSTANCE_UID,
+        VR::UI,
+        sop_instance_uid.into(),
+    );
+    put(&mut obj, tags::PATIENT_NAME, VR::PN, "Doe^Jane".into());
+    put(&mut obj, tags::PATIENT_ID, VR::LO, "12345".into());
+    put(&mut obj, tags::STUDY_DATE, VR::DA, "20200229".into());
+    put(&mut obj, tags::MODALITY, VR::CS, "CT".into());
+    put(
+        &mut obj,
+        tags::STUDY_INSTANCE_UID,
+        VR::UI,
+        STUDY_INSTANCE_UID.into(),
+    );
+    put(
+        &mut obj,
+        tags::SERIES_INSTANCE_UID,
+        VR::UI,
+        SERIES_INSTANCE_UID.into(),
+    );
+    put(
+        &mut obj,
+        tags::FRAME_OF_REFERENCE_UID,
+        VR::UI,
+        FRAME_OF_REFERENCE_UID.into(),
+    );
+    put(
+        &mut obj,
+        tags::INSTANCE_NUMBER,
+        VR::IS,
+        (index + 1).to_string().into(),
+    );
+    put(
+        &mut obj,
+        tags::IMAGE_POSITION_PATIENT,
+        VR::DS,
+        strings(&geometry.position),
+    );
+    put(
+        &mut obj,
+        tags::IMAGE_ORIENTATION_PATIENT,
+        VR::DS,
+        strings(&geometry.orientation),
+    );
+    put(
+        &mut obj,
+        tags::PIXEL_SPACING,
+        VR::DS,
+        strings(&geometry.pixel_spacing),
+    );
+    put(
+        &mut obj,
+        tags::SAMPLES_PER_PIXEL,
+        VR::US,
+        PrimitiveValue::from(1u16),
+    );
+    put(
+        &mut obj,
+        tags::PHOTOMETRIC_INTERPRETATION,
+        VR::CS,
+        "MONOCHROME2".into(),
+    );
+    put(
+        &mut obj,
+        tags::ROWS,
+        VR::US,
+        PrimitiveValue::from(geometry.rows),
+    );
+    put(
+        &mut obj,
+        tags::COLUMNS,
+        VR::US,
+        PrimitiveValue::from(geometry.columns),
+    );
+    put(
+        &mut obj,
+        tags::BITS_ALLOCATED,
+        VR::US,
+        PrimitiveValue::from(16u16),
+    );
+    put(
+        &mut obj,
+        tags::BITS_STORED,
+        VR::US,
+        PrimitiveValue::from(16u16),
+    );
+    put(
+        &mut obj,
+        tags::HIGH_BIT,
+        VR::US,
+        PrimitiveValue::from(15u16),
+    );
+    put(
+        &mut obj,
+        tags::PIXEL_REPRESENTATION,
+        VR::US,
+        PrimitiveValue::from(1u16),
+    );
+    put(&mut obj, tags::RESCALE_INTERCEPT, VR::DS, "-1024".into());
+    put(&mut obj, tags::RESCALE_SLOPE, VR::DS, "1".into());
+    put(
+        &mut obj,
+        tags::PIXEL_DATA,
+        VR::OW,
+        PrimitiveValue::I16(stored.into()),
+    );
+    obj
+}
+
+/// Add a file meta group with the given transfer syntax.
+pub fn with_meta(obj: InMemDicomObject, transfer_syntax: &str) -> DefaultDicomObject {
+    obj.with_meta(FileMetaTableBuilder::new().transfer_syntax(transfer_syntax))
+        .unwrap()
+}
+
+/// Write `obj` as an explicit VR little endian file.
+pub fn write_file(obj: InMemDicomObject, path: &Path) {
+    with_meta(obj, uids::EXPLICIT_VR_LITTLE_ENDIAN)
+        .write_to_file(path)
+        .unwrap();
+}
+
+/// Write an axial series of `slices` slices 2 mm apart to `dir`, slice `k`
+/// holding the stored value `k * 10 + i` at column `i`.
+pub fn write_ct_series(dir: &Path, slices: usize, geometry: &SliceGeometry) -> Vec<PathBuf> {
+    std::fs::create_dir_all(dir).unwrap();
+    let size = geometry.columns as usize * geometry.rows as usize;
+    (0..slices)
+        .map(|k| {
+            let position = [
+                geometry.position[0],
+                geometry.position[1],
+                geometry.position[2] + 2. * k as f32,
+            ];
+            let stored: Vec<i16> = (0..size)
+                .map(|index| (k * 10 + index % geometry.columns as usize) as i16)
+                .collect();
+            let slice = ct_slice(
+                k,
+                &SliceGeometry {
+                    position,
+                    ..*geometry
+                },
+                &stored,
+            );
+            let path = dir.join(format!("CT{}.dcm", k + 1));
+            write_file(slice, &path);
+            path
+        })
+        .collect()
+}
+
+/// Write an 8 bit grayscale PNG.
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) {
+    let mut encoder = png::Encoder::new(File::create(path).unwrap(), width, height);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder
+        .write_header()
+        .unwrap()
+        .write_image_data(pixels)
+        .unwrap();
+}
+
+/// An axis aligned volume of zeros on the test frame of reference.
+pub fn grid(size: (u16, u16, usize), spacing: Vec3, origin: Vec3) -> ImageVolume {
+    let (columns, rows, slices) = size;
+    ImageVolume {
+        columns,
+        rows,
+        slices,
+        pixel_spacing: spacing,
+        position_patient: origin,
+        image_orientation_patient: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+        volume: vec![0.; columns as usize * rows as usize * slices],
+        metadata: crate::image_volume::VolumeMetadata {
+            frame_of_reference_uid: FRAME_OF_REFERENCE_UID.to_string(),
+            ..Default::default()
+        },
+    }
+}
+
+pub fn sequence(tag: dicom::core::Tag, items: Vec<InMemDicomObject>) -> InMemElement {
+    DataElement::new(tag, VR::SQ, DataSetSequence::from(items))
+}
+
+/// Write an RT Structure Set of closed planar contours drawn on
+/// `frame_of_reference_uid`.
+pub fn write_structure_set(path: &Path, frame_of_reference_uid: &str, rois: &[Roi]) {
+    let mut obj = InMemDicomObject::new_empty();
+    let class = uids::RT_STRUCTURE_SET_STORAGE;
+    put(&mut obj, tags::SOP_CLASS_UID, VR::UI, class.into());
+    let instance = format!("{}.100", SERIES_INSTANCE_UID);
+    put(&mut obj, tags::SOP_INSTANCE_UID, VR::UI, instance.into());
+    put(&mut obj, tags::MODALITY, VR::CS, "RTSTRUCT".into());
+    let study = STUDY_INSTANCE_UID;
+    put(&mut obj, tags::STUDY_INSTANCE_UID, VR::UI, study.into());
+    let series = format!("{}.99", SERIES_INSTANCE_UID);
+    put(&mut obj, tags::SERIES_INSTANCE_UID, VR::UI, series.into());
+    put(&mut obj, tags::STRUCTURE_SET_LABEL, VR::SH, "Test".into());
+
+    let mut frame = InMemDicomObject::new_empty();
+    let uid = frame_of_reference_uid;
+    put(&mut frame, tags::FRAME_OF_REFERENCE_UID, VR::UI, uid.into());
+    obj.put(sequence(
+        tags::REFERENCED_FRAME_OF_REFERENCE_SEQUENCE,
+        vec![frame],
+    ));
+
+    // Items of the ROI sequences, numbered by `tag`
+    let item = |roi: &Roi, tag| {
+        let mut item = InMemDicomObject::new_empty();
+        put(&mut item, tag, VR::IS, roi.number.to_string().into());
+        item
+    };
+    let structures = rois
+        .iter()
+        .map(|roi| {
+            let mut item = item(roi, tags::ROI_NUMBER);
+            put(&mut item, tags::ROI_NAME, VR::LO, roi.name.as_str().into());
+            item
+        })
+        .collect();
+    obj.put(sequence(tags::STRUCTURE_SET_ROI_SEQUENCE, structures));
+    let contours = rois
+        .iter()
+        .map(|roi| {
+            let mut item = item(roi, tags::REFERENCED_ROI_NUMBER);
+            put(
+                &mut item,
+                tags::ROI_DISPLAY_COLOR,
+                VR::IS,
+                strings(&roi.color),
+            );
+            let contours = roi
+                .contours
+                .iter()
+                .map(|points| {
+                    let mut contour = InMemDicomObject::new_empty();
+                    let geometric_type = PrimitiveValue::from("CLOSED_PLANAR");
+                    put(
+                        &mut contour,
+                        tags::CONTOUR_GEOMETRIC_TYPE,
+                        VR::CS,
+                        geometric_type,
+                    );
+                    let count = PrimitiveValue::from(points.len().to_string());
+                    put(&mut contour, tags::NUMBER_OF_CONTOUR_POINTS, VR::IS, count);
+                    let data = strings(&points.concat());
+                    put(&mut contour, tags::CONTOUR_DATA, VR::DS, data);
+                    contour
+                })
+                .collect();
+            item.put(sequence(tags::CONTOUR_SEQUENCE, contours));
+            item
+        })
+        .collect();
+    obj.put(sequence(tags::ROI_CONTOUR_SEQUENCE, contours));
+    let observations = rois
+        .iter()
+        .map(|roi| {
+            let mut item = item(roi, tags::REFERENCED_ROI_NUMBER);
+            let interpreted_type = roi.interpreted_type.as_str().into();
+            put(
+                &mut item,
+                tags::RTROI_INTERPRETED_TYPE,
+                VR::CS,
+                interpreted_type,
+            );
+            item
+        })
+        .collect();
+    obj.put(sequence(tags::RTROI_OBSERVATIONS_SEQUENCE, observations));
+    write_file(obj, path);
+}
+
+/// Write a multi-frame RT Dose on the test frame of reference, frame `k`
+/// lying `offsets[k]` along the normal and holding `stored` values scaled
+/// by `scaling`.
+pub fn write_rt_dose(
+    path: &Path,
+    geometry: &SliceGeometry,
+    offsets: &[f32],
+    stored: &[u32],
+    scaling: f32,
+) {
+    let mut obj = ct_slice(0, geometry, &[]);
+    for tag in [
+        tags::RESCALE_INTERCEPT,
+        tags::RESCALE_SLOPE,
+        tags::INSTANCE_NUMBER,
+    ] {
+        obj.remove_element(tag);
+    }
+    put(
+        &mut obj,
+        tags::SOP_CLASS_UID,
+        VR::UI,
+        uids::RT_DOSE_STORAGE.into(),
+    );
+    put(&mut obj, tags::MODALITY, VR::CS, "RTDOSE".into());
+    let frames = PrimitiveValue::from(offsets.len().to_string());
+    put(&mut obj, tags::NUMBER_OF_FRAMES, VR::IS, frames);
+    put(
+        &mut obj,
+        tags::GRID_FRAME_OFFSET_VECTOR,
+        VR::DS,
+        strings(offsets),
+    );
+    put(
+        &mut obj,
+        tags::DOSE_GRID_SCALING,
+        VR::DS,
+        scaling.to_string().into(),
+    );
+    put(&mut obj, tags::DOSE_UNITS, VR::CS, "GY".into());
+    put(&mut obj, tags::DOSE_TYPE, VR::CS, "PHYSICAL".into());
+    put(&mut obj, tags::DOSE_SUMMATION_TYPE, VR::CS, "PLAN".into());
+    for tag in [tags::BITS_ALLOCATED, tags::BITS_STORED] {
+        put(&mut obj, tag, VR::US, PrimitiveValue::from(32u16));
+    }
+    put(
+        &mut obj,
+        tags::HIGH_BIT,
+        VR::US,
+        PrimitiveValue::from(31u16),
+    );
+    let representation = PrimitiveValue::from(0u16);
+    put(&mut obj, tags::PIXEL_REPRESENTATION, VR::US, representation);
+    let pixels = PrimitiveValue::U32(stored.into());
+    put(&mut obj, tags::PIXEL_DATA, VR::OW, pixels);
+    write_file(obj, path);
+}